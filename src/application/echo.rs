@@ -0,0 +1,57 @@
+use std::{
+    io::{self, Read, Write},
+    net::Shutdown,
+};
+
+use crate::socket::{TcpListener, TcpStream, UdpSocket};
+
+/// Echoes every connection on `listener` back to itself, one thread per
+/// connection, written like any `std::net` server would be.
+pub fn serve(listener: TcpListener) {
+    std::thread::spawn(move || {
+        loop {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    tracing::info!(%peer, "Echo accepted");
+                    std::thread::spawn(move || {
+                        if let Err(e) = echo(stream) {
+                            tracing::info!(?e, %peer, "Echo connection ended");
+                        }
+                    });
+                }
+                Err(e) => {
+                    tracing::warn!(?e, "Echo listener failed");
+                    return;
+                }
+            }
+        }
+    });
+}
+
+fn echo(mut stream: TcpStream) -> io::Result<()> {
+    let mut buf = [0; 1024];
+    loop {
+        let read = stream.read(&mut buf)?;
+        if read == 0 {
+            return stream.shutdown(Shutdown::Write);
+        }
+        stream.write_all(&buf[..read])?;
+    }
+}
+
+/// Sends every datagram on `socket` back to where it came from.
+pub fn serve_udp(socket: UdpSocket) {
+    std::thread::spawn(move || {
+        let mut buf = [0; 65_507];
+        loop {
+            let sent = socket
+                .recv_from(&mut buf)
+                .and_then(|(read, peer)| socket.send_to(&buf[..read], peer));
+
+            if let Err(e) = sent {
+                tracing::warn!(?e, "UDP echo failed");
+                return;
+            }
+        }
+    });
+}
@@ -1,3 +1,6 @@
+pub mod echo;
+pub mod ping;
+
 use serde::Deserialize;
 
 use crate::{
@@ -33,6 +36,8 @@ fn on_req(ReqBody(wat): ReqBody<Wat>) -> HttpResp {
     HttpResp::ok()
 }
 
+// The fields are only ever read through `Debug`, which the lint ignores.
+#[allow(dead_code)]
 #[derive(Deserialize, Debug)]
 struct Wat {
     key1: String,
@@ -0,0 +1,196 @@
+use std::{
+    fmt::Display,
+    io,
+    net::Ipv4Addr,
+    time::{Duration, Instant},
+};
+
+use crate::socket::{
+    PingSocket,
+    icmp::{Reply, ReplyKind},
+};
+
+/// What every echo request carries, the same as `ping` sends by default.
+const PAYLOAD: [u8; 56] = [0xa5; 56];
+const TTL: u8 = 64;
+
+/// Round trip times of the answered requests out of everything sent.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Stats {
+    pub sent: u32,
+    pub rtts: Vec<Duration>,
+}
+
+impl Stats {
+    pub fn received(&self) -> u32 {
+        self.rtts.len() as u32
+    }
+
+    /// Percentage of requests nothing came back for.
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        100.0 * (self.sent - self.received()) as f64 / self.sent as f64
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.rtts.iter().min().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.rtts.iter().max().copied()
+    }
+
+    pub fn avg(&self) -> Option<Duration> {
+        let total: Duration = self.rtts.iter().sum();
+        (!self.rtts.is_empty()).then(|| total / self.received())
+    }
+
+    /// Mean deviation from `avg`, how much the round trips jitter.
+    pub fn mdev(&self) -> Option<Duration> {
+        let avg = self.avg()?;
+        let total: Duration = self.rtts.iter().map(|rtt| rtt.abs_diff(avg)).sum();
+        Some(total / self.received())
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} packets transmitted, {} received, {:.0}% packet loss",
+            self.sent,
+            self.received(),
+            self.loss()
+        )?;
+
+        let (Some(min), Some(avg), Some(max), Some(mdev)) =
+            (self.min(), self.avg(), self.max(), self.mdev())
+        else {
+            return Ok(());
+        };
+        let ms = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "\nrtt min/avg/max/mdev = {:.3}/{:.3}/{:.3}/{:.3} ms",
+            ms(min),
+            ms(avg),
+            ms(max),
+            ms(mdev)
+        )
+    }
+}
+
+/// Sends `count` echo requests to `destination`, one every `interval`,
+/// handing every reply to `report` as it comes in. Late replies still
+/// count, as long as they arrive before the last interval is up.
+pub fn ping(
+    socket: &mut PingSocket,
+    destination: Ipv4Addr,
+    count: u16,
+    interval: Duration,
+    mut report: impl FnMut(&Reply),
+) -> io::Result<Stats> {
+    let mut stats = Stats::default();
+
+    for sequence in 0..count {
+        socket.send_to(destination, TTL, sequence, &PAYLOAD)?;
+        stats.sent += 1;
+
+        let deadline = Instant::now() + interval;
+        while let Some(reply) = recv_until(socket, deadline)? {
+            report(&reply);
+            if reply.kind == ReplyKind::Echo {
+                stats.rtts.push(reply.rtt);
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+/// One step of a traceroute, `reply` is `None` if nothing came back in time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Hop {
+    pub ttl: u8,
+    pub reply: Option<Reply>,
+}
+
+/// Sends echo requests to `destination` with a TTL of 1, 2 and so on, for
+/// the routers on the way to tell us where they expired. Stops once the
+/// destination answers or says it can't be reached, or after `max_hops`.
+pub fn traceroute(
+    socket: &mut PingSocket,
+    destination: Ipv4Addr,
+    max_hops: u8,
+    timeout: Duration,
+    mut report: impl FnMut(&Hop),
+) -> io::Result<Vec<Hop>> {
+    let mut hops = vec![];
+
+    for ttl in 1..=max_hops {
+        // The TTL doubles as the sequence number, late answers to earlier
+        // hops are told apart by it.
+        socket.send_to(destination, ttl, ttl.into(), &PAYLOAD)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut hop = Hop { ttl, reply: None };
+        while let Some(reply) = recv_until(socket, deadline)? {
+            if reply.sequence == u16::from(ttl) {
+                hop.reply = Some(reply);
+                break;
+            }
+        }
+
+        report(&hop);
+        hops.push(hop);
+        if hop
+            .reply
+            .is_some_and(|reply| reply.kind != ReplyKind::TimeExceeded)
+        {
+            break;
+        }
+    }
+
+    Ok(hops)
+}
+
+/// The next reply before `deadline`, `None` once it has passed.
+fn recv_until(socket: &mut PingSocket, deadline: Instant) -> io::Result<Option<Reply>> {
+    let left = deadline.saturating_duration_since(Instant::now());
+    if left.is_zero() {
+        return Ok(None);
+    }
+
+    socket.set_read_timeout(Some(left));
+    match socket.recv() {
+        Ok(reply) => Ok(Some(reply)),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stats_summarize_round_trips() {
+        let stats = Stats {
+            sent: 4,
+            rtts: [10, 20, 30].map(Duration::from_millis).to_vec(),
+        };
+
+        assert_eq!(stats.avg(), Some(Duration::from_millis(20)));
+        assert_eq!(
+            stats.to_string(),
+            "4 packets transmitted, 3 received, 25% packet loss\n\
+             rtt min/avg/max/mdev = 10.000/20.000/30.000/6.667 ms"
+        );
+        assert_eq!(
+            Stats::default().to_string(),
+            "0 packets transmitted, 0 received, 0% packet loss"
+        );
+    }
+}
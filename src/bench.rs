@@ -0,0 +1,109 @@
+use std::{
+    io,
+    net::Ipv4Addr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+
+use crate::{
+    device::loopback::LoopbackDevice,
+    network::{icmp::IcmpHandler, ip::IpHandler, tcp::TcpHandler, udp::UdpHandler},
+    proto::{
+        ip::Ip,
+        tcp::{Tcp, TcpControl},
+    },
+    socket::{Sockets, TcpListener},
+    stack::{Stack, peer::Peer},
+    time::Timers,
+};
+
+const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+const PEER: Peer = Peer {
+    address: Ipv4Addr::new(10, 0, 0, 2),
+    port: 40000,
+    server: Ipv4Addr::new(10, 0, 0, 1),
+    server_port: 3000,
+};
+/// Segments the client keeps in flight, enough to keep the stack busy
+/// without letting the queue grow without bound.
+const WINDOW: usize = 256;
+const PAYLOAD: &[u8] = &[0xab; 64];
+
+/// Pushes `packets` data segments of one connection through `run_nic` over
+/// a loopback pair and reports how many came back acknowledged per second.
+/// It measures the whole in-process path: device, receiver thread, stack,
+/// an application reading the stream and transmit, without any kernel in
+/// the way. The client keeps to the window the stack announces.
+pub fn run(packets: usize, mtu: u16) -> anyhow::Result<()> {
+    let (client, server) = LoopbackDevice::pair();
+
+    let timers = Timers::default();
+    let sockets = Sockets::default();
+    let ip = IpHandler {
+        icmp: IcmpHandler::new(timers.clone(), sockets.clone()),
+        udp: UdpHandler::new(SERVER, sockets.clone()),
+        tcp: TcpHandler::new(timers.clone(), sockets.clone()),
+    };
+    let listener = TcpListener::bind(&sockets, PEER.server_port)?;
+    let reader = std::thread::spawn(move || -> io::Result<u64> {
+        let (mut stream, _) = listener.accept()?;
+        io::copy(&mut stream, &mut io::sink())
+    });
+    let stack = Stack::new(ip, timers).mtu(mtu);
+    std::thread::spawn(move || crate::run_nic(vec![Arc::new(server)], stack, mtu));
+
+    client.send_buf(PEER.segment(TcpControl::SYN, 0, 0, &[]))?;
+    let syn_ack = client.recv_buf().context("No SYN-ACK")?;
+    let ack = Tcp::parse(Ip::parse(&syn_ack)?)?
+        .sequence_number()
+        .wrapping_add(1);
+    client.send_buf(PEER.segment(TcpControl::ACK, 1, ack, &[]))?;
+
+    let start = Instant::now();
+    let mut sent = 0;
+    let mut acked = 0;
+    let mut window = usize::from(u16::MAX);
+
+    while acked < packets {
+        let fits = |in_flight: usize| (in_flight + 1) * PAYLOAD.len() <= window;
+        while sent < packets && sent - acked < WINDOW && fits(sent - acked) {
+            let sequence = 1 + (sent * PAYLOAD.len()) as u32;
+            client.send_buf(PEER.segment(TcpControl::ACK, sequence, ack, PAYLOAD))?;
+            sent += 1;
+        }
+
+        let reply = client.recv_buf().context("Stack stopped answering")?;
+        let tcp = Tcp::parse(Ip::parse(&reply)?)?;
+        anyhow::ensure!(tcp.control() == TcpControl::ACK, "Expected an ACK");
+        // Window updates ack nothing new, count by what's acked.
+        acked = tcp.ack_number().wrapping_sub(1) as usize / PAYLOAD.len();
+        window = tcp.window().into();
+    }
+
+    report(packets, start.elapsed());
+    client.send_buf(PEER.segment(
+        TcpControl::FIN | TcpControl::ACK,
+        1 + (sent * PAYLOAD.len()) as u32,
+        ack,
+        &[],
+    ))?;
+    let read = reader.join().expect("Reader panicked")?;
+    anyhow::ensure!(
+        read == (packets * PAYLOAD.len()) as u64,
+        "Reader missed data"
+    );
+    Ok(())
+}
+
+fn report(packets: usize, elapsed: Duration) {
+    let pps = packets as f64 / elapsed.as_secs_f64();
+    println!(
+        "{} packets in {:.3}s: {:.0} packets/s, {:.2}us per packet",
+        packets,
+        elapsed.as_secs_f64(),
+        pps,
+        1_000_000.0 / pps
+    );
+}
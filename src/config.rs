@@ -0,0 +1,580 @@
+use std::{
+    net::{Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    str::FromStr,
+};
+
+use anyhow::{Context, bail};
+use serde::Deserialize;
+
+use crate::{
+    network::{
+        firewall::{Action, Rule},
+        routing::{Ipv4Prefix, RoutingTable},
+    },
+    proto::ethernet::MacAddr,
+};
+
+/// Everything that used to be hardcoded in `main`. Loaded from a JSON file
+/// with `--config`, any other flag overrides what the file says.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub interface: InterfaceConfig,
+    pub tcp: Vec<ServiceConfig>,
+    pub udp: Vec<ServiceConfig>,
+    /// Routes between `interface` and the ones in here, when set.
+    pub router: Option<RouterConfig>,
+    /// Filters what arrives, when set.
+    pub firewall: Option<FirewallConfig>,
+    pub icmp: IcmpConfig,
+}
+
+/// How much we send over ICMP, the stack's defaults for whatever isn't set.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct IcmpConfig {
+    /// Echo replies.
+    pub echo_rate: Option<RateConfig>,
+    /// Largest echo payload answered, bigger requests are dropped.
+    pub max_echo_payload: Option<usize>,
+    /// ICMP errors and RSTs, together.
+    pub error_rate: Option<RateConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateConfig {
+    /// Sent a second once the burst is used up.
+    pub per_second: u32,
+    /// Sent back to back before the rate kicks in.
+    pub burst: u32,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FirewallConfig {
+    /// What happens to packets no rule matches.
+    #[serde(default)]
+    pub default: Action,
+    /// Checked in order, the first one that matches decides.
+    pub rules: Vec<Rule>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RouterConfig {
+    /// Every interface besides `Config::interface`, which is always the first.
+    pub interfaces: Vec<InterfaceConfig>,
+    /// Routes beyond the subnets the interfaces are on.
+    #[serde(default)]
+    pub routes: Vec<RouteConfig>,
+    /// Masquerades everything forwarded out of one interface.
+    #[serde(default)]
+    pub nat: Option<NatConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RouteConfig {
+    pub destination: Ipv4Prefix,
+    /// The name of the interface to send it out of.
+    pub interface: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct NatConfig {
+    /// The name of the interface facing the public network.
+    pub interface: String,
+    /// What flows leaving through it appear to come from, the interface's
+    /// own address if not given.
+    #[serde(default)]
+    pub address: Option<Ipv4Addr>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct InterfaceConfig {
+    pub name: String,
+    pub address: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    /// More addresses to answer on besides `address`.
+    pub aliases: Vec<Ipv4Addr>,
+    /// Lets UDP sockets send to IPv6 peers. TCP answers on whatever address
+    /// a connection came in on, so it doesn't need this.
+    pub ipv6_address: Option<Ipv6Addr>,
+    pub mtu: u16,
+    pub mode: Mode,
+    /// Our hardware address in TAP mode.
+    pub mac: MacAddr,
+    /// Skips checksum verification on receipt, for devices that offload it
+    /// and hand over packets they already checked.
+    pub trust_checksums: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// Bare IP packets.
+    #[default]
+    Tun,
+    /// Ethernet frames, with ARP.
+    Tap,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ServiceConfig {
+    /// Only serves on this local address, every one of them if not given.
+    #[serde(default)]
+    pub address: Option<Ipv4Addr>,
+    pub port: u16,
+    pub service: Service,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Service {
+    /// The application in `application::Api`.
+    Http,
+    /// Answers every request with a 404.
+    HttpNotFound,
+    /// Sends back whatever it receives, served through the socket API. The
+    /// only service that also works over UDP.
+    Echo,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            interface: Default::default(),
+            tcp: vec![ServiceConfig {
+                address: None,
+                port: 3000,
+                service: Service::Http,
+            }],
+            udp: vec![],
+            router: None,
+            firewall: None,
+            icmp: IcmpConfig::default(),
+        }
+    }
+}
+
+impl Default for InterfaceConfig {
+    fn default() -> Self {
+        Self {
+            name: "utun9".into(),
+            address: Ipv4Addr::new(10, 0, 0, 1),
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            aliases: vec![],
+            ipv6_address: None,
+            mtu: 1500,
+            mode: Mode::Tun,
+            // Locally administered, so it can't clash with a real vendor's.
+            mac: MacAddr([0x02, 0, 0, 0, 0, 0x01]),
+            trust_checksums: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(args: &Args) -> anyhow::Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => {
+                let file = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read config {}", path.display()))?;
+                serde_json::from_str(&file)
+                    .with_context(|| format!("Failed to parse config {}", path.display()))?
+            }
+            None => Config::default(),
+        };
+
+        args.apply(&mut config);
+        Ok(config)
+    }
+}
+
+impl Config {
+    /// Every interface in the order the stack numbers them.
+    pub fn interfaces(&self) -> impl Iterator<Item = &InterfaceConfig> {
+        let others = self.router.iter().flat_map(|router| &router.interfaces);
+        std::iter::once(&self.interface).chain(others)
+    }
+
+    /// Our addresses on every interface and, in router mode, the routes
+    /// beyond them.
+    pub fn routing_table(&self) -> anyhow::Result<RoutingTable> {
+        if self.router.is_some()
+            && self
+                .interfaces()
+                .any(|interface| interface.mode == Mode::Tap)
+        {
+            bail!("Router mode only works with TUN interfaces");
+        }
+
+        let mut table = RoutingTable::default();
+        for interface in self.interfaces() {
+            let subnet = Ipv4Prefix::from_netmask(interface.address, interface.netmask)
+                .with_context(|| format!("Invalid netmask on {}", interface.name))?;
+            table = table.interface(interface.address, subnet);
+            for &alias in &interface.aliases {
+                table = table.alias(alias);
+            }
+        }
+        for service in self.tcp.iter().chain(&self.udp) {
+            if let Some(address) = service.address
+                && !table.is_local(address)
+            {
+                bail!(
+                    "Service on port {} binds {}, which isn't ours",
+                    service.port,
+                    address
+                );
+            }
+        }
+
+        let Some(router) = &self.router else {
+            return Ok(table);
+        };
+        for route in &router.routes {
+            let index = self
+                .interface_index(&route.interface)
+                .with_context(|| format!("Route to {} via unknown interface", route.destination))?;
+            table = table.route(route.destination, index);
+        }
+
+        Ok(table)
+    }
+
+    /// The public address and interface index NAT translates to, if it's on.
+    pub fn nat(&self) -> anyhow::Result<Option<(Ipv4Addr, usize)>> {
+        let Some(nat) = self.router.as_ref().and_then(|router| router.nat.as_ref()) else {
+            return Ok(None);
+        };
+        let index = self
+            .interface_index(&nat.interface)
+            .context("NAT on unknown interface")?;
+        let address = match nat.address {
+            Some(address) => address,
+            None => self.interfaces().nth(index).unwrap().address,
+        };
+        Ok(Some((address, index)))
+    }
+
+    fn interface_index(&self, name: &str) -> anyhow::Result<usize> {
+        self.interfaces()
+            .position(|interface| interface.name == name)
+            .with_context(|| format!("No interface named {}", name))
+    }
+}
+
+impl FromStr for Service {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_value(serde_json::Value::String(s.into()))
+            .with_context(|| format!("Unknown service: {}", s))
+    }
+}
+
+impl FromStr for ServiceConfig {
+    type Err = anyhow::Error;
+
+    /// `[<address>:]<port>=<service>`, eg. `3000=http` or `10.0.0.2:80=http`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (bind, service) = s
+            .split_once('=')
+            .with_context(|| format!("Expected [<address>:]<port>=<service>, got: {}", s))?;
+        let (address, port) = match bind.rsplit_once(':') {
+            Some((address, port)) => (
+                Some(
+                    address
+                        .parse()
+                        .with_context(|| format!("Invalid address: {}", address))?,
+                ),
+                port,
+            ),
+            None => (None, bind),
+        };
+
+        Ok(Self {
+            address,
+            port: port
+                .parse()
+                .with_context(|| format!("Invalid port: {}", port))?,
+            service: service.parse()?,
+        })
+    }
+}
+
+/// Command line:
+///
+/// ```text
+/// rusnet [bench [--packets <count>] | ping <ip> [--count <count>]
+///         | traceroute <ip> [--max-hops <count>]]
+///        [--config <file>] [--interface <name>] [--address <ip>] [--netmask <ip>]
+///        [--ipv6-address <ip>]
+///        [--mtu <bytes>] [--tap] [--mac <address>] [--trust-checksums]
+///        [--tcp [<address>:]<port>=<service>]... [--udp [<address>:]<port>=<service>]...
+///        [--capture <file>] [--replay <input> <output>]
+/// ```
+#[derive(Default, Debug)]
+pub struct Args {
+    pub command: Command,
+    pub config: Option<PathBuf>,
+    pub interface: Option<String>,
+    pub address: Option<Ipv4Addr>,
+    pub netmask: Option<Ipv4Addr>,
+    pub ipv6_address: Option<Ipv6Addr>,
+    pub mtu: Option<u16>,
+    pub tap: bool,
+    pub mac: Option<MacAddr>,
+    pub trust_checksums: bool,
+    pub tcp: Vec<ServiceConfig>,
+    pub udp: Vec<ServiceConfig>,
+    pub capture: Option<PathBuf>,
+    pub replay: Option<(PathBuf, PathBuf)>,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// Serve on the configured interface.
+    #[default]
+    Run,
+    /// Measure how many packets per second the stack handles over a loopback pair.
+    Bench { packets: usize },
+    /// Ping from the configured interface, then print round trip statistics.
+    Ping { destination: Ipv4Addr, count: u16 },
+    /// List the routers on the way to `destination`, as seen from the configured interface.
+    Traceroute { destination: Ipv4Addr, max_hops: u8 },
+}
+
+impl Args {
+    pub fn parse() -> anyhow::Result<Self> {
+        Self::parse_from(std::env::args().skip(1))
+    }
+
+    pub fn parse_from(args: impl IntoIterator<Item = String>) -> anyhow::Result<Self> {
+        let mut parsed = Self::default();
+        let mut iter = args.into_iter().peekable();
+
+        if iter.next_if(|arg| arg == "bench").is_some() {
+            parsed.command = Command::Bench { packets: 100_000 };
+        } else if iter.next_if(|arg| arg == "ping").is_some() {
+            let destination = iter.next().context("ping expects an address")?;
+            parsed.command = Command::Ping {
+                destination: destination.parse()?,
+                count: 4,
+            };
+        } else if iter.next_if(|arg| arg == "traceroute").is_some() {
+            let destination = iter.next().context("traceroute expects an address")?;
+            parsed.command = Command::Traceroute {
+                destination: destination.parse()?,
+                max_hops: 30,
+            };
+        }
+
+        while let Some(arg) = iter.next() {
+            let mut value = |what: &str| {
+                iter.next()
+                    .with_context(|| format!("{} expects {}", arg, what))
+            };
+
+            match arg.as_str() {
+                "--config" => parsed.config = Some(value("a file")?.into()),
+                "--interface" => parsed.interface = Some(value("a name")?),
+                "--address" => parsed.address = Some(value("an address")?.parse()?),
+                "--netmask" => parsed.netmask = Some(value("a netmask")?.parse()?),
+                "--ipv6-address" => parsed.ipv6_address = Some(value("an address")?.parse()?),
+                "--mtu" => parsed.mtu = Some(value("a size")?.parse()?),
+                "--tap" => parsed.tap = true,
+                "--mac" => parsed.mac = Some(value("an address")?.parse()?),
+                "--trust-checksums" => parsed.trust_checksums = true,
+                "--tcp" => parsed
+                    .tcp
+                    .push(value("[<address>:]<port>=<service>")?.parse()?),
+                "--udp" => parsed
+                    .udp
+                    .push(value("[<address>:]<port>=<service>")?.parse()?),
+                "--capture" => parsed.capture = Some(value("a file")?.into()),
+                "--packets" => match &mut parsed.command {
+                    Command::Bench { packets } => *packets = value("a count")?.parse()?,
+                    _ => bail!("--packets only applies to bench"),
+                },
+                "--count" => match &mut parsed.command {
+                    Command::Ping { count, .. } => *count = value("a count")?.parse()?,
+                    _ => bail!("--count only applies to ping"),
+                },
+                "--max-hops" => match &mut parsed.command {
+                    Command::Traceroute { max_hops, .. } => {
+                        *max_hops = value("a count")?.parse()?
+                    }
+                    _ => bail!("--max-hops only applies to traceroute"),
+                },
+                "--replay" => {
+                    let input = value("an input file")?;
+                    let output = value("an output file")?;
+                    parsed.replay = Some((input.into(), output.into()));
+                }
+                other => bail!("Unknown argument: {}", other),
+            }
+        }
+
+        Ok(parsed)
+    }
+
+    fn apply(&self, config: &mut Config) {
+        let interface = &mut config.interface;
+        if let Some(name) = &self.interface {
+            interface.name = name.clone();
+        }
+        if let Some(address) = self.address {
+            interface.address = address;
+        }
+        if let Some(netmask) = self.netmask {
+            interface.netmask = netmask;
+        }
+        if let Some(address) = self.ipv6_address {
+            interface.ipv6_address = Some(address);
+        }
+        if let Some(mtu) = self.mtu {
+            interface.mtu = mtu;
+        }
+        if self.tap {
+            interface.mode = Mode::Tap;
+        }
+        if let Some(mac) = self.mac {
+            interface.mac = mac;
+        }
+        if self.trust_checksums {
+            interface.trust_checksums = true;
+        }
+
+        for (services, overrides) in [(&mut config.tcp, &self.tcp), (&mut config.udp, &self.udp)] {
+            for service in overrides {
+                services.retain(|s| (s.address, s.port) != (service.address, service.port));
+                services.push(*service);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_override_config_file() {
+        let mut config: Config = serde_json::from_str(
+            r#"{
+                "interface": {
+                    "name": "tun3",
+                    "address": "192.168.5.1",
+                    "ipv6_address": "fd00::1",
+                    "mtu": 9000
+                },
+                "tcp": [
+                    { "port": 80, "service": "http" },
+                    { "port": 8080, "service": "http-not-found" }
+                ],
+                "udp": [{ "port": 7, "service": "echo" }],
+                "icmp": { "error_rate": { "per_second": 10, "burst": 5 } }
+            }"#,
+        )
+        .unwrap();
+
+        let args = Args::parse_from(
+            [
+                "--mtu",
+                "1280",
+                "--tap",
+                "--tcp",
+                "80=http-not-found",
+                "--tcp",
+                "443=http",
+                "--udp",
+                "9=echo",
+                "--tcp",
+                "192.168.5.2:80=http",
+            ]
+            .map(String::from),
+        )
+        .unwrap();
+        args.apply(&mut config);
+
+        assert_eq!(
+            config.interface,
+            InterfaceConfig {
+                name: "tun3".into(),
+                address: Ipv4Addr::new(192, 168, 5, 1),
+                netmask: Ipv4Addr::new(255, 255, 255, 0),
+                aliases: vec![],
+                ipv6_address: Some("fd00::1".parse().unwrap()),
+                mtu: 1280,
+                mode: Mode::Tap,
+                mac: "02:00:00:00:00:01".parse().unwrap(),
+                trust_checksums: false,
+            }
+        );
+
+        let service = |port, service| ServiceConfig {
+            address: None,
+            port,
+            service,
+        };
+        assert_eq!(
+            config.tcp,
+            [
+                service(8080, Service::HttpNotFound),
+                service(80, Service::HttpNotFound),
+                service(443, Service::Http),
+                ServiceConfig {
+                    address: Some(Ipv4Addr::new(192, 168, 5, 2)),
+                    ..service(80, Service::Http)
+                },
+            ]
+        );
+        assert_eq!(
+            config.udp,
+            [service(7, Service::Echo), service(9, Service::Echo)]
+        );
+        assert_eq!(
+            config.icmp,
+            IcmpConfig {
+                error_rate: Some(RateConfig {
+                    per_second: 10,
+                    burst: 5
+                }),
+                ..IcmpConfig::default()
+            }
+        );
+    }
+
+    #[test]
+    fn subcommands_take_their_own_flags() {
+        let parse = |args: &[&str]| Args::parse_from(args.iter().map(|arg| arg.to_string()));
+
+        let args = parse(&["ping", "10.0.1.5", "--count", "2", "--mtu", "1280"]).unwrap();
+        assert_eq!(
+            args.command,
+            Command::Ping {
+                destination: Ipv4Addr::new(10, 0, 1, 5),
+                count: 2,
+            }
+        );
+        assert_eq!(args.mtu, Some(1280));
+
+        let args = parse(&["traceroute", "10.0.1.5"]).unwrap();
+        assert_eq!(
+            args.command,
+            Command::Traceroute {
+                destination: Ipv4Addr::new(10, 0, 1, 5),
+                max_hops: 30,
+            }
+        );
+        assert!(parse(&["traceroute", "10.0.1.5", "--count", "2"]).is_err());
+        assert!(parse(&["ping"]).is_err());
+    }
+}
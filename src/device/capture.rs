@@ -0,0 +1,77 @@
+use std::{
+    io::{self, Read, Write},
+    sync::Mutex,
+};
+
+use crate::pcap::{PcapReader, PcapWriter};
+
+use super::Device;
+
+/// Wraps a device and records every packet received from and sent to it.
+pub struct CaptureDevice<D: Device, W: Write + Send> {
+    inner: D,
+    capture: Mutex<PcapWriter<W>>,
+}
+
+impl<D: Device, W: Write + Send> CaptureDevice<D, W> {
+    pub fn new(inner: D, capture: PcapWriter<W>) -> Self {
+        Self {
+            inner,
+            capture: Mutex::new(capture),
+        }
+    }
+
+    fn record(&self, packet: &[u8]) {
+        if let Err(e) = self.capture.lock().unwrap().write_packet(packet) {
+            tracing::warn!(?e, "Failed to write packet to capture");
+        }
+    }
+}
+
+impl<D: Device, W: Write + Send> Device for CaptureDevice<D, W> {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let bytes = self.inner.recv(buf)?;
+        self.record(&buf[..bytes]);
+        Ok(bytes)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        let sent = self.inner.send(buf)?;
+        self.record(&buf[..sent]);
+        Ok(sent)
+    }
+}
+
+/// Feeds the packets of a capture into the stack, and records what the stack
+/// sends back into a second capture. Once the input is exhausted `recv`
+/// returns `UnexpectedEof`, which ends `run_nic`.
+pub struct ReplayDevice<R: Read + Send, W: Write + Send> {
+    input: Mutex<PcapReader<R>>,
+    output: Mutex<PcapWriter<W>>,
+}
+
+impl<R: Read + Send, W: Write + Send> ReplayDevice<R, W> {
+    pub fn new(input: PcapReader<R>, output: PcapWriter<W>) -> Self {
+        Self {
+            input: Mutex::new(input),
+            output: Mutex::new(output),
+        }
+    }
+}
+
+impl<R: Read + Send, W: Write + Send> Device for ReplayDevice<R, W> {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(packet) = self.input.lock().unwrap().next_packet()? else {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        };
+
+        let len = packet.data.len().min(buf.len());
+        buf[..len].copy_from_slice(&packet.data[..len]);
+        Ok(len)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        self.output.lock().unwrap().write_packet(buf)?;
+        Ok(buf.len())
+    }
+}
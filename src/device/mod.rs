@@ -0,0 +1,47 @@
+use std::io;
+
+use crate::proto::NetworkBuffer;
+
+pub mod capture;
+pub mod loopback;
+
+/// A layer 3 packet device. Everything above `run_nic` talks to the network
+/// through this, so the stack can run on top of a real tun interface or
+/// entirely in memory.
+pub trait Device: Send + Sync {
+    /// Blocks until a packet is available and copies it into `buf`.
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Writes a single packet to the device.
+    fn send(&self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Writes every packet in `packets`, in order, leaving `packets` empty.
+    /// Devices that can hand off several packets at once override this.
+    fn send_batch(&self, packets: &mut Vec<NetworkBuffer>) -> io::Result<()> {
+        for packet in packets.drain(..) {
+            // The stack fragments to the MTU, so anything short is the device's doing.
+            let sent = self.send(&packet)?;
+            if sent < packet.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::WriteZero,
+                    format!(
+                        "Failed to send all bytes?? sent: {}, buflen: {}",
+                        sent,
+                        packet.len()
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Device for tun::Device {
+    fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        tun::Device::recv(self, buf)
+    }
+
+    fn send(&self, buf: &[u8]) -> io::Result<usize> {
+        tun::Device::send(self, buf)
+    }
+}
@@ -1,65 +1,379 @@
-use std::sync::Arc;
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter},
+    net::{Ipv4Addr, SocketAddr},
+    sync::{
+        Arc,
+        mpsc::{RecvTimeoutError, Sender, channel},
+    },
+    time::Duration,
+};
 
 use anyhow::{Context, bail};
-use network::{Handler, ip::IpHandler, tcp::TcpHandler};
-use proto::{NetworkBuffer, ProtocolBuffer, http::PackedHttpResp, ip::Ip, tcp::Tcp, udp::Udp};
+use config::{Args, Command, Config, InterfaceConfig, Mode, Service, ServiceConfig};
+use device::{
+    Device,
+    capture::{CaptureDevice, ReplayDevice},
+};
+use network::{
+    ethernet::EthernetHandler, http::HttpHandler, ip::IpHandler, tcp::TcpHandler, udp::UdpHandler,
+};
+use pcap::{PcapReader, PcapWriter};
+use proto::{
+    NetworkBuffer, ProtocolBuffer,
+    ethernet::{ETHERNET_HEADER_LEN, EtherType, Ethernet},
+    http::PackedHttpResp,
+    ip::IpPacket,
+    tcp::Tcp,
+    udp::Udp,
+};
+use socket::{PingSocket, Sockets, TcpListener, UdpSocket, icmp::ReplyKind};
+use stack::Stack;
+use time::{Instant, Timers};
 
 mod application;
+mod bench;
+mod config;
+mod device;
 mod network;
 pub mod oob_buffer;
+mod pcap;
 mod proto;
+mod socket;
+mod stack;
+mod time;
 mod utils;
 
 fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt().init();
+    let args = Args::parse()?;
+    let config = Config::load(&args)?;
 
-    let nic = create_nic()?;
-    let nic = Arc::new(nic);
+    if let Command::Bench { packets } = args.command {
+        // Logging every packet would be all the benchmark measures.
+        tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::WARN)
+            .init();
+        return bench::run(packets, config.interface.mtu);
+    }
+
+    // The clients print their own results, packet logs would bury them.
+    let level = match args.command {
+        Command::Run => tracing::Level::INFO,
+        _ => tracing::Level::WARN,
+    };
+    tracing_subscriber::fmt().with_max_level(level).init();
+    tracing::info!(?config, "Starting");
+
+    let routing_table = config.routing_table()?;
+    // Capturing and replaying only apply to the first interface.
+    let mut nics = vec![open_nic(&args, &config.interface)?];
+    for interface in config.interfaces().skip(1) {
+        nics.push(Arc::new(create_nic(interface)?));
+    }
+    let timers = Timers::default();
+
+    let sockets = Sockets::default();
+
+    let mut tcp = TcpHandler::new(timers.clone(), sockets.clone());
+    for &ServiceConfig {
+        address,
+        port,
+        service,
+    } in &config.tcp
+    {
+        let local = SocketAddr::new(address.unwrap_or(Ipv4Addr::UNSPECIFIED).into(), port);
+        tcp = match service {
+            Service::Http => tcp.listen_on(local, HttpHandler::new(application::Api)),
+            Service::HttpNotFound => tcp.listen_on(local, HttpHandler::none()),
+            Service::Echo => {
+                application::echo::serve(TcpListener::bind_addr(&sockets, local)?);
+                tcp
+            }
+        };
+    }
+
+    for &ServiceConfig {
+        address,
+        port,
+        service,
+    } in &config.udp
+    {
+        let local = SocketAddr::new(address.unwrap_or(Ipv4Addr::UNSPECIFIED).into(), port);
+        match service {
+            Service::Echo => application::echo::serve_udp(UdpSocket::bind_addr(&sockets, local)?),
+            other => bail!("{:?} can't be served over UDP", other),
+        }
+    }
+
+    let mut udp = UdpHandler::new(config.interface.address, sockets.clone());
+    if let Some(address) = config.interface.ipv6_address {
+        udp = udp.ipv6(address);
+    }
 
-    let http_handler = network::http::HttpHandler::new(application::Api);
+    let mut icmp = network::icmp::IcmpHandler::new(timers.clone(), sockets.clone());
+    if let Some(rate) = config.icmp.echo_rate {
+        icmp = icmp.rate_limit(rate.per_second, rate.burst);
+    }
+    if let Some(bytes) = config.icmp.max_echo_payload {
+        icmp = icmp.max_payload(bytes);
+    }
 
-    let ip_layer = IpHandler {
-        icmp: network::icmp::IcmpHandler,
-        udp: network::udp::UdpHandler,
-        tcp: TcpHandler::new(3000, http_handler, nic.clone()),
+    let ip_layer = IpHandler { icmp, udp, tcp };
+
+    let mut stack = Stack::new(ip_layer, timers)
+        .mtu(config.interface.mtu)
+        .trust_checksums(config.interface.trust_checksums);
+    if let Some(rate) = config.icmp.error_rate {
+        stack = stack.error_rate_limit(rate.per_second, rate.burst);
+    }
+    if let Some(address) = config.interface.ipv6_address {
+        stack = stack.ipv6_address(address);
+    }
+    for (index, interface) in config.interfaces().enumerate() {
+        stack = stack.interface_mtu(index, interface.mtu);
+    }
+    if config.interface.mode == Mode::Tap {
+        let interface = &config.interface;
+        stack = stack.ethernet(EthernetHandler::new(interface.mac, interface.address));
+    }
+    stack = match config.router {
+        Some(_) => stack.router(routing_table),
+        None => stack.addresses(routing_table),
     };
+    if let Some((address, interface)) = config.nat()? {
+        stack = stack.nat(address, interface);
+    }
+    if let Some(firewall) = &config.firewall {
+        stack = stack.firewall(firewall.rules.clone(), firewall.default);
+    }
+
+    // Receive buffers have to fit the largest interface.
+    let mtu = config.interfaces().map(|interface| interface.mtu).max();
+    let mtu = mtu.unwrap_or(config.interface.mtu);
+    match args.command {
+        Command::Ping { destination, count } => {
+            let socket = PingSocket::bind(&sockets, config.interface.address)?;
+            with_stack(nics, stack, mtu, || ping(socket, destination, count))
+        }
+        Command::Traceroute {
+            destination,
+            max_hops,
+        } => {
+            let socket = PingSocket::bind(&sockets, config.interface.address)?;
+            with_stack(nics, stack, mtu, || {
+                traceroute(socket, destination, max_hops)
+            })
+        }
+        _ => run_nic(nics, stack, mtu),
+    }
+}
 
-    run_nic(nic, ip_layer)?;
+/// Runs `client` while the stack runs on its own, like bench. If the stack
+/// stopped meanwhile, that's what went wrong, whatever the client says.
+fn with_stack<T>(
+    nics: Vec<Arc<dyn Device>>,
+    stack: Stack,
+    mtu: u16,
+    client: impl FnOnce() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    let stack = std::thread::spawn(move || run_nic(nics, stack, mtu));
+    let result = client();
+    if stack.is_finished() {
+        match stack.join() {
+            Ok(Err(e)) => return Err(e.context("Stack stopped")),
+            Ok(Ok(())) => bail!("Stack stopped"),
+            Err(_) => bail!("Stack panicked"),
+        }
+    }
+    result
+}
+
+/// How long ping waits between requests, and traceroute for each hop.
+const PROBE_INTERVAL: Duration = Duration::from_secs(1);
+
+fn ping(mut socket: PingSocket, destination: Ipv4Addr, count: u16) -> anyhow::Result<()> {
+    println!("PING {}", destination);
+    let stats = application::ping::ping(
+        &mut socket,
+        destination,
+        count,
+        PROBE_INTERVAL,
+        |reply| match reply.kind {
+            ReplyKind::Echo => println!(
+                "reply from {}: seq={} time={:.3} ms",
+                reply.from,
+                reply.sequence,
+                reply.rtt.as_secs_f64() * 1000.0
+            ),
+            ReplyKind::TimeExceeded => {
+                println!("from {}: seq={} Time Exceeded", reply.from, reply.sequence)
+            }
+            ReplyKind::Unreachable(reason) => println!(
+                "from {}: seq={} Destination Unreachable ({:?})",
+                reply.from, reply.sequence, reason
+            ),
+        },
+    )?;
+
+    println!("--- {} ping statistics ---", destination);
+    println!("{}", stats);
+    Ok(())
+}
 
+fn traceroute(mut socket: PingSocket, destination: Ipv4Addr, max_hops: u8) -> anyhow::Result<()> {
+    println!("traceroute to {}, {} hops max", destination, max_hops);
+    application::ping::traceroute(&mut socket, destination, max_hops, PROBE_INTERVAL, |hop| {
+        match hop.reply {
+            Some(reply) => println!(
+                "{:2}  {}  {:.3} ms",
+                hop.ttl,
+                reply.from,
+                reply.rtt.as_secs_f64() * 1000.0
+            ),
+            None => println!("{:2}  *", hop.ttl),
+        }
+    })?;
     Ok(())
 }
 
-fn run_nic(nic: Arc<tun::Device>, mut ip_layer: IpHandler) -> anyhow::Result<()> {
-    let mut buf = [0; 1500];
+/// Most events `run_nic` handles before running timers and flushing replies.
+const MAX_BATCH: usize = 64;
+
+/// What wakes up the main loop, besides timers.
+enum Event {
+    /// A packet from the device of an interface.
+    Received(usize, io::Result<NetworkBuffer>),
+    /// An application queued something on a socket.
+    Wake,
+}
+
+/// Drives `stack` with one device per interface, in the stack's order.
+fn run_nic(nics: Vec<Arc<dyn Device>>, mut stack: Stack, mtu: u16) -> anyhow::Result<()> {
+    let (events, packets) = channel();
+    for (interface, nic) in nics.iter().enumerate() {
+        spawn_receiver(nic.clone(), interface, mtu, events.clone());
+    }
+    stack.sockets().set_waker(move || {
+        _ = events.send(Event::Wake);
+    });
+
+    let start = std::time::Instant::now();
+    let clock = || Instant::from(start.elapsed());
+    let mut batch = Vec::with_capacity(MAX_BATCH);
+    let mut logged = std::time::Instant::now();
 
     loop {
-        tracing::info!("RECEIVING");
-        let bytes = nic.recv(&mut buf).context("Failed to recieve from nic")?;
-        tracing::info!("RECV: {}", bytes);
-        let ip_header = Ip::parse(&buf[..bytes])?;
-
-        let out = ip_layer.handle(ip_header)?;
-
-        if !out.is_empty() {
-            _ = print(&out);
-            // Sent will always be MTU size at least, it looks like.
-            let sent = nic.send(&out).context("Failed to send to nic")?;
-            tracing::info!("SENT: {}", sent);
-
-            if sent < out.len() {
-                bail!(
-                    "Failed to send all bytes?? sent: {}, buflen: {}",
-                    sent,
-                    out.len()
-                )
+        let first = match stack.poll_at() {
+            Some(at) => packets.recv_timeout(at.saturating_duration_since(clock())),
+            None => packets.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+
+        let first = match first {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => bail!("Receiver thread stopped"),
+        };
+
+        // Whatever else queued up while we were busy gets handled before
+        // timers run and replies go out, all in one go.
+        let events = first
+            .into_iter()
+            .chain(packets.try_iter().take(MAX_BATCH - 1));
+        let mut closed = false;
+        for event in events {
+            match event {
+                Event::Received(interface, Ok(packet)) => {
+                    tracing::info!("RECV: {} on {}", packet.len(), interface);
+                    stack.receive_on(clock(), interface, &packet)?;
+                }
+                Event::Received(_, Err(e)) if e.kind() == io::ErrorKind::UnexpectedEof => {
+                    // Replies to what came before still go out.
+                    closed = true;
+                    break;
+                }
+                Event::Received(_, Err(e)) => return Err(e).context("Failed to recieve from nic"),
+                Event::Wake => {}
+            }
+        }
+
+        stack.poll(clock());
+
+        for (interface, nic) in nics.iter().enumerate() {
+            batch.extend(std::iter::from_fn(|| stack.poll_transmit_on(interface)));
+            if batch.is_empty() {
+                continue;
             }
+            if tracing::enabled!(tracing::Level::INFO) {
+                for out in &batch {
+                    _ = print(out, interface == 0 && stack.is_ethernet());
+                }
+            }
+            let sent = batch.len();
+            nic.send_batch(&mut batch)
+                .context("Failed to send to nic")?;
+            tracing::info!("SENT: {} packets on {}", sent, interface);
+        }
+
+        if logged.elapsed() >= COUNTERS_INTERVAL {
+            log_counters(&stack, "Stack counters");
+            logged = std::time::Instant::now();
+        }
+
+        if closed {
+            log_counters(&stack, "Device closed");
+            return Ok(());
         }
     }
-    Ok(())
 }
 
-fn print(out: &NetworkBuffer) -> anyhow::Result<()> {
-    let d = Ip::parse(out)?;
+/// How often a busy stack logs what it dropped and what the firewall decided.
+const COUNTERS_INTERVAL: Duration = Duration::from_secs(60);
+
+fn log_counters(stack: &Stack, message: &str) {
+    tracing::info!(
+        rule_hits = ?stack.rule_hits(),
+        dropped = ?stack.counters(),
+        "{}",
+        message
+    );
+}
+
+/// The device blocks on `recv`, so it gets a thread of its own. That leaves
+/// the main loop free to wake up for timers while waiting for packets.
+fn spawn_receiver(nic: Arc<dyn Device>, interface: usize, mtu: u16, events: Sender<Event>) {
+    // Leaves room for the Ethernet header in TAP mode.
+    let size = mtu as usize + ETHERNET_HEADER_LEN;
+
+    std::thread::spawn(move || {
+        loop {
+            tracing::info!("RECEIVING");
+            let mut packet = NetworkBuffer::new_zeroed(size);
+            let received = nic.recv(&mut packet).map(|bytes| {
+                packet.truncate(bytes);
+                packet
+            });
+            let failed = received.is_err();
+
+            if events.send(Event::Received(interface, received)).is_err() || failed {
+                return;
+            }
+        }
+    });
+}
+
+fn print(out: &NetworkBuffer, ethernet: bool) -> anyhow::Result<()> {
+    let packet = if ethernet {
+        let frame = Ethernet::parse(out)?;
+        tracing::info!("OUT: {}", frame);
+        if frame.ether_type() != EtherType::Ipv4 {
+            return Ok(());
+        }
+        frame.payload()
+    } else {
+        out
+    };
+
+    let d = IpPacket::parse(packet)?;
 
     tracing::info!("OUT: {}", d);
     match d.protocol() {
@@ -68,7 +382,7 @@ fn print(out: &NetworkBuffer) -> anyhow::Result<()> {
             tracing::info!("OUT: {}", dd);
 
             if !dd.buf().is_empty() {
-                let ddd = PackedHttpResp::parse(dd);
+                let ddd = PackedHttpResp::parse(dd)?;
                 tracing::info!("OUT: {}", ddd);
             }
         }
@@ -76,21 +390,191 @@ fn print(out: &NetworkBuffer) -> anyhow::Result<()> {
             let dd = Udp::parse(d)?;
             tracing::info!("OUT: {}", dd);
         }
-        other => return Ok(()),
+        _ => return Ok(()),
     }
 
     Ok(())
 }
 
-fn create_nic() -> anyhow::Result<tun::Device> {
+fn open_nic(args: &Args, interface: &InterfaceConfig) -> anyhow::Result<Arc<dyn Device>> {
+    let linktype = match interface.mode {
+        Mode::Tun => pcap::LINKTYPE_RAW,
+        Mode::Tap => pcap::LINKTYPE_ETHERNET,
+    };
+
+    if let Some((input, output)) = &args.replay {
+        let input = File::open(input).context("Failed to open replay input")?;
+        let input = PcapReader::new(BufReader::new(input))?;
+        if input.linktype() != linktype {
+            bail!(
+                "Replay input has link type {}, {:?} mode expects {}",
+                input.linktype(),
+                interface.mode,
+                linktype
+            );
+        }
+
+        let output = File::create(output).context("Failed to create replay output")?;
+        let device = ReplayDevice::new(
+            input,
+            PcapWriter::with_linktype(BufWriter::new(output), linktype)?,
+        );
+        return Ok(Arc::new(device));
+    }
+
+    let nic = create_nic(interface)?;
+
+    if let Some(path) = &args.capture {
+        let file = File::create(path).context("Failed to create capture file")?;
+        let capture = PcapWriter::with_linktype(BufWriter::new(file), linktype)?;
+        let device = CaptureDevice::new(nic, capture);
+        return Ok(Arc::new(device));
+    }
+
+    Ok(Arc::new(nic))
+}
+
+fn create_nic(interface: &InterfaceConfig) -> anyhow::Result<tun::Device> {
     let mut conf = tun::configure();
-    conf.tun_name("utun9")
-        .address((10, 0, 0, 1))
+    conf.tun_name(&interface.name)
+        .address(interface.address)
         // .broadcast((10, 0, 0, 255))
-        .netmask((255, 255, 255, 0))
+        .netmask(interface.netmask)
         // .destination((10, 0, 0, 1))
+        .mtu(interface.mtu)
         .up();
 
+    if interface.mode == Mode::Tap {
+        conf.layer(tun::Layer::L2);
+    }
+
     let nic = tun::create(&conf)?;
     Ok(nic)
 }
+
+#[cfg(test)]
+mod tests {
+    use device::loopback::LoopbackDevice;
+    use network::routing::RoutingTable;
+    use proto::{
+        Protocol,
+        ip::{Ip, IpHeaderWriter},
+        tcp::{TcpControl, TcpHeaderWriter},
+    };
+
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn syn(source_port: u16, destination_port: u16, sequence: u32) -> NetworkBuffer {
+        let pseudo = IpHeaderWriter::new(CLIENT, SERVER, Protocol::TCP, 64, NetworkBuffer::empty())
+            .into_buf();
+        let tcp = TcpHeaderWriter::new(source_port, destination_port, sequence, 0)
+            .set(TcpControl::SYN)
+            .calc_checksum(&IpPacket::parse(&pseudo).unwrap())
+            .into_buf();
+
+        IpHeaderWriter::new(CLIENT, SERVER, Protocol::TCP, 64, tcp).into_buf()
+    }
+
+    fn stack() -> Stack {
+        stack_with(Sockets::default())
+    }
+
+    fn stack_with(sockets: Sockets) -> Stack {
+        let timers = Timers::default();
+        let ip_layer = IpHandler {
+            icmp: network::icmp::IcmpHandler::new(timers.clone(), sockets.clone()),
+            udp: UdpHandler::new(SERVER, sockets.clone()),
+            tcp: TcpHandler::new(timers.clone(), sockets).listen(3000, HttpHandler::none()),
+        };
+        Stack::new(ip_layer, timers)
+    }
+
+    fn assert_syn_ack(reply: &[u8]) {
+        let ip = Ip::parse(reply).unwrap();
+        assert_eq!(ip.source(), SERVER);
+        assert_eq!(ip.destination(), CLIENT);
+
+        let tcp = Tcp::parse(ip).unwrap();
+        assert_eq!(tcp.source_port(), 3000);
+        assert_eq!(tcp.destination_port(), 50000);
+        assert_eq!(tcp.control(), TcpControl::SYN | TcpControl::ACK);
+        assert_eq!(tcp.ack_number(), 42);
+    }
+
+    #[test]
+    fn answers_syn_over_loopback_pair() {
+        let (client, server) = LoopbackDevice::pair();
+        let server: Arc<dyn Device> = Arc::new(server);
+
+        let stack = std::thread::spawn(move || run_nic(vec![server], stack(), 1500));
+
+        client.send_buf(syn(50000, 3000, 41)).unwrap();
+
+        assert_syn_ack(&client.recv_buf().unwrap());
+
+        // Hanging up our end makes the stack's recv fail, which ends the loop.
+        drop(client);
+        assert!(stack.join().unwrap().is_err());
+    }
+
+    #[test]
+    fn traceroute_across_a_router_stops_at_the_destination() {
+        let destination = Ipv4Addr::new(10, 0, 1, 2);
+        let table = RoutingTable::default()
+            .interface(SERVER, "10.0.0.0/24".parse().unwrap())
+            .interface(Ipv4Addr::new(10, 0, 1, 1), "10.0.1.0/24".parse().unwrap());
+        let (client, inside) = LoopbackDevice::pair();
+        let (outside, far_end) = LoopbackDevice::pair();
+        let router: Vec<Arc<dyn Device>> = vec![Arc::new(inside), Arc::new(outside)];
+        std::thread::spawn(move || run_nic(router, stack().router(table), 1500));
+        std::thread::spawn(move || run_nic(vec![Arc::new(far_end)], stack(), 1500));
+
+        let sockets = Sockets::default();
+        let mut socket = PingSocket::bind(&sockets, CLIENT).unwrap();
+        let hops = with_stack(vec![Arc::new(client)], stack_with(sockets), 1500, || {
+            let timeout = Duration::from_secs(1);
+            Ok(application::ping::traceroute(
+                &mut socket,
+                destination,
+                8,
+                timeout,
+                |_| {},
+            )?)
+        })
+        .unwrap();
+
+        let replies: Vec<_> = hops
+            .iter()
+            .map(|hop| hop.reply.map(|reply| (reply.from, reply.kind)))
+            .collect();
+        assert_eq!(
+            replies,
+            [
+                Some((SERVER, ReplyKind::TimeExceeded)),
+                Some((destination, ReplyKind::Echo))
+            ]
+        );
+    }
+
+    #[test]
+    fn replays_capture_and_records_replies() {
+        let mut input = PcapWriter::new(Vec::new()).unwrap();
+        input.write_packet(&syn(50000, 3000, 41)).unwrap();
+        let input = PcapReader::new(std::io::Cursor::new(input.into_inner())).unwrap();
+
+        let path = std::env::temp_dir().join(format!("rusnet-replay-{}.pcap", std::process::id()));
+        let output = PcapWriter::new(File::create(&path).unwrap()).unwrap();
+
+        let nic: Arc<dyn Device> = Arc::new(ReplayDevice::new(input, output));
+        run_nic(vec![nic], stack(), 1500).unwrap();
+
+        let mut replies = PcapReader::new(File::open(&path).unwrap()).unwrap();
+        assert_syn_ack(&replies.next_packet().unwrap().unwrap().data);
+        assert!(replies.next_packet().unwrap().is_none());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
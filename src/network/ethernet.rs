@@ -0,0 +1,337 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::Ipv4Addr,
+    time::Duration,
+};
+
+use crate::{
+    proto::{
+        NetworkBuffer,
+        arp::{Arp, ArpOperation, ArpWriter},
+        ethernet::{EtherType, Ethernet, EthernetWriter, MacAddr},
+        ip::IpPacket,
+    },
+    time::Instant,
+};
+
+/// How long a learned address stays in the cache.
+const CACHE_LIFETIME: Duration = Duration::from_secs(60);
+/// How often an unanswered request is repeated while packets wait on it.
+const REQUEST_INTERVAL: Duration = Duration::from_secs(1);
+/// How long packets wait for an address to resolve before they're dropped.
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Packets held per unresolved address, older ones are dropped first.
+const MAX_PENDING: usize = 3;
+
+struct Pending {
+    since: Instant,
+    requested: Instant,
+    packets: VecDeque<NetworkBuffer>,
+}
+
+/// Ethernet II framing for TAP mode. Unwraps IPv4 packets for the layers
+/// above, answers ARP requests for our addresses and resolves the hardware
+/// address of everything we send.
+pub struct EthernetHandler {
+    mac: MacAddr,
+    address: Ipv4Addr,
+    // Address to the hardware address behind it, and when that expires.
+    cache: HashMap<Ipv4Addr, (MacAddr, Instant)>,
+    pending: HashMap<Ipv4Addr, Pending>,
+    transmit: VecDeque<NetworkBuffer>,
+}
+
+impl EthernetHandler {
+    pub fn new(mac: MacAddr, address: Ipv4Addr) -> Self {
+        Self {
+            mac,
+            address,
+            cache: HashMap::new(),
+            pending: HashMap::new(),
+            transmit: VecDeque::new(),
+        }
+    }
+
+    pub fn lookup(&self, now: Instant, address: Ipv4Addr) -> Option<MacAddr> {
+        if address == Ipv4Addr::BROADCAST {
+            return Some(MacAddr::BROADCAST);
+        }
+
+        let (mac, expires) = self.cache.get(&address)?;
+        (*expires > now).then_some(*mac)
+    }
+
+    /// Handles a frame from the wire, returning the IPv4 packet it carries
+    /// if there's one for the layers above. ARP requests are answered for
+    /// our address and whatever else `is_ours`, like aliases.
+    pub fn receive<'a>(
+        &mut self,
+        now: Instant,
+        frame: &'a [u8],
+        is_ours: impl Fn(Ipv4Addr) -> bool,
+    ) -> anyhow::Result<Option<&'a [u8]>> {
+        let frame = Ethernet::parse(frame)?;
+        tracing::info!("Ethernet: {}", frame);
+
+        let destination = frame.destination();
+        if destination != self.mac && destination != MacAddr::BROADCAST {
+            return Ok(None);
+        }
+
+        match frame.ether_type() {
+            EtherType::Ipv4 => {
+                // Short packets come padded up to the minimum frame size,
+                // parsing the IP header cuts that off again.
+                Ok(Some(frame.payload()))
+            }
+            EtherType::Arp => {
+                self.handle_arp(now, Arp::parse(frame.payload())?, is_ours);
+                Ok(None)
+            }
+            EtherType::Unknown(ether_type) => {
+                tracing::info!("Ignoring frame with EtherType {:#06x}", ether_type);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Frames an IPv4 packet, holding it back while its destination is being resolved.
+    pub fn send(&mut self, now: Instant, packet: NetworkBuffer) {
+        let destination = match IpPacket::parse(&packet) {
+            Ok(IpPacket::V4(ip)) => ip.destination(),
+            Ok(IpPacket::V6(_)) => {
+                tracing::warn!("Dropping IPv6 packet, there's no neighbour discovery in TAP mode");
+                return;
+            }
+            Err(e) => {
+                tracing::warn!(?e, "Dropping malformed outgoing packet");
+                return;
+            }
+        };
+
+        if let Some(mac) = self.lookup(now, destination) {
+            self.transmit
+                .push_back(self.frame(mac, EtherType::Ipv4, &packet));
+            return;
+        }
+
+        let new = !self.pending.contains_key(&destination);
+        let pending = self.pending.entry(destination).or_insert_with(|| Pending {
+            since: now,
+            requested: now,
+            packets: VecDeque::new(),
+        });
+
+        if pending.packets.len() == MAX_PENDING {
+            pending.packets.pop_front();
+        }
+        pending.packets.push_back(packet);
+
+        // `poll` repeats it from here on.
+        if new {
+            self.request(destination);
+        }
+    }
+
+    /// Forgets stale cache entries, repeats unanswered requests and gives
+    /// up on addresses that never resolved.
+    pub fn poll(&mut self, now: Instant) {
+        self.cache.retain(|_, (_, expires)| *expires > now);
+        self.pending.retain(|address, pending| {
+            let waiting = now.saturating_duration_since(pending.since) < RESOLVE_TIMEOUT;
+            if !waiting {
+                tracing::warn!(%address, dropped = pending.packets.len(), "ARP resolution failed");
+            }
+            waiting
+        });
+
+        let mut unanswered: Vec<_> = self
+            .pending
+            .iter_mut()
+            .filter(|(_, pending)| pending.requested + REQUEST_INTERVAL <= now)
+            .map(|(address, pending)| {
+                pending.requested = now;
+                *address
+            })
+            .collect();
+        // Keep the order stable, simulations depend on it.
+        unanswered.sort();
+        for address in unanswered {
+            self.request(address);
+        }
+    }
+
+    /// When `poll` next has a request to repeat or an address to give up on.
+    pub fn poll_at(&self) -> Option<Instant> {
+        self.pending
+            .values()
+            .map(|pending| {
+                (pending.requested + REQUEST_INTERVAL).min(pending.since + RESOLVE_TIMEOUT)
+            })
+            .min()
+    }
+
+    pub fn poll_transmit(&mut self) -> Option<NetworkBuffer> {
+        self.transmit.pop_front()
+    }
+
+    fn handle_arp(&mut self, now: Instant, arp: Arp, is_ours: impl Fn(Ipv4Addr) -> bool) {
+        tracing::info!("Arp: {}", arp);
+
+        let (sender, target) = (arp.sender_ip(), arp.target_ip());
+        let for_us = target == self.address || is_ours(target);
+
+        // RFC 826: refresh what we know about the sender, and only learn
+        // new senders when the packet is meant for us.
+        if for_us || self.cache.contains_key(&sender) {
+            self.learn(now, sender, arp.sender_mac());
+        }
+
+        if for_us && arp.operation() == ArpOperation::Request {
+            let reply = ArpWriter::new(
+                ArpOperation::Reply,
+                (self.mac, target),
+                (arp.sender_mac(), sender),
+            )
+            .into_buf();
+            self.transmit
+                .push_back(self.frame(arp.sender_mac(), EtherType::Arp, &reply));
+        }
+    }
+
+    fn request(&mut self, address: Ipv4Addr) {
+        let request = ArpWriter::new(
+            ArpOperation::Request,
+            (self.mac, self.address),
+            (MacAddr::ZERO, address),
+        )
+        .into_buf();
+        self.transmit
+            .push_back(self.frame(MacAddr::BROADCAST, EtherType::Arp, &request));
+    }
+
+    fn learn(&mut self, now: Instant, address: Ipv4Addr, mac: MacAddr) {
+        self.cache.insert(address, (mac, now + CACHE_LIFETIME));
+
+        if let Some(pending) = self.pending.remove(&address) {
+            for packet in pending.packets {
+                self.transmit
+                    .push_back(self.frame(mac, EtherType::Ipv4, &packet));
+            }
+        }
+    }
+
+    fn frame(&self, destination: MacAddr, ether_type: EtherType, payload: &[u8]) -> NetworkBuffer {
+        EthernetWriter::new(destination, self.mac, ether_type)
+            .data(payload)
+            .into_buf()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::ip::Ip;
+
+    use super::*;
+
+    const OURS: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 1]);
+    const THEIRS: MacAddr = MacAddr([0x02, 0, 0, 0, 0, 2]);
+    const ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+
+    fn no_aliases(_: Ipv4Addr) -> bool {
+        false
+    }
+
+    fn arp_frame(operation: ArpOperation, target: (MacAddr, Ipv4Addr)) -> NetworkBuffer {
+        let arp = ArpWriter::new(operation, (THEIRS, PEER), target).into_buf();
+        let destination = match operation {
+            ArpOperation::Request => MacAddr::BROADCAST,
+            _ => target.0,
+        };
+        EthernetWriter::new(destination, THEIRS, EtherType::Arp)
+            .data(&arp)
+            .into_buf()
+    }
+
+    #[test]
+    fn answers_requests_and_resolves_before_sending() {
+        let mut ethernet = EthernetHandler::new(OURS, ADDRESS);
+        let now = Instant::from_millis(1);
+
+        // Requests for someone else are ignored.
+        let other = arp_frame(
+            ArpOperation::Request,
+            (MacAddr::ZERO, Ipv4Addr::new(10, 0, 0, 9)),
+        );
+        assert_eq!(ethernet.receive(now, &other, no_aliases).unwrap(), None);
+        assert!(ethernet.poll_transmit().is_none());
+
+        // An outgoing packet to an unknown address asks for it first.
+        let packet = crate::proto::ip::IpHeaderWriter::new(
+            ADDRESS,
+            PEER,
+            crate::proto::Protocol::UDP,
+            64,
+            vec![0; 8].into(),
+        )
+        .into_buf();
+        ethernet.send(now, packet);
+
+        let request = ethernet.poll_transmit().unwrap();
+        let frame = Ethernet::parse(&request).unwrap();
+        assert_eq!(frame.destination(), MacAddr::BROADCAST);
+        let arp = Arp::parse(frame.payload()).unwrap();
+        assert_eq!(arp.operation(), ArpOperation::Request);
+        assert_eq!((arp.sender_mac(), arp.sender_ip()), (OURS, ADDRESS));
+        assert_eq!(arp.target_ip(), PEER);
+        assert!(ethernet.poll_transmit().is_none());
+
+        // Unanswered, it goes out again a second later.
+        assert_eq!(ethernet.poll_at(), Some(now + REQUEST_INTERVAL));
+        ethernet.poll(now + REQUEST_INTERVAL);
+        let repeated = ethernet.poll_transmit().unwrap();
+        assert_eq!(repeated.as_slice(), request.as_slice());
+        assert!(ethernet.poll_transmit().is_none());
+
+        // Their request for us gets answered, and releases the held packet.
+        let request = arp_frame(ArpOperation::Request, (MacAddr::ZERO, ADDRESS));
+        assert_eq!(ethernet.receive(now, &request, no_aliases).unwrap(), None);
+
+        let released = ethernet.poll_transmit().unwrap();
+        let frame = Ethernet::parse(&released).unwrap();
+        assert_eq!(frame.destination(), THEIRS);
+        assert_eq!(frame.ether_type(), EtherType::Ipv4);
+        assert_eq!(Ip::parse(frame.payload()).unwrap().destination(), PEER);
+
+        let reply = ethernet.poll_transmit().unwrap();
+        let frame = Ethernet::parse(&reply).unwrap();
+        assert_eq!(frame.destination(), THEIRS);
+        let arp = Arp::parse(frame.payload()).unwrap();
+        assert_eq!(arp.operation(), ArpOperation::Reply);
+        assert_eq!((arp.sender_mac(), arp.sender_ip()), (OURS, ADDRESS));
+        assert_eq!((arp.target_mac(), arp.target_ip()), (THEIRS, PEER));
+
+        assert_eq!(ethernet.lookup(now, PEER), Some(THEIRS));
+        assert_eq!(ethernet.lookup(now + CACHE_LIFETIME, PEER), None);
+    }
+
+    #[test]
+    fn answers_requests_for_aliases() {
+        let mut ethernet = EthernetHandler::new(OURS, ADDRESS);
+        let now = Instant::from_millis(1);
+        let alias = Ipv4Addr::new(10, 0, 0, 5);
+
+        let request = arp_frame(ArpOperation::Request, (MacAddr::ZERO, alias));
+        assert_eq!(ethernet.receive(now, &request, no_aliases).unwrap(), None);
+        assert!(ethernet.poll_transmit().is_none());
+
+        let is_ours = |address| address == alias;
+        assert_eq!(ethernet.receive(now, &request, is_ours).unwrap(), None);
+        let reply = ethernet.poll_transmit().unwrap();
+        let arp = Arp::parse(Ethernet::parse(&reply).unwrap().payload()).unwrap();
+        assert_eq!(arp.operation(), ArpOperation::Reply);
+        assert_eq!((arp.sender_mac(), arp.sender_ip()), (OURS, alias));
+        assert_eq!((arp.target_mac(), arp.target_ip()), (THEIRS, PEER));
+    }
+}
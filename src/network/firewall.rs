@@ -0,0 +1,568 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::Display,
+    net::IpAddr,
+    str::FromStr,
+    time::Duration,
+};
+
+use anyhow::{Context, bail};
+use serde::Deserialize;
+
+use crate::{
+    proto::{Protocol, ip::IpPacket, tcp::TcpControl},
+    time::{Instant, Timers},
+    utils,
+};
+
+use super::{Timer, routing::Ipv4Prefix};
+
+/// How long a tracked flow may idle before replies stop being let through.
+const TCP_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+/// Until the other end answers, a connection is only being attempted.
+const TCP_UNREPLIED_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+/// After a FIN or RST there's only the tail end of the connection left.
+const TCP_CLOSING_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+const UDP_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+const OTHER_TIMEOUT: Duration = Duration::from_secs(30);
+/// Flows tracked at most, the least recently seen make room for new ones.
+const MAX_CONNECTIONS: usize = 65_536;
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    #[default]
+    Allow,
+    /// Drops the packet without a word.
+    Deny,
+    /// Drops the packet, and tells the sender with a RST or an ICMP error.
+    Reject,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RuleProtocol {
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+impl From<RuleProtocol> for Protocol {
+    fn from(value: RuleProtocol) -> Self {
+        match value {
+            RuleProtocol::Tcp => Protocol::TCP,
+            RuleProtocol::Udp => Protocol::UDP,
+            RuleProtocol::Icmp => Protocol::ICMP,
+        }
+    }
+}
+
+/// `80` or `8000-8080`, both ends included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    start: u16,
+    end: u16,
+}
+
+impl PortRange {
+    pub fn contains(&self, port: u16) -> bool {
+        (self.start..=self.end).contains(&port)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s.split_once('-').unwrap_or((s, s));
+        let port = |port: &str| {
+            port.trim()
+                .parse::<u16>()
+                .with_context(|| format!("Invalid port: {}", port))
+        };
+        let (start, end) = (port(start)?, port(end)?);
+        if start > end {
+            bail!("Port range {} ends before it starts", s);
+        }
+        Ok(Self { start, end })
+    }
+}
+
+impl Display for PortRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PortRange {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Port {
+            Single(u16),
+            Range(String),
+        }
+
+        match Port::deserialize(deserializer)? {
+            Port::Single(port) => Ok(Self {
+                start: port,
+                end: port,
+            }),
+            Port::Range(range) => range.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// TCP flags that have to be set, or with a `!` in front cleared, eg.
+/// `syn,!ack` for the first segment of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpFlags {
+    mask: TcpControl,
+    set: TcpControl,
+}
+
+impl TcpFlags {
+    pub fn matches(&self, control: TcpControl) -> bool {
+        control.intersection(self.mask) == self.set
+    }
+}
+
+impl FromStr for TcpFlags {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut flags = Self {
+            mask: TcpControl::empty(),
+            set: TcpControl::empty(),
+        };
+        for flag in s.split(',').map(str::trim) {
+            let (cleared, name) = match flag.strip_prefix('!') {
+                Some(name) => (true, name),
+                None => (false, flag),
+            };
+            let control = TcpControl::from_name(&name.to_ascii_uppercase())
+                .with_context(|| format!("Unknown TCP flag: {}", name))?;
+            flags.mask |= control;
+            if !cleared {
+                flags.set |= control;
+            }
+        }
+        Ok(flags)
+    }
+}
+
+impl Display for TcpFlags {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, (name, flag)) in self.mask.iter_names().enumerate() {
+            let cleared = if self.set.contains(flag) { "" } else { "!" };
+            let comma = if i == 0 { "" } else { "," };
+            write!(f, "{}{}{}", comma, cleared, name.to_ascii_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for TcpFlags {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// One line of the rule set. Whatever a rule leaves out matches anything.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    pub action: Action,
+    #[serde(default)]
+    pub source: Option<Ipv4Prefix>,
+    #[serde(default)]
+    pub destination: Option<Ipv4Prefix>,
+    #[serde(default)]
+    pub protocol: Option<RuleProtocol>,
+    #[serde(default)]
+    pub source_port: Option<PortRange>,
+    #[serde(default)]
+    pub destination_port: Option<PortRange>,
+    /// Only for TCP, other protocols never match a rule with flags.
+    #[serde(default)]
+    pub tcp_flags: Option<TcpFlags>,
+}
+
+impl Rule {
+    fn matches(&self, packet: &Packet) -> bool {
+        let prefix = |prefix: Option<Ipv4Prefix>, address: IpAddr| match (prefix, address) {
+            (None, _) => true,
+            (Some(prefix), IpAddr::V4(address)) => prefix.contains(address),
+            // The prefixes are IPv4 only.
+            (Some(_), IpAddr::V6(_)) => false,
+        };
+        let port = |range: Option<PortRange>, port: Option<u16>| match (range, port) {
+            (None, _) => true,
+            (Some(range), Some(port)) => range.contains(port),
+            (Some(_), None) => false,
+        };
+
+        prefix(self.source, packet.flow.source)
+            && prefix(self.destination, packet.flow.destination)
+            && self
+                .protocol
+                .is_none_or(|protocol| Protocol::from(protocol) == packet.protocol)
+            && port(self.source_port, packet.ports.map(|(source, _)| source))
+            && port(
+                self.destination_port,
+                packet.ports.map(|(_, destination)| destination),
+            )
+            && self
+                .tcp_flags
+                .is_none_or(|flags| packet.tcp.is_some_and(|control| flags.matches(control)))
+    }
+}
+
+impl Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let endpoint = |f: &mut std::fmt::Formatter<'_>,
+                        direction: &str,
+                        prefix: Option<Ipv4Prefix>,
+                        ports: Option<PortRange>| {
+            match (prefix, ports) {
+                (None, None) => return Ok(()),
+                (Some(prefix), _) => write!(f, " {} {}", direction, prefix)?,
+                (None, Some(_)) => write!(f, " {} any", direction)?,
+            }
+            match ports {
+                Some(ports) => write!(f, " port {}", ports),
+                None => Ok(()),
+            }
+        };
+
+        write!(f, "{:?}", self.action)?;
+        if let Some(protocol) = self.protocol {
+            write!(f, " {:?}", protocol)?;
+        }
+        endpoint(f, "from", self.source, self.source_port)?;
+        endpoint(f, "to", self.destination, self.destination_port)?;
+        if let Some(flags) = self.tcp_flags {
+            write!(f, " flags {}", flags)?;
+        }
+        Ok(())
+    }
+}
+
+/// One direction of a flow, ports are zero for protocols without them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct FlowKey {
+    protocol: u8,
+    source: IpAddr,
+    source_port: u16,
+    destination: IpAddr,
+    destination_port: u16,
+}
+
+impl FlowKey {
+    fn reversed(&self) -> Self {
+        Self {
+            protocol: self.protocol,
+            source: self.destination,
+            source_port: self.destination_port,
+            destination: self.source,
+            destination_port: self.source_port,
+        }
+    }
+}
+
+/// What the rules look at.
+struct Packet {
+    protocol: Protocol,
+    flow: FlowKey,
+    ports: Option<(u16, u16)>,
+    tcp: Option<TcpControl>,
+}
+
+impl Packet {
+    fn of(ip: IpPacket) -> Self {
+        let protocol = ip.protocol();
+        let transport = ip.remainder();
+        let ports = match protocol {
+            Protocol::TCP | Protocol::UDP if transport.len() >= 4 => {
+                Some((utils::read_u16(transport), utils::read_u16(&transport[2..])))
+            }
+            _ => None,
+        };
+        let tcp = match protocol {
+            Protocol::TCP if transport.len() >= 14 => {
+                Some(TcpControl::from_bits_retain(transport[13]))
+            }
+            _ => None,
+        };
+        let (source_port, destination_port) = ports.unwrap_or_default();
+
+        Self {
+            protocol,
+            flow: FlowKey {
+                protocol: protocol.into(),
+                source: ip.source(),
+                source_port,
+                destination: ip.destination(),
+                destination_port,
+            },
+            ports,
+            tcp,
+        }
+    }
+
+    /// How long its flow is tracked for, `replied` once anything came
+    /// back the other way.
+    fn timeout(&self, replied: bool) -> Duration {
+        match (self.protocol, self.tcp) {
+            (Protocol::TCP, Some(control))
+                if control.intersects(TcpControl::FIN | TcpControl::RST) =>
+            {
+                TCP_CLOSING_TIMEOUT
+            }
+            (Protocol::TCP, _) if !replied => TCP_UNREPLIED_TIMEOUT,
+            (Protocol::TCP, _) => TCP_TIMEOUT,
+            (Protocol::UDP, _) => UDP_TIMEOUT,
+            _ => OTHER_TIMEOUT,
+        }
+    }
+}
+
+struct Connection {
+    last_seen: Instant,
+    timeout: Duration,
+    replied: bool,
+}
+
+/// Ordered allow, deny and reject rules, the first match decides. Flows a
+/// rule allowed, or that we started, are tracked so what comes back for
+/// them passes without going through the rules again.
+pub struct Firewall {
+    rules: Vec<Rule>,
+    /// Packets each rule decided on, by position.
+    hits: Vec<u64>,
+    /// What happens to packets no rule matches.
+    default: Action,
+    /// Flows by the direction they were allowed in.
+    connections: HashMap<FlowKey, Connection>,
+    /// The same flows by when they were last seen, oldest first.
+    by_age: BTreeSet<(Instant, FlowKey)>,
+    max_connections: usize,
+    timers: Timers<Timer>,
+}
+
+impl Firewall {
+    pub fn new(rules: Vec<Rule>, default: Action, timers: Timers<Timer>) -> Self {
+        Self {
+            hits: vec![0; rules.len()],
+            rules,
+            default,
+            connections: HashMap::new(),
+            by_age: BTreeSet::new(),
+            max_connections: MAX_CONNECTIONS,
+            timers,
+        }
+    }
+
+    /// How many packets each rule decided on, in the order of `rules`.
+    pub fn hits(&self) -> &[u64] {
+        &self.hits
+    }
+
+    #[cfg(test)]
+    pub fn connections(&self) -> usize {
+        self.connections.len()
+    }
+
+    /// What to do with a packet arriving from the network.
+    pub fn filter(&mut self, now: Instant, ip: IpPacket) -> Action {
+        let packet = Packet::of(ip);
+        if self.refresh(now, &packet) {
+            return Action::Allow;
+        }
+
+        let rule = self.rules.iter().position(|rule| rule.matches(&packet));
+        let action = match rule {
+            Some(index) => {
+                self.hits[index] += 1;
+                self.rules[index].action
+            }
+            None => self.default,
+        };
+
+        if action == Action::Allow {
+            self.track(now, &packet);
+        } else {
+            tracing::debug!(
+                rule = rule.map(|index| self.rules[index].to_string()),
+                flow = ?packet.flow,
+                ?action,
+                "Firewall dropped packet"
+            );
+        }
+        action
+    }
+
+    /// Lets replies to something we send through.
+    pub fn outbound(&mut self, now: Instant, ip: IpPacket) {
+        let packet = Packet::of(ip);
+        if !self.refresh(now, &packet) {
+            self.track(now, &packet);
+        }
+    }
+
+    /// Forgets a flow that went idle, or checks back later if it didn't.
+    pub fn expire(&mut self, now: Instant, key: FlowKey) {
+        let Some(connection) = self.connections.get(&key) else {
+            return;
+        };
+        let idle = now.saturating_duration_since(connection.last_seen);
+        if idle < connection.timeout {
+            self.timers
+                .schedule(connection.timeout - idle, Timer::Conntrack(key));
+            return;
+        }
+        self.forget(key);
+    }
+
+    /// Marks the flow of `packet` as in use, in whichever direction it's
+    /// tracked. False if it isn't.
+    fn refresh(&mut self, now: Instant, packet: &Packet) -> bool {
+        for (key, reply) in [(packet.flow, false), (packet.flow.reversed(), true)] {
+            if let Some(connection) = self.connections.get_mut(&key) {
+                self.by_age.remove(&(connection.last_seen, key));
+                self.by_age.insert((now, key));
+                connection.last_seen = now;
+                connection.replied |= reply;
+                connection.timeout = packet.timeout(connection.replied);
+                return true;
+            }
+        }
+        false
+    }
+
+    fn track(&mut self, now: Instant, packet: &Packet) {
+        if self.connections.len() >= self.max_connections {
+            self.evict_oldest();
+        }
+
+        let timeout = packet.timeout(false);
+        self.by_age.insert((now, packet.flow));
+        self.connections.insert(
+            packet.flow,
+            Connection {
+                last_seen: now,
+                timeout,
+                replied: false,
+            },
+        );
+        self.timers.schedule(timeout, Timer::Conntrack(packet.flow));
+    }
+
+    /// Its timer finds it gone and lets it be. Ties go by key, simulations
+    /// depend on the order.
+    fn evict_oldest(&mut self) {
+        if let Some(&(_, key)) = self.by_age.first() {
+            tracing::debug!(flow = ?key, "Connection table full, evicting oldest flow");
+            self.forget(key);
+        }
+    }
+
+    fn forget(&mut self, key: FlowKey) {
+        if let Some(connection) = self.connections.remove(&key) {
+            self.by_age.remove(&(connection.last_seen, key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::proto::{NetworkBuffer, ip::IpHeaderWriter, tcp::TcpHeaderWriter};
+
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 2);
+
+    #[test]
+    fn parses_rules_from_config() {
+        let rules: Vec<Rule> = serde_json::from_str(
+            r#"[
+                { "action": "allow", "protocol": "tcp", "destination_port": 80 },
+                { "action": "reject", "source": "10.0.0.0/8", "destination_port": "8000-8080" },
+                { "action": "deny", "protocol": "tcp", "tcp_flags": "syn,!ack" }
+            ]"#,
+        )
+        .unwrap();
+
+        assert_eq!(rules[0].destination_port, Some("80".parse().unwrap()));
+        assert!(rules[1].destination_port.unwrap().contains(8042));
+        let flags = rules[2].tcp_flags.unwrap();
+        assert!(flags.matches(TcpControl::SYN));
+        assert!(!flags.matches(TcpControl::SYN | TcpControl::ACK));
+
+        assert_eq!(rules[0].to_string(), "Allow Tcp to any port 80");
+        assert_eq!(
+            rules[1].to_string(),
+            "Reject from 10.0.0.0/8 to any port 8000-8080"
+        );
+        assert_eq!(rules[2].to_string(), "Deny Tcp flags syn,!ack");
+        let rule: Rule = serde_json::from_str(
+            r#"{ "action": "allow", "source_port": 53, "destination": "10.0.1.0/24" }"#,
+        )
+        .unwrap();
+        assert_eq!(rule.to_string(), "Allow from any port 53 to 10.0.1.0/24");
+        assert!("syn,bogus".parse::<TcpFlags>().is_err());
+        assert!("90-80".parse::<PortRange>().is_err());
+    }
+
+    fn segment(from: (Ipv4Addr, u16), to: (Ipv4Addr, u16), control: TcpControl) -> NetworkBuffer {
+        let tcp = TcpHeaderWriter::new(from.1, to.1, 0, 0)
+            .set(control)
+            .into_buf();
+        IpHeaderWriter::new(from.0, to.0, Protocol::TCP, 64, tcp).into_buf()
+    }
+
+    #[test]
+    fn unanswered_flows_expire_early_and_the_oldest_make_room() {
+        let mut firewall = Firewall::new(vec![], Action::Allow, Timers::default());
+        firewall.max_connections = 2;
+        let syn_from = |firewall: &mut Firewall, now, port| {
+            let syn = segment((CLIENT, port), (SERVER, 80), TcpControl::SYN);
+            let packet = IpPacket::parse(&syn).unwrap();
+            assert_eq!(firewall.filter(now, packet), Action::Allow);
+            Packet::of(packet).flow
+        };
+
+        let start = Instant::from_millis(1);
+        let unanswered = syn_from(&mut firewall, start, 50000);
+        let answered = syn_from(&mut firewall, start, 50001);
+        let syn_ack = segment(
+            (SERVER, 80),
+            (CLIENT, 50001),
+            TcpControl::SYN | TcpControl::ACK,
+        );
+        firewall.outbound(start, IpPacket::parse(&syn_ack).unwrap());
+
+        let later = start + TCP_UNREPLIED_TIMEOUT;
+        firewall.expire(later, unanswered);
+        firewall.expire(later, answered);
+        assert!(!firewall.connections.contains_key(&unanswered));
+        assert!(firewall.connections.contains_key(&answered));
+
+        // Full, so the answered flow seen longest ago goes.
+        let newer = syn_from(&mut firewall, later, 50002);
+        let newest = syn_from(&mut firewall, later, 50003);
+        assert_eq!(firewall.connections(), 2);
+        assert_eq!(firewall.by_age.len(), 2);
+        assert!(!firewall.connections.contains_key(&answered));
+        assert!(firewall.connections.contains_key(&newer));
+        assert!(firewall.connections.contains_key(&newest));
+    }
+}
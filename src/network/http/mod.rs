@@ -4,7 +4,11 @@ pub use utils::*;
 
 use crate::{
     application,
-    proto::{NetworkBuffer, ProtocolBuffer, http::HttpReq, tcp::Tcp},
+    proto::{
+        NetworkBuffer, ProtocolBuffer,
+        http::{HttpReq, HttpResp},
+        tcp::Tcp,
+    },
 };
 
 use super::Handler;
@@ -28,13 +32,21 @@ const RESPONSE: &[u8; 19] = b"HTTP/1.1 404 OK\r\n\r\n";
 impl<P: ProtocolBuffer> Handler<Tcp<P>> for HttpHandler {
     type ReturnType = (NetworkBuffer, Tcp<P>);
     fn handle(&mut self, msg: Tcp<P>) -> anyhow::Result<Self::ReturnType> {
-        let http = HttpReq::parse(msg);
+        // The segment goes back to TCP either way, a request it doesn't
+        // hold gets a 400 rather than an error.
+        let http = match HttpReq::parse(&msg) {
+            Ok(http) => http,
+            Err(e) => {
+                tracing::warn!("Bad HTTP request: {}", e);
+                return Ok((HttpResp::bad_request().into_buf(), msg));
+            }
+        };
 
         tracing::info!("{}", http);
 
         let buf = if let Some(server) = self.server.as_mut() {
             let resp = server.on_request(&http);
-            resp.to_buf()
+            resp.into_buf()
         } else {
             let mut buf = NetworkBuffer::new(RESPONSE.len());
 
@@ -43,6 +55,6 @@ impl<P: ProtocolBuffer> Handler<Tcp<P>> for HttpHandler {
             buf
         };
 
-        Ok((buf, http.into_inner()))
+        Ok((buf, msg))
     }
 }
@@ -8,20 +8,20 @@ use crate::proto::{
 pub struct ReqBody<T: DeserializeOwned>(pub T);
 pub struct ReqPath(pub String);
 
-pub trait FromHttpRequest<P: ProtocolBuffer> {
-    fn from_context(context: &HttpReq<P>) -> Self;
+pub trait FromHttpRequest<P: ProtocolBuffer>: Sized {
+    /// `None` if the request doesn't hold what the handler takes.
+    fn from_context(context: &HttpReq<P>) -> Option<Self>;
 }
 
 impl<P: ProtocolBuffer> FromHttpRequest<P> for ReqPath {
-    fn from_context(context: &HttpReq<P>) -> Self {
-        ReqPath(context.path().to_owned())
+    fn from_context(context: &HttpReq<P>) -> Option<Self> {
+        Some(ReqPath(context.path().to_owned()))
     }
 }
 
 impl<T: DeserializeOwned, P: ProtocolBuffer> FromHttpRequest<P> for ReqBody<T> {
-    fn from_context(context: &HttpReq<P>) -> Self {
-        let data = serde_json::from_str(context.data()).unwrap();
-        ReqBody(data)
+    fn from_context(context: &HttpReq<P>) -> Option<Self> {
+        serde_json::from_str(context.data()).ok().map(ReqBody)
     }
 }
 
@@ -36,7 +36,10 @@ where
     P: ProtocolBuffer,
 {
     fn call(self, context: &HttpReq<P>) -> HttpResp {
-        (self)(T::from_context(context))
+        match T::from_context(context) {
+            Some(t) => (self)(t),
+            None => HttpResp::bad_request(),
+        }
     }
 }
 
@@ -1,16 +1,204 @@
-use crate::proto::{NetworkBuffer, ProtocolBuffer, icmp::Icmp};
+use std::net::IpAddr;
 
-use super::Handler;
+use crate::{
+    proto::{
+        NetworkBuffer, Protocol,
+        icmp::{self, Echo, Icmp, IcmpMessage, Quote, QuotedTransport},
+        ip::{self, IpPacket},
+    },
+    socket::{Sockets, icmp::ReplyKind},
+    time::{RateLimit, Timers},
+};
 
-pub struct IcmpHandler;
+use super::{Handler, Timer};
 
-impl<P: ProtocolBuffer> Handler<P> for IcmpHandler {
+/// Echo replies sent per second once the burst is used up.
+const DEFAULT_RATE: u32 = 100;
+/// Echo replies sent back to back before the rate kicks in.
+const DEFAULT_BURST: u32 = 50;
+/// Largest echo payload answered, what fits a 1500 byte MTU unfragmented.
+/// Bigger requests are dropped, a truncated reply wouldn't match.
+const DEFAULT_MAX_PAYLOAD: usize = 1472;
+
+/// Answers pings, and sends those of `PingSocket`s. Replies are rate
+/// limited, so a flood of requests can't have us send just as many back.
+pub struct IcmpHandler {
+    timers: Timers<Timer>,
+    replies: RateLimit,
+    max_payload: usize,
+    sockets: Sockets,
+}
+
+impl IcmpHandler {
+    /// `timers` is only read, for the time requests arrive at.
+    pub fn new(timers: Timers<Timer>, sockets: Sockets) -> Self {
+        Self {
+            timers,
+            replies: RateLimit::new(DEFAULT_RATE, DEFAULT_BURST),
+            max_payload: DEFAULT_MAX_PAYLOAD,
+            sockets,
+        }
+    }
+
+    /// Answers at most `per_second` requests a second, after `burst` of
+    /// them in a row.
+    pub fn rate_limit(mut self, per_second: u32, burst: u32) -> Self {
+        self.replies = RateLimit::new(per_second, burst);
+        self
+    }
+
+    /// Drops requests with more than `bytes` of payload.
+    pub fn max_payload(mut self, bytes: usize) -> Self {
+        self.max_payload = bytes;
+        self
+    }
+
+    /// Sends the echo requests applications queued, as complete IP packets.
+    pub fn poll(&mut self) -> Vec<NetworkBuffer> {
+        self.sockets
+            .icmp_take(self.timers.now())
+            .into_iter()
+            .map(|probe| {
+                let request = IcmpMessage::EchoRequest(Echo {
+                    identifier: probe.identifier,
+                    sequence: probe.sequence,
+                    data: &probe.data,
+                });
+                let (source, destination) = (probe.source.into(), probe.destination.into());
+                ip::write_packet(
+                    source,
+                    destination,
+                    Protocol::ICMP,
+                    probe.ttl,
+                    request.into_buf(),
+                )
+            })
+            .collect()
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.sockets.icmp_has_pending()
+    }
+
+    /// Hands an echo reply, or an error quoting one of our echo requests,
+    /// to the socket that sent the request.
+    fn deliver(&self, from: IpAddr, message: &IcmpMessage) {
+        let IpAddr::V4(from) = from else {
+            return;
+        };
+        let (identifier, sequence, kind) = match message {
+            IcmpMessage::EchoReply(echo) => (echo.identifier, echo.sequence, ReplyKind::Echo),
+            IcmpMessage::TimeExceeded { quote, .. } => {
+                let Some((identifier, sequence)) = quoted_echo(quote) else {
+                    return;
+                };
+                (identifier, sequence, ReplyKind::TimeExceeded)
+            }
+            IcmpMessage::DestinationUnreachable { reason, quote } => {
+                let Some((identifier, sequence)) = quoted_echo(quote) else {
+                    return;
+                };
+                (identifier, sequence, ReplyKind::Unreachable(*reason))
+            }
+            _ => return,
+        };
+
+        let now = self.timers.now();
+        if !self
+            .sockets
+            .icmp_received(now, identifier, sequence, from, kind)
+        {
+            tracing::debug!(%from, identifier, sequence, "Nobody waiting for this ICMP reply");
+        }
+    }
+}
+
+/// Identifier and sequence number of the echo request an error quotes.
+fn quoted_echo(quote: &Quote) -> Option<(u16, u16)> {
+    match quote.transport().ok()? {
+        QuotedTransport::Icmp {
+            icmp_type: icmp::ECHO_REQUEST,
+            identifier,
+            sequence,
+            ..
+        } => Some((identifier, sequence)),
+        _ => None,
+    }
+}
+
+impl Handler<IpPacket<'_>> for IcmpHandler {
     type ReturnType = NetworkBuffer;
 
-    fn handle(&mut self, msg: P) -> anyhow::Result<Self::ReturnType> {
-        let icmp_msg = Icmp::parse(msg)?;
+    fn handle(&mut self, ip: IpPacket) -> anyhow::Result<Self::ReturnType> {
+        let icmp_msg = Icmp::parse(ip)?;
         tracing::info!("Icmp: {}", icmp_msg);
 
-        Ok(NetworkBuffer::empty())
+        let echo = match icmp_msg.message()? {
+            IcmpMessage::EchoRequest(echo) => echo,
+            other => {
+                self.deliver(ip.source(), &other);
+                return Ok(NetworkBuffer::empty());
+            }
+        };
+        if echo.data.len() > self.max_payload {
+            tracing::debug!(len = echo.data.len(), "Dropping oversized echo request");
+            return Ok(NetworkBuffer::empty());
+        }
+        if !self.replies.allow(self.timers.now()) {
+            tracing::debug!("Echo replies rate limited");
+            return Ok(NetworkBuffer::empty());
+        }
+
+        Ok(IcmpMessage::EchoReply(echo).into_buf())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use crate::proto::{Protocol, icmp::Echo, ip::IpHeaderWriter};
+
+    use super::*;
+    use crate::time::Instant;
+
+    fn ping(sequence: u16, data: &[u8]) -> NetworkBuffer {
+        let request = IcmpMessage::EchoRequest(Echo {
+            identifier: 0x1234,
+            sequence,
+            data,
+        })
+        .into_buf();
+        let (client, server) = (Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1));
+        IpHeaderWriter::new(client, server, Protocol::ICMP, 64, request).into_buf()
+    }
+
+    #[test]
+    fn answers_pings_within_limits() {
+        let timers = Timers::default();
+        let mut handler = IcmpHandler::new(timers.clone(), Sockets::default())
+            .rate_limit(10, 2)
+            .max_payload(56);
+        let mut handle = |packet: &[u8]| handler.handle(IpPacket::parse(packet).unwrap()).unwrap();
+
+        let reply = handle(&ping(1, b"abcdefgh"));
+        let reply = Icmp::parse(reply).unwrap();
+        assert!(reply.checksum_valid());
+        assert_eq!(
+            reply.message().unwrap(),
+            IcmpMessage::EchoReply(Echo {
+                identifier: 0x1234,
+                sequence: 1,
+                data: b"abcdefgh",
+            })
+        );
+
+        assert!(handle(&ping(2, &[0; 57])).is_empty());
+        assert!(!handle(&ping(3, &[0; 56])).is_empty());
+        // The burst of two is used up, the next token takes 100ms.
+        assert!(handle(&ping(4, b"")).is_empty());
+        timers.advance(Instant::from_millis(100));
+        assert!(!handle(&ping(5, b"")).is_empty());
+        assert!(handle(&ping(6, b"")).is_empty());
     }
 }
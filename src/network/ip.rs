@@ -1,9 +1,22 @@
+use std::net::SocketAddr;
+
 use crate::proto::{
     NetworkBuffer, Protocol,
-    ip::{Ip, IpHeaderWriter},
+    icmp::{Icmp, IcmpMessage, QuotedTransport},
+    ip::{self, IpPacket},
+    tcp::Tcp,
+    udp::Udp,
+};
+
+use super::{
+    Handler,
+    icmp::IcmpHandler,
+    tcp::{Quad, TcpHandler},
+    udp::UdpHandler,
 };
 
-use super::{Handler, icmp::IcmpHandler, tcp::TcpHandler, udp::UdpHandler};
+/// TTL of the replies sent back, whatever the request arrived with.
+const TTL: u8 = 64;
 
 pub struct IpHandler {
     pub icmp: IcmpHandler,
@@ -11,13 +24,100 @@ pub struct IpHandler {
     pub tcp: TcpHandler,
 }
 
-impl Handler<Ip<'_>> for IpHandler {
+/// Why nobody here can take a packet, which decides the error sent back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unreachable {
+    /// A transport protocol we don't speak.
+    Protocol,
+    /// A UDP port nobody is bound to.
+    Port,
+    /// A TCP port nobody listens on, answered with a RST.
+    Reset,
+}
+
+impl IpHandler {
+    /// Whether `ip` is for a protocol or port nobody here takes, checked
+    /// before handing it over. Malformed packets are left for `handle`.
+    pub fn unreachable(&self, ip: IpPacket) -> Option<Unreachable> {
+        if let IpPacket::V6(ipv6) = &ip
+            && ipv6.is_fragment()
+        {
+            return None;
+        }
+
+        match ip.protocol() {
+            Protocol::TCP => {
+                let tcp = Tcp::parse(ip).ok()?;
+                let remote = SocketAddr::new(ip.source(), tcp.source_port());
+                let local = SocketAddr::new(ip.destination(), tcp.destination_port());
+                (!self.tcp.accepts(remote, local)).then_some(Unreachable::Reset)
+            }
+            Protocol::UDP => {
+                let udp = Udp::parse(ip).ok()?;
+                let local = SocketAddr::new(ip.destination(), udp.destination_port());
+                (!self.udp.is_bound(local)).then_some(Unreachable::Port)
+            }
+            Protocol::ICMP => None,
+            _ => Some(Unreachable::Protocol),
+        }
+    }
+
+    /// Hands a destination unreachable about something we sent to the
+    /// connection or socket that sent it, found by the packet it quotes.
+    fn deliver_error(&mut self, ip: IpPacket) {
+        let Ok(icmp) = Icmp::parse(ip) else {
+            return;
+        };
+        let Ok(IcmpMessage::DestinationUnreachable { reason, quote }) = icmp.message() else {
+            return;
+        };
+        let (Ok(quoted), Ok(transport)) = (quote.ip(), quote.transport()) else {
+            tracing::debug!("Dropping ICMP error with a truncated quote");
+            return;
+        };
+
+        // We sent the quoted packet, so its source is our end.
+        let endpoints = |source_port, destination_port| {
+            (
+                SocketAddr::new(quoted.source().into(), source_port),
+                SocketAddr::new(quoted.destination().into(), destination_port),
+            )
+        };
+        match transport {
+            QuotedTransport::Tcp {
+                source_port,
+                destination_port,
+                sequence,
+            } => {
+                let (local, remote) = endpoints(source_port, destination_port);
+                self.tcp
+                    .on_unreachable(Quad::new(remote, local), sequence, reason);
+            }
+            QuotedTransport::Udp {
+                source_port,
+                destination_port,
+            } => {
+                let (local, remote) = endpoints(source_port, destination_port);
+                self.udp.on_unreachable(local, remote, reason);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Handler<IpPacket<'_>> for IpHandler {
     type ReturnType = NetworkBuffer;
 
-    fn handle(&mut self, ip_header: Ip) -> anyhow::Result<Self::ReturnType> {
+    fn handle(&mut self, ip_header: IpPacket) -> anyhow::Result<Self::ReturnType> {
         tracing::info!("IpHeader: {}", ip_header);
 
-        let ttl = ip_header.ttl();
+        if let IpPacket::V6(ip) = &ip_header
+            && ip.is_fragment()
+        {
+            tracing::warn!("Dropping IPv6 fragment, they aren't reassembled");
+            return Ok(NetworkBuffer::empty());
+        }
+
         let src = ip_header.source();
         let dest = ip_header.destination();
         let protocol = ip_header.protocol();
@@ -27,16 +127,95 @@ impl Handler<Ip<'_>> for IpHandler {
         } else if ip_header.protocol() == Protocol::UDP {
             self.udp.handle(ip_header)?
         } else if ip_header.protocol() == Protocol::ICMP {
+            self.deliver_error(ip_header);
             self.icmp.handle(ip_header)?
         } else {
             NetworkBuffer::empty()
         };
 
         if !inner.is_empty() {
-            let writer = IpHeaderWriter::new(dest, src, protocol, ttl, inner);
-            inner = writer.to_buf();
+            inner = ip::write_packet(dest, src, protocol, TTL, inner);
         }
 
         Ok(inner)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv6Addr;
+
+    use crate::{
+        network::{http::HttpHandler, tcp::TcpHandler, udp::UdpHandler},
+        proto::{
+            ipv6::{Ipv6, Ipv6HeaderWriter},
+            tcp::{TcpControl, TcpHeaderWriter},
+            udp::UdpHeaderWriter,
+        },
+        socket::{Sockets, UdpSocket},
+        time::Timers,
+    };
+
+    use super::*;
+
+    const CLIENT: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+    const SERVER: Ipv6Addr = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+
+    fn packet(protocol: Protocol, data: NetworkBuffer) -> NetworkBuffer {
+        // Arrives a few hops from where it started.
+        Ipv6HeaderWriter::new(CLIENT, SERVER, protocol, 61, data).into_buf()
+    }
+
+    #[test]
+    fn serves_tcp_and_udp_over_ipv6() {
+        let sockets = Sockets::default();
+        let socket = UdpSocket::bind(&sockets, 5000).unwrap();
+        let timers = Timers::default();
+        let mut ip = IpHandler {
+            icmp: IcmpHandler::new(timers.clone(), sockets.clone()),
+            udp: UdpHandler::new([10, 0, 0, 1].into(), sockets.clone()).ipv6(SERVER),
+            tcp: TcpHandler::new(timers, sockets.clone()).listen(3000, HttpHandler::none()),
+        };
+
+        let syn = TcpHeaderWriter::new(50000, 3000, 41, 0)
+            .set(TcpControl::SYN)
+            .calc_checksum_for(CLIENT.into(), SERVER.into())
+            .into_buf();
+        let syn = packet(Protocol::TCP, syn);
+        let reply = ip.handle(IpPacket::parse(&syn).unwrap()).unwrap();
+
+        let reply = Ipv6::parse(&reply).unwrap();
+        assert_eq!((reply.source(), reply.destination()), (SERVER, CLIENT));
+        assert_eq!(reply.hop_limit(), 64);
+        let (tcp, _) = etherparse::TcpHeader::from_slice(reply.remainder()).unwrap();
+        assert!(tcp.syn && tcp.ack);
+        assert_eq!(tcp.acknowledgment_number, 42);
+        let checksum = tcp
+            .calc_checksum_ipv6_raw(SERVER.octets(), CLIENT.octets(), &[])
+            .unwrap();
+        assert_eq!(tcp.checksum, checksum);
+
+        let datagram = UdpHeaderWriter::new(50000, 5000)
+            .data(b"ping".into())
+            .calc_checksum_for(CLIENT.into(), SERVER.into())
+            .into_buf();
+        let datagram = packet(Protocol::UDP, datagram);
+        ip.handle(IpPacket::parse(&datagram).unwrap()).unwrap();
+
+        let mut buf = [0; 16];
+        let (read, from) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b"ping");
+        assert_eq!(from, SocketAddr::new(CLIENT.into(), 50000));
+
+        socket.send_to(b"pong", from).unwrap();
+        let sent = ip.udp.poll().pop().unwrap();
+        let sent = Ipv6::parse(&sent).unwrap();
+        assert_eq!((sent.source(), sent.destination()), (SERVER, CLIENT));
+        let (udp, data) = etherparse::UdpHeader::from_slice(sent.remainder()).unwrap();
+        assert_eq!(data, b"pong");
+        let checksum = udp
+            .calc_checksum_ipv6_raw(SERVER.octets(), CLIENT.octets(), data)
+            .unwrap();
+        assert_eq!(udp.checksum, checksum);
+    }
+}
@@ -1,8 +1,13 @@
 use crate::proto::ProtocolBuffer;
 
+pub mod ethernet;
+pub mod firewall;
 pub mod http;
 pub mod icmp;
 pub mod ip;
+pub mod nat;
+pub mod reassembly;
+pub mod routing;
 pub mod tcp;
 pub mod udp;
 
@@ -10,3 +15,12 @@ pub trait Handler<P: ProtocolBuffer> {
     type ReturnType;
     fn handle(&mut self, msg: P) -> anyhow::Result<Self::ReturnType>;
 }
+
+/// Everything a handler can ask the stack to wake it up for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    Tcp(tcp::Quad, tcp::TcpTimer),
+    Reassembly(reassembly::FragmentKey),
+    Nat(nat::MappingKey),
+    Conntrack(firewall::FlowKey),
+}
@@ -0,0 +1,507 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddrV4},
+    ops::RangeInclusive,
+    time::Duration,
+};
+
+use crate::{
+    proto::{
+        Protocol,
+        icmp::{IcmpMessage, QuotedTransport, REDIRECT},
+        ip::Ip,
+    },
+    time::{Instant, Timers},
+    utils,
+};
+
+use super::Timer;
+
+/// RFC 4787 wants at least two minutes, five is what it recommends.
+const UDP_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// RFC 5382: an established connection may idle for two hours and four minutes.
+const TCP_ESTABLISHED_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60 + 4 * 60);
+/// Connections that are opening or closing only get four minutes.
+const TCP_TRANSITORY_TIMEOUT: Duration = Duration::from_secs(4 * 60);
+/// Public ports handed out to flows, clear of the ports services listen on.
+const PORTS: RangeInclusive<u16> = 49152..=65535;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_SYN: u8 = 0x02;
+const TCP_RST: u8 = 0x04;
+
+/// An inside endpoint, which keeps its public port for as long as it's in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MappingKey {
+    protocol: u8,
+    inside: SocketAddrV4,
+}
+
+struct Mapping {
+    port: u16,
+    last_seen: Instant,
+    timeout: Duration,
+}
+
+/// Rewrites the source of TCP and UDP flows leaving through one interface
+/// to a public address, and the destination of what comes back. Mappings
+/// are endpoint independent (RFC 4787): an inside address and port keeps
+/// the same public port whoever it talks to, and stays until it's idle for
+/// long enough. ICMP errors about those flows are translated with them.
+pub struct Nat {
+    address: Ipv4Addr,
+    /// The interface `address` is on.
+    outside: usize,
+    mappings: HashMap<MappingKey, Mapping>,
+    /// Public port to the mapping using it, by protocol.
+    ports: HashMap<(u8, u16), MappingKey>,
+    next_port: u16,
+    timers: Timers<Timer>,
+}
+
+impl Nat {
+    pub fn new(address: Ipv4Addr, outside: usize, timers: Timers<Timer>) -> Self {
+        Self {
+            address,
+            outside,
+            mappings: HashMap::new(),
+            ports: HashMap::new(),
+            next_port: *PORTS.start(),
+            timers,
+        }
+    }
+
+    pub fn outside(&self) -> usize {
+        self.outside
+    }
+
+    #[cfg(test)]
+    pub fn mappings(&self) -> usize {
+        self.mappings.len()
+    }
+
+    /// Rewrites the source of a packet about to leave through the outside
+    /// interface. Returns false when it can't be translated and should be
+    /// dropped: it isn't TCP or UDP or an error about a translated flow, or
+    /// every port is taken.
+    pub fn outbound(&mut self, now: Instant, packet: &mut [u8]) -> bool {
+        if let Some((quote, protocol, _, destination)) = quoted(packet) {
+            return self.outbound_error(packet, quote, protocol, destination);
+        }
+        let Some((protocol, source, _)) = endpoints(packet) else {
+            return false;
+        };
+        let key = MappingKey {
+            protocol,
+            inside: source,
+        };
+
+        let port = match self.mappings.get(&key) {
+            Some(mapping) => mapping.port,
+            None => match self.allocate(now, key) {
+                Some(port) => port,
+                None => {
+                    tracing::warn!(?key, "Out of NAT ports");
+                    return false;
+                }
+            },
+        };
+        self.refresh(now, key, packet);
+
+        let header_length = Ip::parse(packet).map_or(0, |ip| ip.header_length());
+        rewrite(packet, 12, &self.address.octets());
+        rewrite(packet, header_length, &port.to_be_bytes());
+        true
+    }
+
+    /// Rewrites the destination of a packet that came in to the public
+    /// address back to the inside endpoint it's for. Returns false for
+    /// anything no mapping covers, which may well be for us instead.
+    pub fn inbound(&mut self, now: Instant, packet: &mut [u8]) -> bool {
+        if let Some((quote, protocol, source, _)) = quoted(packet) {
+            return self.inbound_error(packet, quote, protocol, source);
+        }
+        let Some((protocol, _, destination)) = endpoints(packet) else {
+            return false;
+        };
+        if *destination.ip() != self.address {
+            return false;
+        }
+        let Some(&key) = self.ports.get(&(protocol, destination.port())) else {
+            return false;
+        };
+        self.refresh(now, key, packet);
+
+        let header_length = Ip::parse(packet).map_or(0, |ip| ip.header_length());
+        rewrite(packet, 16, &key.inside.ip().octets());
+        rewrite(packet, header_length + 2, &key.inside.port().to_be_bytes());
+        true
+    }
+
+    /// An inside host's error about a packet that came in through a mapping
+    /// quotes it as it was after translation, so the quoted destination and
+    /// the error's source go back to the public address (RFC 5508).
+    fn outbound_error(
+        &self,
+        packet: &mut [u8],
+        quote: usize,
+        protocol: u8,
+        destination: SocketAddrV4,
+    ) -> bool {
+        let key = MappingKey {
+            protocol,
+            inside: destination,
+        };
+        let Some(mapping) = self.mappings.get(&key) else {
+            return false;
+        };
+
+        let quoted = &mut packet[quote..];
+        let header_length = (quoted[0] & 0xf) as usize * 4;
+        rewrite(quoted, 16, &self.address.octets());
+        rewrite(quoted, header_length + 2, &mapping.port.to_be_bytes());
+        rewrite(packet, 12, &self.address.octets());
+        write_icmp_checksum(packet);
+        true
+    }
+
+    /// An error about a flow we translated quotes it as it left, from the
+    /// public address, so both the error and the quote go back to the
+    /// inside endpoint (RFC 5508). Errors don't keep a mapping alive.
+    fn inbound_error(
+        &self,
+        packet: &mut [u8],
+        quote: usize,
+        protocol: u8,
+        source: SocketAddrV4,
+    ) -> bool {
+        let ours = Ip::parse(packet).is_ok_and(|ip| ip.destination() == self.address);
+        if !ours || *source.ip() != self.address {
+            return false;
+        }
+        let Some(&key) = self.ports.get(&(protocol, source.port())) else {
+            return false;
+        };
+
+        let quoted = &mut packet[quote..];
+        let header_length = (quoted[0] & 0xf) as usize * 4;
+        rewrite(quoted, 12, &key.inside.ip().octets());
+        rewrite(quoted, header_length, &key.inside.port().to_be_bytes());
+        rewrite(packet, 16, &key.inside.ip().octets());
+        write_icmp_checksum(packet);
+        true
+    }
+
+    /// Forgets a mapping that went idle, or checks back later if it didn't.
+    pub fn expire(&mut self, now: Instant, key: MappingKey) {
+        let Some(mapping) = self.mappings.get(&key) else {
+            return;
+        };
+        let idle = now.saturating_duration_since(mapping.last_seen);
+        if idle < mapping.timeout {
+            self.timers
+                .schedule(mapping.timeout - idle, Timer::Nat(key));
+            return;
+        }
+
+        tracing::debug!(?key, port = mapping.port, "NAT mapping expired");
+        self.ports.remove(&(key.protocol, mapping.port));
+        self.mappings.remove(&key);
+    }
+
+    fn allocate(&mut self, now: Instant, key: MappingKey) -> Option<u16> {
+        let count = PORTS.len();
+        let port = (0..count)
+            .map(|i| {
+                let offset = (self.next_port - PORTS.start()) as usize + i;
+                PORTS.start() + (offset % count) as u16
+            })
+            .find(|port| !self.ports.contains_key(&(key.protocol, *port)))?;
+        self.next_port = if port == *PORTS.end() {
+            *PORTS.start()
+        } else {
+            port + 1
+        };
+
+        self.ports.insert((key.protocol, port), key);
+        self.mappings.insert(
+            key,
+            Mapping {
+                port,
+                last_seen: now,
+                timeout: TCP_TRANSITORY_TIMEOUT,
+            },
+        );
+        self.timers
+            .schedule(TCP_TRANSITORY_TIMEOUT, Timer::Nat(key));
+        Some(port)
+    }
+
+    /// Marks the mapping as in use, and works out how long it may idle
+    /// from what the packet says about the flow.
+    fn refresh(&mut self, now: Instant, key: MappingKey, packet: &[u8]) {
+        let Some(mapping) = self.mappings.get_mut(&key) else {
+            return;
+        };
+        mapping.last_seen = now;
+        mapping.timeout = match Protocol::from(key.protocol) {
+            Protocol::TCP => match tcp_flags(packet) {
+                flags if flags & (TCP_FIN | TCP_RST) != 0 => TCP_TRANSITORY_TIMEOUT,
+                flags if flags & TCP_SYN != 0 => mapping.timeout,
+                _ => TCP_ESTABLISHED_TIMEOUT,
+            },
+            _ => UDP_TIMEOUT,
+        };
+    }
+}
+
+/// Protocol, source and destination of a TCP or UDP packet. Fragments
+/// other than the first have no ports, so there's nothing to go by.
+fn endpoints(packet: &[u8]) -> Option<(u8, SocketAddrV4, SocketAddrV4)> {
+    let ip = Ip::parse(packet).ok()?;
+    let ports = ip.remainder();
+    let long_enough = match ip.protocol() {
+        Protocol::TCP => ports.len() >= 20,
+        Protocol::UDP => ports.len() >= 8,
+        _ => false,
+    };
+    if !long_enough || ip.fragment_offset() != 0 {
+        return None;
+    }
+
+    let source = SocketAddrV4::new(ip.source(), utils::read_u16(ports));
+    let destination = SocketAddrV4::new(ip.destination(), utils::read_u16(&ports[2..]));
+    Some((ip.protocol().into(), source, destination))
+}
+
+/// Where the quote in an ICMP error about a TCP or UDP packet starts, and
+/// the protocol, source and destination of the packet it quotes.
+fn quoted(packet: &[u8]) -> Option<(usize, u8, SocketAddrV4, SocketAddrV4)> {
+    let ip = Ip::parse(packet).ok()?;
+    if ip.protocol() != Protocol::ICMP || ip.fragment_offset() != 0 {
+        return None;
+    }
+    // Redirects are for the router that sent the packet, not its source.
+    let message = IcmpMessage::parse(ip.remainder()).ok()?;
+    if message.icmp_type() == REDIRECT {
+        return None;
+    }
+    let quote = message.quote()?;
+    let (source_port, destination_port) = match quote.transport().ok()? {
+        QuotedTransport::Tcp {
+            source_port,
+            destination_port,
+            ..
+        }
+        | QuotedTransport::Udp {
+            source_port,
+            destination_port,
+        } => (source_port, destination_port),
+        _ => return None,
+    };
+
+    let quoted = quote.ip().ok()?;
+    Some((
+        ip.packet().len() - quote.bytes().len(),
+        quoted.protocol().into(),
+        SocketAddrV4::new(quoted.source(), source_port),
+        SocketAddrV4::new(quoted.destination(), destination_port),
+    ))
+}
+
+/// Sums the ICMP message up again after its quote changed.
+fn write_icmp_checksum(packet: &mut [u8]) {
+    let header_length = (packet[0] & 0xf) as usize * 4;
+    let message = &mut packet[header_length..];
+    message[2..4].fill(0);
+    let checksum = utils::ones_complement(utils::add_slice(0, message)).to_be();
+    message[2..4].copy_from_slice(&checksum.to_be_bytes());
+}
+
+fn tcp_flags(packet: &[u8]) -> u8 {
+    Ip::parse(packet).map_or(0, |ip| ip.remainder()[13])
+}
+
+/// Overwrites the bytes at `at` with `new`, patching the IP header checksum
+/// and the TCP or UDP one along with them. Addresses are in the pseudo
+/// header, so the transport checksum covers those too. Quotes may stop
+/// short of the transport checksum, which then stays as it is.
+fn rewrite(packet: &mut [u8], at: usize, new: &[u8]) {
+    let header_length = (packet[0] & 0xf) as usize * 4;
+    let transport = match Protocol::from(packet[9]) {
+        Protocol::TCP => Some(header_length + 16),
+        Protocol::UDP => Some(header_length + 6),
+        _ => None,
+    }
+    .filter(|&checksum| checksum + 2 <= packet.len())
+    // A UDP checksum of zero means there is none.
+    .filter(|&checksum| {
+        packet[9] != u8::from(Protocol::UDP) || utils::read_u16(&packet[checksum..]) != 0
+    });
+    let ip = (at < header_length).then_some(10);
+
+    for (i, word) in new.chunks_exact(2).enumerate() {
+        let offset = at + i * 2;
+        let old = utils::read_u16(&packet[offset..]);
+        let new = utils::read_u16(word);
+
+        for checksum in ip.into_iter().chain(transport) {
+            let mut updated =
+                utils::update_checksum(utils::read_u16(&packet[checksum..]), old, new);
+            if Some(checksum) == transport && packet[9] == u8::from(Protocol::UDP) && updated == 0 {
+                updated = 0xffff;
+            }
+            packet[checksum..checksum + 2].copy_from_slice(&updated.to_be_bytes());
+        }
+        packet[offset..offset + 2].copy_from_slice(word);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::*;
+    use crate::proto::{
+        NetworkBuffer,
+        icmp::{Icmp, Quote, Unreachable},
+        ip::{IpHeaderWriter, IpPacket},
+        tcp::{Tcp, TcpControl, TcpHeaderWriter},
+        udp::{Udp, UdpHeaderWriter},
+    };
+
+    const INSIDE: Ipv4Addr = Ipv4Addr::new(192, 168, 0, 2);
+    const PUBLIC: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 1);
+    const REMOTE: Ipv4Addr = Ipv4Addr::new(198, 51, 100, 7);
+
+    fn datagram(source: SocketAddrV4, destination: SocketAddrV4) -> NetworkBuffer {
+        let udp = UdpHeaderWriter::new(source.port(), destination.port())
+            .data(b"hello".as_slice().into())
+            .calc_checksum_for((*source.ip()).into(), (*destination.ip()).into())
+            .into_buf();
+        IpHeaderWriter::new(*source.ip(), *destination.ip(), Protocol::UDP, 64, udp).into_buf()
+    }
+
+    fn segment(
+        source: SocketAddrV4,
+        destination: SocketAddrV4,
+        control: TcpControl,
+    ) -> NetworkBuffer {
+        let tcp = TcpHeaderWriter::new(source.port(), destination.port(), 1, 0)
+            .set(control)
+            .calc_checksum_for((*source.ip()).into(), (*destination.ip()).into())
+            .into_buf();
+        IpHeaderWriter::new(*source.ip(), *destination.ip(), Protocol::TCP, 64, tcp).into_buf()
+    }
+
+    fn valid(packet: &[u8]) -> bool {
+        let ip = IpPacket::parse(packet).unwrap();
+        let IpPacket::V4(v4) = ip else { unreachable!() };
+        v4.checksum_valid()
+            && match ip.protocol() {
+                Protocol::TCP => Tcp::parse(ip).unwrap().checksum_valid(),
+                _ => Udp::parse(ip).unwrap().checksum_valid(),
+            }
+    }
+
+    #[test]
+    fn translates_flows_both_ways_and_expires_them() {
+        let timers = Timers::default();
+        let mut nat = Nat::new(PUBLIC, 1, timers.clone());
+        let now = Instant::from_millis(1);
+        let inside = SocketAddrV4::new(INSIDE, 40000);
+        let remote = SocketAddrV4::new(REMOTE, 53);
+
+        let mut out = datagram(inside, remote);
+        assert!(nat.outbound(now, &mut out));
+        assert!(valid(&out));
+        let (_, public, destination) = endpoints(&out).unwrap();
+        assert_eq!(*public.ip(), PUBLIC);
+        assert!(PORTS.contains(&public.port()));
+        assert_eq!(destination, remote);
+
+        // The same endpoint keeps its port, whoever it talks to.
+        let mut again = datagram(inside, SocketAddrV4::new(REMOTE, 123));
+        assert!(nat.outbound(now, &mut again));
+        assert_eq!(endpoints(&again).unwrap().1, public);
+
+        let mut reply = datagram(remote, public);
+        assert!(nat.inbound(now, &mut reply));
+        assert!(valid(&reply));
+        assert_eq!(endpoints(&reply).unwrap().2, inside);
+        let ip = IpPacket::parse(&reply).unwrap();
+        assert_eq!(ip.destination(), IpAddr::from(INSIDE));
+
+        // Nothing maps to other ports, those may be ours.
+        let mut stray = datagram(remote, SocketAddrV4::new(PUBLIC, 3000));
+        assert!(!nat.inbound(now, &mut stray));
+
+        // TCP gets a mapping of its own, with the checksum fixed up too.
+        let mut syn = segment(inside, SocketAddrV4::new(REMOTE, 80), TcpControl::SYN);
+        assert!(nat.outbound(now, &mut syn));
+        assert!(valid(&syn));
+        assert_eq!(nat.mappings(), 2);
+
+        // The SYN that never got anywhere goes after four minutes, idle UDP after five.
+        timers.advance(now + UDP_TIMEOUT);
+        for timer in timers.expired() {
+            let Timer::Nat(key) = timer else {
+                panic!("Expected a NAT timer, got {:?}", timer);
+            };
+            nat.expire(timers.now(), key);
+        }
+        assert_eq!(nat.mappings(), 0);
+        assert!(!nat.inbound(now, &mut datagram(remote, public)));
+    }
+
+    fn port_unreachable(from: Ipv4Addr, about: &[u8]) -> NetworkBuffer {
+        let about = Ip::parse(about).unwrap();
+        let message = IcmpMessage::DestinationUnreachable {
+            reason: Unreachable::Port,
+            quote: Quote::of(&about),
+        };
+        IpHeaderWriter::new(from, about.source(), Protocol::ICMP, 64, message.into_buf()).into_buf()
+    }
+
+    /// The error's destination and source, and what its quote says.
+    fn error_endpoints(packet: &[u8]) -> (Ipv4Addr, Ipv4Addr, SocketAddrV4, SocketAddrV4) {
+        let ip = Ip::parse(packet).unwrap();
+        assert!(ip.checksum_valid());
+        let icmp = Icmp::parse(ip).unwrap();
+        assert!(icmp.checksum_valid());
+        let quote = icmp.message().unwrap().quote().unwrap();
+        assert!(quote.ip().unwrap().checksum_valid());
+        let (_, _, source, destination) = quoted(packet).unwrap();
+        (ip.source(), ip.destination(), source, destination)
+    }
+
+    #[test]
+    fn translates_errors_about_translated_flows() {
+        let timers = Timers::default();
+        let mut nat = Nat::new(PUBLIC, 1, timers.clone());
+        let now = Instant::from_millis(1);
+        let inside = SocketAddrV4::new(INSIDE, 40000);
+        let remote = SocketAddrV4::new(REMOTE, 53);
+
+        let mut out = datagram(inside, remote);
+        assert!(nat.outbound(now, &mut out));
+        let public = endpoints(&out).unwrap().1;
+
+        // The remote end refusing what we sent reaches the inside host,
+        // quoting the datagram as that host sent it.
+        let mut error = port_unreachable(REMOTE, &out);
+        assert!(nat.inbound(now, &mut error));
+        assert_eq!(error_endpoints(&error), (REMOTE, INSIDE, inside, remote));
+
+        // And the inside host refusing a reply looks like it came from us.
+        let mut reply = datagram(remote, public);
+        assert!(nat.inbound(now, &mut reply));
+        let mut error = port_unreachable(INSIDE, &reply);
+        assert!(nat.outbound(now, &mut error));
+        assert_eq!(error_endpoints(&error), (PUBLIC, REMOTE, remote, public));
+
+        // Errors about ports nobody was given are left alone.
+        let stray = datagram(SocketAddrV4::new(PUBLIC, 3000), remote);
+        assert!(!nat.inbound(now, &mut port_unreachable(REMOTE, &stray)));
+    }
+}
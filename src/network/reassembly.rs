@@ -0,0 +1,320 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::Ipv4Addr,
+    time::Duration,
+};
+
+use crate::{
+    proto::{
+        NetworkBuffer,
+        ip::{self, Ip},
+    },
+    time::{Instant, TimerId, Timers},
+};
+
+use super::Timer;
+
+/// How long the fragments of a datagram are held before giving up on it.
+/// RFC 791 suggests starting at 15 seconds, Linux waits 30.
+const TIMEOUT: Duration = Duration::from_secs(30);
+/// Bytes held across every incomplete datagram, the oldest ones are dropped beyond it.
+const MAX_MEMORY: usize = 4 * 1024 * 1024;
+/// Fragments held per datagram, so a flood of tiny ones can't pin down a key.
+const MAX_FRAGMENTS: usize = 64;
+const MAX_DATAGRAM_LEN: usize = u16::MAX as usize;
+
+/// What RFC 791 identifies the fragments of one datagram by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FragmentKey {
+    source: Ipv4Addr,
+    destination: Ipv4Addr,
+    protocol: u8,
+    identification: u16,
+}
+
+impl FragmentKey {
+    fn of(ip: &Ip) -> Self {
+        Self {
+            source: ip.source(),
+            destination: ip.destination(),
+            protocol: ip.protocol().into(),
+            identification: ip.identification(),
+        }
+    }
+}
+
+struct Partial {
+    /// The first fragment's header, options and all.
+    header: Option<Vec<u8>>,
+    /// Data by byte offset, never overlapping.
+    fragments: BTreeMap<usize, Vec<u8>>,
+    /// Known once the last fragment is in.
+    length: Option<usize>,
+    size: usize,
+    since: Instant,
+    timer: TimerId,
+}
+
+impl Partial {
+    /// Adds a fragment, returning how many bytes that took. Any
+    /// inconsistency fails the whole datagram, exact duplicates are ignored.
+    fn add(&mut self, ip: &Ip, offset: usize, data: &[u8]) -> Result<usize, &'static str> {
+        let end = offset + data.len();
+
+        if let Some((&start, previous)) = self.fragments.range(..=offset).next_back()
+            && start + previous.len() > offset
+        {
+            if start == offset && previous.as_slice() == data {
+                return Ok(0);
+            }
+            return Err("overlapping fragments");
+        }
+        if self.fragments.range(offset + 1..end).next().is_some() {
+            return Err("overlapping fragments");
+        }
+
+        if !ip.more_fragments() {
+            if self.length.is_some_and(|length| length != end) {
+                return Err("conflicting last fragments");
+            }
+            self.length = Some(end);
+        }
+        let furthest = self
+            .fragments
+            .last_key_value()
+            .map_or(end, |(&start, last)| end.max(start + last.len()));
+        if self.length.is_some_and(|length| furthest > length) {
+            return Err("fragment past the end of the datagram");
+        }
+
+        if self.fragments.len() == MAX_FRAGMENTS {
+            return Err("too many fragments");
+        }
+
+        let mut size = data.len();
+        if offset == 0 {
+            self.header = Some(ip.header().to_vec());
+            size += ip.header_length();
+        }
+        self.fragments.insert(offset, data.to_vec());
+        self.size += size;
+        Ok(size)
+    }
+
+    /// The whole datagram, once there are no holes left in it.
+    fn assemble(&self) -> Option<NetworkBuffer> {
+        let length = self.length?;
+        let header = self.header.as_ref()?;
+
+        let mut next = 0;
+        for (&offset, data) in &self.fragments {
+            if offset != next {
+                return None;
+            }
+            next += data.len();
+        }
+        if next != length {
+            return None;
+        }
+
+        let mut buf = NetworkBuffer::new(header.len() + length);
+        buf.extend_from_slice(header);
+        for data in self.fragments.values() {
+            buf.extend_from_slice(data);
+        }
+
+        buf[2..4].copy_from_slice(&((header.len() + length) as u16).to_be_bytes());
+        // Clear MF and the offset, and checksum the header we now have.
+        buf[6] &= 0b1100_0000;
+        buf[7] = 0;
+        ip::write_header_checksum(&mut buf[..header.len()]);
+
+        Some(buf)
+    }
+}
+
+/// Puts fragmented IPv4 datagrams back together before they go up the stack.
+pub struct Reassembler {
+    datagrams: HashMap<FragmentKey, Partial>,
+    memory: usize,
+    timers: Timers<Timer>,
+}
+
+impl Reassembler {
+    pub fn new(timers: Timers<Timer>) -> Self {
+        Self {
+            datagrams: HashMap::new(),
+            memory: 0,
+            timers,
+        }
+    }
+
+    /// Bytes held for datagrams still missing pieces.
+    #[cfg(test)]
+    pub fn memory(&self) -> usize {
+        self.memory
+    }
+
+    /// Takes one fragment, returning the whole datagram as a single IPv4
+    /// packet once every piece of it is in.
+    pub fn insert(&mut self, now: Instant, ip: &Ip) -> Option<NetworkBuffer> {
+        let key = FragmentKey::of(ip);
+        let offset = ip.fragment_offset() as usize * 8;
+        let data = ip.remainder();
+
+        if data.is_empty()
+            || (ip.more_fragments() && !data.len().is_multiple_of(8))
+            || ip.header_length() + offset + data.len() > MAX_DATAGRAM_LEN
+        {
+            tracing::warn!(
+                ?key,
+                offset,
+                len = data.len(),
+                "Dropping malformed fragment"
+            );
+            return None;
+        }
+
+        let timers = &self.timers;
+        let partial = self.datagrams.entry(key).or_insert_with(|| Partial {
+            header: None,
+            fragments: BTreeMap::new(),
+            length: None,
+            size: 0,
+            since: now,
+            timer: timers.schedule(TIMEOUT, Timer::Reassembly(key)),
+        });
+
+        match partial.add(ip, offset, data) {
+            Ok(size) => self.memory += size,
+            Err(reason) => {
+                tracing::warn!(?key, reason, "Dropping fragmented datagram");
+                self.remove(key);
+                return None;
+            }
+        }
+
+        if let Some(datagram) = partial.assemble() {
+            self.remove(key);
+            return Some(datagram);
+        }
+
+        while self.memory > MAX_MEMORY {
+            let oldest = self
+                .datagrams
+                .iter()
+                .min_by_key(|(_, partial)| partial.since)
+                .map(|(key, _)| *key)?;
+            tracing::warn!(key = ?oldest, "Out of reassembly memory, dropping datagram");
+            self.remove(oldest);
+        }
+
+        None
+    }
+
+    /// Gives up on a datagram whose fragments stopped coming.
+    pub fn expire(&mut self, key: FragmentKey) {
+        if let Some(partial) = self.datagrams.remove(&key) {
+            tracing::warn!(?key, held = partial.fragments.len(), "Reassembly timed out");
+            self.memory -= partial.size;
+        }
+    }
+
+    fn remove(&mut self, key: FragmentKey) {
+        if let Some(partial) = self.datagrams.remove(&key) {
+            self.timers.cancel(partial.timer);
+            self.memory -= partial.size;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proto::{Protocol, ip::IpHeaderWriter},
+        utils,
+    };
+
+    const SOURCE: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const DESTINATION: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    fn fragment(identification: u16, offset: usize, more: bool, data: &[u8]) -> NetworkBuffer {
+        let mut packet =
+            IpHeaderWriter::new(SOURCE, DESTINATION, Protocol::UDP, 64, data.to_vec().into())
+                .into_buf();
+        packet[4..6].copy_from_slice(&identification.to_be_bytes());
+        let flags = ((more as u16) << 13) | (offset / 8) as u16;
+        packet[6..8].copy_from_slice(&flags.to_be_bytes());
+        packet
+    }
+
+    #[test]
+    fn reassembles_out_of_order_fragments() {
+        let timers = Timers::default();
+        let mut reassembler = Reassembler::new(timers.clone());
+        let now = Instant::from_millis(1);
+        let payload: Vec<u8> = (0..40).collect();
+
+        let first = fragment(7, 0, true, &payload[..16]);
+        let middle = fragment(7, 16, true, &payload[16..32]);
+        let last = fragment(7, 32, false, &payload[32..]);
+
+        for packet in [&last, &middle, &middle] {
+            assert!(
+                reassembler
+                    .insert(now, &Ip::parse(packet).unwrap())
+                    .is_none()
+            );
+        }
+        let datagram = reassembler
+            .insert(now, &Ip::parse(&first).unwrap())
+            .unwrap();
+
+        let ip = Ip::parse(&datagram).unwrap();
+        assert!(!ip.is_fragment());
+        assert_eq!(ip.total_length(), 60);
+        assert_eq!(ip.remainder(), payload);
+        assert_eq!(utils::ones_complement(utils::add_slice(0, ip.header())), 0);
+        assert_eq!(reassembler.memory(), 0);
+        assert_eq!(timers.next_deadline(), None);
+    }
+
+    #[test]
+    fn drops_overlaps_and_stale_datagrams() {
+        let timers = Timers::default();
+        let mut reassembler = Reassembler::new(timers.clone());
+        let now = Instant::from_millis(1);
+
+        // A fragment rewriting data we already have fails the datagram.
+        let first = fragment(1, 0, true, &[1; 16]);
+        let overlapping = fragment(1, 8, false, &[2; 16]);
+        assert!(
+            reassembler
+                .insert(now, &Ip::parse(&first).unwrap())
+                .is_none()
+        );
+        assert!(reassembler.memory() > 0);
+        assert!(
+            reassembler
+                .insert(now, &Ip::parse(&overlapping).unwrap())
+                .is_none()
+        );
+        assert_eq!(reassembler.memory(), 0);
+
+        // One that never completes is forgotten once its timer fires.
+        let first = fragment(2, 0, true, &[1; 16]);
+        assert!(
+            reassembler
+                .insert(now, &Ip::parse(&first).unwrap())
+                .is_none()
+        );
+        timers.advance(now + TIMEOUT);
+        let expired = timers.expired();
+        let [Timer::Reassembly(key)] = expired[..] else {
+            panic!("Expected a reassembly timeout, got {:?}", expired);
+        };
+        reassembler.expire(key);
+        assert_eq!(reassembler.memory(), 0);
+    }
+}
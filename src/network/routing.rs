@@ -0,0 +1,216 @@
+use std::{fmt::Display, net::Ipv4Addr, str::FromStr};
+
+use anyhow::Context;
+
+/// An IPv4 network, `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ipv4Prefix {
+    address: Ipv4Addr,
+    length: u8,
+}
+
+impl Ipv4Prefix {
+    pub fn new(address: Ipv4Addr, length: u8) -> anyhow::Result<Self> {
+        if length > 32 {
+            anyhow::bail!("Prefix length {} is longer than 32", length)
+        }
+        // Host bits are ignored, so 10.0.0.1/24 is 10.0.0.0/24.
+        let address = Ipv4Addr::from(u32::from(address) & mask(length));
+        Ok(Self { address, length })
+    }
+
+    /// The network an address with `netmask` is on.
+    pub fn from_netmask(address: Ipv4Addr, netmask: Ipv4Addr) -> anyhow::Result<Self> {
+        let netmask = u32::from(netmask);
+        if netmask.leading_ones() + netmask.trailing_zeros() != 32 {
+            anyhow::bail!("Netmask {} isn't contiguous", Ipv4Addr::from(netmask))
+        }
+        Self::new(address, netmask.leading_ones() as u8)
+    }
+
+    pub fn host(address: Ipv4Addr) -> Self {
+        Self {
+            address,
+            length: 32,
+        }
+    }
+
+    pub fn contains(&self, address: Ipv4Addr) -> bool {
+        u32::from(address) & mask(self.length) == u32::from(self.address)
+    }
+
+    /// The directed broadcast address, none on /31 and /32 which have no
+    /// room for one.
+    pub fn broadcast(&self) -> Option<Ipv4Addr> {
+        (self.length < 31).then(|| Ipv4Addr::from(u32::from(self.address) | !mask(self.length)))
+    }
+}
+
+fn mask(length: u8) -> u32 {
+    u32::MAX.checked_shl(32 - length as u32).unwrap_or(0)
+}
+
+impl Display for Ipv4Prefix {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.address, self.length)
+    }
+}
+
+impl FromStr for Ipv4Prefix {
+    type Err = anyhow::Error;
+
+    /// `10.0.0.0/8`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, length) = s
+            .split_once('/')
+            .with_context(|| format!("Expected <address>/<length>, got: {}", s))?;
+        Self::new(
+            address
+                .parse()
+                .with_context(|| format!("Invalid address: {}", address))?,
+            length
+                .parse()
+                .with_context(|| format!("Invalid prefix length: {}", length))?,
+        )
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Ipv4Prefix {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// Where a packet goes next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NextHop {
+    /// It's for us.
+    Local,
+    /// Out of the interface with this index.
+    Interface(usize),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Route {
+    prefix: Ipv4Prefix,
+    next_hop: NextHop,
+}
+
+/// Longest prefix match over the routes to every interface. The interfaces
+/// are point to point, so a route only needs to say which one to leave by.
+#[derive(Debug, Clone, Default)]
+pub struct RoutingTable {
+    /// Longest prefix first, so the first match is the best one.
+    routes: Vec<Route>,
+    /// Our address on each interface, by index.
+    addresses: Vec<Ipv4Addr>,
+    /// Local addresses that reach every host on a subnet.
+    broadcasts: Vec<Ipv4Addr>,
+}
+
+impl RoutingTable {
+    /// Adds the next interface, with a route to our own address on it, one
+    /// to the subnet it's connected to and one to the subnet's broadcast.
+    pub fn interface(self, address: Ipv4Addr, subnet: Ipv4Prefix) -> Self {
+        let index = self.addresses.len();
+        let mut s = self
+            .add(Ipv4Prefix::host(address), NextHop::Local)
+            .add(subnet, NextHop::Interface(index));
+        s.addresses.push(address);
+        if let Some(broadcast) = subnet.broadcast() {
+            s = s.broadcast(broadcast);
+        }
+        // Limited broadcast never leaves the link, whatever the subnet.
+        if index == 0 {
+            s = s.broadcast(Ipv4Addr::BROADCAST);
+        }
+        s
+    }
+
+    /// Takes packets to `address` as ours, besides the interface addresses.
+    pub fn alias(self, address: Ipv4Addr) -> Self {
+        self.add(Ipv4Prefix::host(address), NextHop::Local)
+    }
+
+    fn broadcast(mut self, address: Ipv4Addr) -> Self {
+        self.broadcasts.push(address);
+        self.add(Ipv4Prefix::host(address), NextHop::Local)
+    }
+
+    /// Sends everything in `prefix` out of `interface`.
+    pub fn route(self, prefix: Ipv4Prefix, interface: usize) -> Self {
+        self.add(prefix, NextHop::Interface(interface))
+    }
+
+    fn add(mut self, prefix: Ipv4Prefix, next_hop: NextHop) -> Self {
+        // After routes of the same length, so the first one added wins ties.
+        let at = self
+            .routes
+            .partition_point(|route| route.prefix.length >= prefix.length);
+        self.routes.insert(at, Route { prefix, next_hop });
+        self
+    }
+
+    pub fn lookup(&self, destination: Ipv4Addr) -> Option<NextHop> {
+        self.routes
+            .iter()
+            .find(|route| route.prefix.contains(destination))
+            .map(|route| route.next_hop)
+    }
+
+    pub fn is_local(&self, address: Ipv4Addr) -> bool {
+        self.lookup(address) == Some(NextHop::Local)
+    }
+
+    pub fn is_broadcast(&self, address: Ipv4Addr) -> bool {
+        self.broadcasts.contains(&address)
+    }
+
+    pub fn interfaces(&self) -> usize {
+        self.addresses.len()
+    }
+
+    /// Our address on `interface`, which errors about packets from there come from.
+    pub fn address(&self, interface: usize) -> Ipv4Addr {
+        self.addresses[interface]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prefix(s: &str) -> Ipv4Prefix {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn longest_prefix_wins() {
+        let table = RoutingTable::default()
+            .interface(Ipv4Addr::new(10, 0, 0, 1), prefix("10.0.0.0/24"))
+            .interface(Ipv4Addr::new(10, 0, 1, 1), prefix("10.0.1.0/24"))
+            .route(prefix("0.0.0.0/0"), 0)
+            .route(prefix("192.168.0.0/16"), 1)
+            .route(prefix("192.168.7.0/24"), 0);
+
+        let lookup = |address: &str| table.lookup(address.parse().unwrap());
+        assert_eq!(lookup("10.0.0.1"), Some(NextHop::Local));
+        assert_eq!(lookup("10.0.1.1"), Some(NextHop::Local));
+        assert_eq!(lookup("10.0.1.9"), Some(NextHop::Interface(1)));
+        assert_eq!(lookup("192.168.3.4"), Some(NextHop::Interface(1)));
+        assert_eq!(lookup("192.168.7.4"), Some(NextHop::Interface(0)));
+        assert_eq!(lookup("8.8.8.8"), Some(NextHop::Interface(0)));
+        assert_eq!(lookup("10.0.1.255"), Some(NextHop::Local));
+        assert!(table.is_broadcast(Ipv4Addr::new(10, 0, 1, 255)));
+        assert!(table.is_broadcast(Ipv4Addr::BROADCAST));
+        assert!(!table.is_broadcast(Ipv4Addr::new(10, 0, 1, 1)));
+
+        assert_eq!(prefix("10.1.2.3/8").to_string(), "10.0.0.0/8");
+        assert!(
+            Ipv4Prefix::from_netmask(Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(255, 0, 255, 0))
+                .is_err()
+        );
+        assert!("10.0.0.0/33".parse::<Ipv4Prefix>().is_err());
+    }
+}
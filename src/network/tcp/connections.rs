@@ -1,41 +1,145 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::HashMap,
+    hash::{BuildHasher, RandomState},
+    net::{IpAddr, SocketAddr},
+};
 
-use crate::proto::{ip::Ip, tcp::Tcp};
+use crate::{
+    network::Timer,
+    proto::{ip::IpPacket, tcp::Tcp},
+    socket::tcp::StreamId,
+    time::{Instant, Timers},
+};
 
-use super::state::TcpState;
+use super::state::{TcpState, TcpTimer, TimerOutcome};
 
+/// Source address and port, destination address and port of inbound segments.
 #[derive(Debug, Hash, PartialEq, Eq, Clone, Copy)]
-pub struct Quad(u32, u16);
+pub struct Quad(IpAddr, u16, IpAddr, u16);
 
-impl Tcp<Ip<'_>> {
+impl Quad {
+    pub fn new(remote: SocketAddr, local: SocketAddr) -> Self {
+        Self(remote.ip(), remote.port(), local.ip(), local.port())
+    }
+
+    pub fn remote(&self) -> SocketAddr {
+        SocketAddr::new(self.0, self.1)
+    }
+
+    pub fn local(&self) -> SocketAddr {
+        SocketAddr::new(self.2, self.3)
+    }
+}
+
+impl Tcp<IpPacket<'_>> {
     fn quad(&self) -> Quad {
-        Quad(self.inner().source(), self.source_port())
+        Quad(
+            self.inner().source(),
+            self.source_port(),
+            self.inner().destination(),
+            self.destination_port(),
+        )
+    }
+}
+
+/// Where our side of new connections starts counting.
+pub enum Isn {
+    /// RFC 6528: a clock ticking every 4 microseconds plus a keyed hash of
+    /// the quad. Nobody off path guesses it, and a quad used again starts
+    /// past where it left off.
+    Random(RandomState),
+    /// The same for every connection, for peers scripted by hand.
+    #[cfg(test)]
+    Fixed(u32),
+}
+
+impl Isn {
+    fn next(&self, quad: Quad, now: Instant) -> u32 {
+        match self {
+            Self::Random(secret) => {
+                let clock = (now.micros() / 4) as u32;
+                clock.wrapping_add(secret.hash_one(quad) as u32)
+            }
+            #[cfg(test)]
+            Self::Fixed(isn) => *isn,
+        }
     }
 }
 
 pub struct TcpConnections {
     inner: HashMap<Quad, TcpState>,
-    nic: Arc<tun::Device>,
+    /// Of the interfaces, new connections size their segments by it.
+    mtu: Option<u16>,
+    isn: Isn,
+    timers: Timers<Timer>,
 }
 
 impl TcpConnections {
-    pub fn new(nic: Arc<tun::Device>) -> Self {
+    pub fn new(timers: Timers<Timer>) -> Self {
         Self {
             inner: HashMap::new(),
-            nic,
+            mtu: None,
+            isn: Isn::Random(RandomState::new()),
+            timers,
         }
     }
 
-    pub fn get(&mut self, msg: &Tcp<Ip<'_>>) -> (&mut TcpState, Quad) {
+    pub fn get(&mut self, msg: &Tcp<IpPacket<'_>>) -> (&mut TcpState, Quad) {
         let quad = msg.quad();
-        let state = self
-            .inner
-            .entry(quad)
-            .or_insert_with(|| TcpState::new(self.nic.clone()));
+        let state = self.inner.entry(quad).or_insert_with(|| {
+            let isn = self.isn.next(quad, self.timers.now());
+            TcpState::new(quad, self.mtu, isn, self.timers.clone())
+        });
         (state, quad)
     }
 
-    pub fn remove(&mut self, quad: Quad) {
-        self.inner.remove(&quad);
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.mtu = Some(mtu);
+        self
+    }
+
+    #[cfg(test)]
+    pub fn isn(mut self, isn: Isn) -> Self {
+        self.isn = isn;
+        self
+    }
+
+    pub fn find(&self, quad: Quad) -> Option<&TcpState> {
+        self.inner.get(&quad)
+    }
+
+    pub fn get_mut(&mut self, quad: Quad) -> Option<&mut TcpState> {
+        self.inner.get_mut(&quad)
+    }
+
+    /// Drops a connection, returning the socket it was attached to.
+    pub fn remove(&mut self, quad: Quad) -> Option<StreamId> {
+        let mut state = self.inner.remove(&quad)?;
+        state.cancel_timers();
+        state.socket()
+    }
+
+    pub fn on_timer(&mut self, quad: Quad, timer: TcpTimer) -> Option<TimerOutcome> {
+        Some(self.inner.get_mut(&quad)?.on_timer(timer))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn random_isns_differ_by_quad_and_move_with_the_clock() {
+        let isn = Isn::Random(RandomState::new());
+        let quad = |port| Quad::new(([10, 0, 0, 2], port).into(), ([10, 0, 0, 1], 3000).into());
+        let now = Instant::from_millis(1);
+
+        let first = isn.next(quad(50000), now);
+        assert_ne!(first, isn.next(quad(50001), now));
+        let later = now + Duration::from_micros(400);
+        assert_eq!(isn.next(quad(50000), later), first.wrapping_add(100));
+        assert_eq!(Isn::Fixed(7).next(quad(50000), later), 7);
     }
 }
@@ -1,64 +1,233 @@
 mod connections;
 mod state;
 
-use std::sync::Arc;
+use std::{collections::HashMap, net::SocketAddr};
 
-use anyhow::bail;
+#[cfg(test)]
+pub use connections::Isn;
+pub use connections::Quad;
 use connections::TcpConnections;
+pub use state::TcpTimer;
+use state::TimerOutcome;
 
-use crate::proto::{
-    NetworkBuffer,
-    ip::Ip,
-    tcp::{Tcp, TcpControl},
+use crate::{
+    proto::{
+        NetworkBuffer, ProtocolBuffer,
+        icmp::Unreachable,
+        ip::IpPacket,
+        tcp::{Tcp, TcpControl},
+    },
+    socket::{self, Sockets},
+    time::Timers,
 };
 
-use super::{Handler, http::HttpHandler};
+use super::{Handler, Timer, http::HttpHandler};
 
 pub struct TcpHandler {
-    listen_port: u16,
     connections: TcpConnections,
-    higher_level_handler: HttpHandler,
+    /// By what they're bound to, `socket::any` for every local address.
+    services: HashMap<SocketAddr, HttpHandler>,
+    sockets: Sockets,
+    /// Segments cut down to a smaller path MTU, sent on the next poll.
+    resend: Vec<NetworkBuffer>,
 }
 
 impl TcpHandler {
-    pub fn new(port: u16, handler: HttpHandler, nic: Arc<tun::Device>) -> Self {
+    pub fn new(timers: Timers<Timer>, sockets: Sockets) -> Self {
         Self {
-            listen_port: port,
-            connections: TcpConnections::new(nic),
-            higher_level_handler: handler,
+            connections: TcpConnections::new(timers),
+            services: HashMap::new(),
+            sockets,
+            resend: vec![],
+        }
+    }
+
+    /// Starts new connections at `isn` instead of a random sequence number.
+    #[cfg(test)]
+    pub fn isn(mut self, isn: Isn) -> Self {
+        self.connections = self.connections.isn(isn);
+        self
+    }
+
+    /// Sizes segments, and the MSS we announce, to an interface MTU of `mtu`.
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.connections = self.connections.mtu(mtu);
+        self
+    }
+
+    pub fn sockets(&self) -> &Sockets {
+        &self.sockets
+    }
+
+    /// Serves `handler` on `port` of every local address, replacing
+    /// whatever was listening there.
+    #[cfg(test)]
+    pub fn listen(self, port: u16, handler: HttpHandler) -> Self {
+        self.listen_on(socket::any(port), handler)
+    }
+
+    /// Serves `handler` on one local address only, ahead of anything on
+    /// the same port of every address.
+    pub fn listen_on(mut self, local: SocketAddr, handler: HttpHandler) -> Self {
+        self.services.insert(local, handler);
+        self
+    }
+
+    /// Runs a connection timer, returning a complete IP packet if it has something to send.
+    pub fn on_timer(&mut self, quad: Quad, timer: TcpTimer) -> Option<NetworkBuffer> {
+        match self.connections.on_timer(quad, timer)? {
+            TimerOutcome::Send(buf) => Some(buf),
+            TimerOutcome::None => None,
+            TimerOutcome::Closed => {
+                self.close(quad);
+                None
+            }
+        }
+    }
+
+    /// Sends whatever applications wrote to their sockets as far as the
+    /// peers' windows go, as complete IP packets.
+    pub fn poll(&mut self) -> Vec<NetworkBuffer> {
+        let mut out = std::mem::take(&mut self.resend);
+
+        for (socket, quad) in self.sockets.tcp_pending(|quad| self.send_window(quad)) {
+            let Some(connection) = self.connections.get_mut(quad) else {
+                self.sockets.tcp_closed(socket);
+                continue;
+            };
+
+            connection.set_receive_window(self.sockets.tcp_receive_window(socket));
+            let (data, fin) = self.sockets.tcp_take(socket, connection.send_window());
+            out.extend(connection.send_data(&data));
+            if fin {
+                out.extend(connection.shutdown());
+            } else if data.is_empty() {
+                out.push(connection.window_update());
+            }
+        }
+
+        out
+    }
+
+    pub fn has_pending(&self) -> bool {
+        !self.resend.is_empty() || self.sockets.tcp_has_pending(|quad| self.send_window(quad))
+    }
+
+    /// Sockets of connections that are gone still have to hear about it.
+    fn send_window(&self, quad: Quad) -> usize {
+        self.connections
+            .find(quad)
+            .map_or(usize::MAX, state::TcpState::send_window)
+    }
+
+    /// Acts on a destination unreachable about the segment of `quad` that
+    /// started at `sequence`. Hard errors abort the connection, a smaller
+    /// path MTU shrinks its segments, anything else is left to retransmits.
+    pub fn on_unreachable(&mut self, quad: Quad, sequence: u32, reason: Unreachable) {
+        let Some(connection) = self.connections.get_mut(quad) else {
+            return;
+        };
+        if !connection.in_flight(sequence) {
+            tracing::debug!(
+                ?quad,
+                sequence,
+                "Ignoring ICMP error for a segment not in flight"
+            );
+            return;
+        }
+
+        match reason {
+            Unreachable::FragmentationNeeded { next_hop_mtu } => {
+                self.resend.extend(connection.lower_mtu(next_hop_mtu));
+            }
+            Unreachable::Protocol | Unreachable::Port => {
+                tracing::info!(?quad, ?reason, "Peer unreachable, aborting connection");
+                self.close(quad);
+            }
+            _ => tracing::debug!(?quad, ?reason, "Ignoring soft ICMP error"),
+        }
+    }
+
+    /// Whether a segment from `remote` to `local` belongs to a connection
+    /// we have, or a service or listener takes new ones there.
+    pub fn accepts(&self, remote: SocketAddr, local: SocketAddr) -> bool {
+        self.connections.find(Quad::new(remote, local)).is_some()
+            || socket::bound_for(&self.services, local).is_some()
+            || self.sockets.tcp_listening(local)
+    }
+
+    fn close(&mut self, quad: Quad) {
+        if let Some(socket) = self.connections.remove(quad) {
+            self.sockets.tcp_closed(socket);
         }
     }
 }
 
-impl Handler<Ip<'_>> for TcpHandler {
+impl Handler<IpPacket<'_>> for TcpHandler {
     type ReturnType = NetworkBuffer;
-    fn handle(&mut self, ip: Ip) -> anyhow::Result<Self::ReturnType> {
+    fn handle(&mut self, ip: IpPacket) -> anyhow::Result<Self::ReturnType> {
         let tcp_header = Tcp::parse(ip)?;
         tracing::info!("TcpHeader: {}", tcp_header);
 
-        if tcp_header.destination_port() != self.listen_port {
-            bail!(
-                "Received TCP message to wrong port! Expected: {} -> Actual: {}",
-                self.listen_port,
-                tcp_header.destination_port()
-            )
+        let local = SocketAddr::new(ip.destination(), tcp_header.destination_port());
+        let remote = SocketAddr::new(ip.source(), tcp_header.source_port());
+        let service = socket::bound_for(&self.services, local);
+        let listening = self.sockets.tcp_listening(local);
+        // Connections outlive the listener that accepted them. The stack
+        // answers anything else with a RST before it gets here.
+        let known = self.connections.find(Quad::new(remote, local)).is_some();
+        if !known && service.is_none() && !listening {
+            tracing::warn!(%local, "Dropping TCP segment to a port nobody listens on");
+            return Ok(NetworkBuffer::empty());
         }
 
+        let control = tcp_header.control();
         let (connection, quad) = self.connections.get(&tcp_header);
+        if let Some(socket) = connection.socket() {
+            connection.set_receive_window(self.sockets.tcp_receive_window(socket));
+        }
 
         let msg = match connection.handle(tcp_header)? {
             state::TcpControlMessage::None(tcp) => tcp,
             state::TcpControlMessage::Intercepted(tcp_control_message) => {
+                // Listeners take precedence over services. Without either
+                // anymore, nobody's there to take the connection.
+                if connection.is_established()
+                    && connection.socket().is_none()
+                    && (listening || service.is_none())
+                {
+                    match listening.then(|| self.sockets.tcp_accept(quad)).flatten() {
+                        Some(socket) => connection.attach(socket),
+                        None => {
+                            self.close(quad);
+                            return Ok(NetworkBuffer::empty());
+                        }
+                    }
+                }
+
+                if let Some(socket) = connection.socket()
+                    && control.contains(TcpControl::FIN)
+                    && connection.peer_closed()
+                {
+                    self.sockets.tcp_peer_closed(socket);
+                }
+
                 return Ok(tcp_control_message);
             }
             state::TcpControlMessage::Closed => {
-                self.connections.remove(quad);
+                self.close(quad);
                 return Ok(NetworkBuffer::empty());
             }
         };
 
+        if let Some(socket) = connection.socket() {
+            self.sockets.tcp_received(socket, msg.buf());
+            return Ok(connection.send(NetworkBuffer::empty(), msg));
+        }
+
         let (buf, msg) = if msg.control().contains(TcpControl::PSH) {
-            self.higher_level_handler.handle(msg)?
+            let handler = self.services.get_mut(&service.unwrap()).unwrap();
+            handler.handle(msg)?
         } else {
             (NetworkBuffer::empty(), msg)
         };
@@ -1,42 +1,266 @@
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, VecDeque},
+    net::SocketAddr,
+    time::Duration,
+};
 
 use crate::{
-    network::Handler,
+    network::{Handler, Timer},
     proto::{
-        NetworkBuffer, ProtocolBuffer,
-        ip::{Ip, IpHeaderWriter},
+        NetworkBuffer, Protocol, ProtocolBuffer,
+        ip::{self, IpPacket},
         tcp::{Tcp, TcpControl, TcpHeaderWriter},
     },
+    socket::tcp::StreamId,
+    time::{TimerId, Timers},
 };
 
+use super::connections::Quad;
+
+const INITIAL_RTO: Duration = Duration::from_secs(1);
+const MAX_RTO: Duration = Duration::from_secs(60);
+const MAX_RETRANSMITS: u32 = 5;
+/// How long we wait after the peer's FIN before sending our own.
+const FIN_DELAY: Duration = Duration::from_secs(1);
+/// Twice the maximum segment lifetime.
+const TIME_WAIT: Duration = Duration::from_secs(60);
+const KEEPALIVE_IDLE: Duration = Duration::from_secs(60);
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(10);
+const KEEPALIVE_PROBES: u32 = 3;
+/// Connections without any data for this long get closed from our side.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+const TTL: u8 = 64;
+/// What we put in a segment when nobody told us the MTU, a 1500 byte one.
+const DEFAULT_MSS: usize = 1460;
+/// What a peer takes without saying otherwise in its SYN (RFC 1122).
+const PEER_DEFAULT_MSS: usize = 536;
+/// Less than any peer gets, segments would be all headers (Linux' `TCP_MIN_MSS`).
+const MIN_PEER_MSS: usize = 88;
+/// The least path MTU discovery takes us down to, what every IPv4 host
+/// has to accept.
+const MIN_MSS: usize = 536;
+/// IPv4 and TCP headers without options, the rest of an MTU is data.
+const HEADERS_LEN: usize = 40;
+/// The same over IPv6, whose header is 20 bytes longer.
+const IPV6_HEADERS_LEN: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TcpTimer {
+    Retransmit,
+    Fin,
+    TimeWait,
+    Keepalive,
+    Idle,
+}
+
 pub struct TcpState {
     state: State,
     sequence: TcpSequences,
     requires_ack: bool,
-    nic: Arc<tun::Device>,
+    endpoints: Endpoints,
+    unacked: VecDeque<Unacked>,
+    /// How much the peer last said it takes beyond what it acked.
+    send_window: u16,
+    /// How much we take beyond what we acked, what the socket has room for.
+    receive_window: u16,
+    retransmits: u32,
+    keepalive_probes: u32,
+    /// What we put in a segment, at most what we announced in our SYN.
+    mss: usize,
+    /// The sequence number of our SYN.
+    isn: u32,
+    socket: Option<StreamId>,
+    timers: TcpTimers,
 }
 
 impl TcpState {
-    pub fn new(nic: Arc<tun::Device>) -> Self {
+    /// `mtu` is the interface's, segments and the MSS we announce fit it.
+    /// Our SYN goes out with a sequence number of `isn`.
+    pub fn new(quad: Quad, mtu: Option<u16>, isn: u32, timers: Timers<Timer>) -> Self {
+        let headers = match quad.local() {
+            SocketAddr::V4(_) => HEADERS_LEN,
+            SocketAddr::V6(_) => IPV6_HEADERS_LEN,
+        };
+        let mss = mtu.map_or(DEFAULT_MSS, |mtu| {
+            (mtu as usize).saturating_sub(headers).max(MIN_PEER_MSS)
+        });
+
         Self {
             state: State::Listen,
             sequence: Default::default(),
             requires_ack: false,
-            nic,
+            endpoints: Endpoints {
+                local: quad.local(),
+                remote: quad.remote(),
+            },
+            unacked: VecDeque::new(),
+            send_window: 0,
+            // Services take data as it comes, sockets say otherwise.
+            receive_window: u16::MAX,
+            retransmits: 0,
+            keepalive_probes: 0,
+            mss,
+            isn,
+            socket: None,
+            timers: TcpTimers::new(quad, timers),
+        }
+    }
+
+    pub fn is_established(&self) -> bool {
+        matches!(self.state, State::Established)
+    }
+
+    /// The socket this connection delivers to, if it was accepted through a `TcpListener`.
+    pub fn socket(&self) -> Option<StreamId> {
+        self.socket
+    }
+
+    pub fn attach(&mut self, socket: StreamId) {
+        self.socket = Some(socket);
+    }
+
+    /// Whether the peer sent its FIN, and with it everything it had.
+    pub fn peer_closed(&self) -> bool {
+        matches!(
+            self.state,
+            State::CloseWait | State::LastAck | State::Closing | State::TimeWait
+        )
+    }
+
+    /// How much more data the peer's window takes right now.
+    pub fn send_window(&self) -> usize {
+        let in_flight = self
+            .sequence
+            .server_sequence
+            .wrapping_sub(self.oldest_unacked());
+        (self.send_window as usize).saturating_sub(in_flight as usize)
+    }
+
+    pub fn set_receive_window(&mut self, window: usize) {
+        self.receive_window = window.min(u16::MAX as usize) as u16;
+    }
+
+    /// An ACK telling the peer how much we take now, once the application
+    /// made room.
+    pub fn window_update(&self) -> NetworkBuffer {
+        self.to_ip(self.ack())
+    }
+
+    /// Segments data written to the socket, returning complete IP packets.
+    /// Callers keep it to `send_window`.
+    pub fn send_data(&mut self, data: &[u8]) -> Vec<NetworkBuffer> {
+        if !matches!(self.state, State::Established | State::CloseWait) {
+            return vec![];
         }
+
+        if !data.is_empty() && self.is_established() {
+            self.on_activity(true);
+        }
+
+        data.chunks(self.mss)
+            .map(|chunk| {
+                let sequence = self.sequence.server_sequence;
+                let segment =
+                    self.segment(TcpControl::PSH | TcpControl::ACK, sequence, chunk.into());
+                self.sequence.server_sequence = self
+                    .sequence
+                    .server_sequence
+                    .wrapping_add(chunk.len() as u32);
+                self.track(&segment, sequence, self.sequence.server_sequence);
+                self.to_ip(segment)
+            })
+            .collect()
     }
 
-    pub fn send(&mut self, data: NetworkBuffer, msg: Tcp<Ip<'_>>) -> NetworkBuffer {
+    /// Closes our side of the connection, returning the FIN to send.
+    pub fn shutdown(&mut self) -> Option<NetworkBuffer> {
+        match self.state {
+            State::Established => {
+                self.timers.cancel(TcpTimer::Keepalive);
+                self.timers.cancel(TcpTimer::Idle);
+                self.state = State::FinWait1;
+            }
+            State::CloseWait => self.state = State::LastAck,
+            _ => return None,
+        }
+
+        Some(self.send_fin())
+    }
+
+    /// Whether we sent `sequence` and haven't seen it acked yet, so an ICMP
+    /// error quoting it can't have been made up by someone off path.
+    pub fn in_flight(&self, sequence: u32) -> bool {
+        sequence_reached(sequence, self.oldest_unacked())
+            && sequence_reached(self.sequence.server_sequence, sequence)
+    }
+
+    /// Where what the peer hasn't acked yet starts.
+    fn oldest_unacked(&self) -> u32 {
+        self.unacked
+            .front()
+            .map_or(self.sequence.server_sequence, |unacked| unacked.sequence)
+    }
+
+    /// Shrinks segments to fit a path MTU of `mtu`, resending what's in
+    /// flight and too big in pieces that fit. Returns complete IP packets.
+    pub fn lower_mtu(&mut self, mtu: u16) -> Vec<NetworkBuffer> {
+        let mss = (mtu as usize).saturating_sub(HEADERS_LEN).max(MIN_MSS);
+        if mss >= self.mss {
+            return vec![];
+        }
+        tracing::info!(mss, "Path MTU lowered, shrinking segments");
+        self.mss = mss;
+
+        let mut out = vec![];
+        for unacked in std::mem::take(&mut self.unacked) {
+            let Ok(segment) = Tcp::parse(unacked.segment) else {
+                continue;
+            };
+            if segment.buf().len() <= mss {
+                self.unacked.push_back(Unacked {
+                    segment: segment.into_inner(),
+                    ..unacked
+                });
+                continue;
+            }
+
+            let mut sequence = unacked.sequence;
+            for chunk in segment.buf().chunks(mss) {
+                let piece = self.segment(segment.control(), sequence, chunk.into());
+                let end_sequence = sequence.wrapping_add(chunk.len() as u32);
+                self.unacked.push_back(Unacked {
+                    segment: piece.as_slice().into(),
+                    sequence,
+                    end_sequence,
+                });
+                sequence = end_sequence;
+                out.push(self.to_ip(piece));
+            }
+        }
+
+        if !out.is_empty() {
+            self.timers.set(TcpTimer::Retransmit, INITIAL_RTO);
+        }
+        out
+    }
+
+    pub fn send(&mut self, data: NetworkBuffer, msg: Tcp<IpPacket<'_>>) -> NetworkBuffer {
         let buf = TcpHeaderWriter::new(
             msg.destination_port(),
             msg.source_port(),
             self.sequence.server_sequence,
             self.sequence.client_sequence,
-        );
+        )
+        .window(self.receive_window);
 
-        self.sequence.server_sequence += data.len() as u32;
+        let sequence = self.sequence.server_sequence;
+        self.sequence.server_sequence = self
+            .sequence
+            .server_sequence
+            .wrapping_add(data.len() as u32);
+        let has_data = !data.is_empty();
 
-        let buf = if !data.is_empty() {
+        let buf = if has_data {
             buf.set(TcpControl::PSH).data(data)
         } else {
             buf
@@ -49,60 +273,291 @@ impl TcpState {
             buf
         };
 
-        buf.calc_checksum(msg.inner()).to_buf()
+        let buf = buf.calc_checksum(msg.inner()).into_buf();
+        if has_data {
+            self.track(&buf, sequence, self.sequence.server_sequence);
+        }
+        buf
     }
 
-    fn send_fin(&self, msg: Tcp<Ip<'_>>) {
-        let tcp = TcpHeaderWriter::new(
-            msg.destination_port(),
-            msg.source_port(),
+    pub fn on_timer(&mut self, timer: TcpTimer) -> TimerOutcome {
+        self.timers.fired(timer);
+
+        match timer {
+            TcpTimer::Retransmit => {
+                let Some(unacked) = self.unacked.front() else {
+                    return TimerOutcome::None;
+                };
+
+                if self.retransmits >= MAX_RETRANSMITS {
+                    tracing::info!("Retransmits exhausted, dropping connection");
+                    return self.close();
+                }
+
+                let segment = unacked.segment.as_slice().into();
+                self.retransmits += 1;
+                let rto = (INITIAL_RTO * 2u32.pow(self.retransmits)).min(MAX_RTO);
+                self.timers.set(TcpTimer::Retransmit, rto);
+
+                tracing::info!("RETRANSMITTING");
+                TimerOutcome::Send(self.to_ip(segment))
+            }
+            TcpTimer::Fin => {
+                tracing::info!("SENDING FIN");
+                TimerOutcome::Send(self.send_fin())
+            }
+            TcpTimer::TimeWait => {
+                tracing::info!("TIME_WAIT expired");
+                self.close()
+            }
+            TcpTimer::Keepalive => {
+                if self.keepalive_probes >= KEEPALIVE_PROBES {
+                    tracing::info!("Keepalive unanswered, dropping connection");
+                    return self.close();
+                }
+
+                self.keepalive_probes += 1;
+                self.timers.set(TcpTimer::Keepalive, KEEPALIVE_INTERVAL);
+
+                // One byte behind what the peer expects, which it has to ack.
+                let probe = self.segment(
+                    TcpControl::ACK,
+                    self.sequence.server_sequence.wrapping_sub(1),
+                    NetworkBuffer::empty(),
+                );
+                TimerOutcome::Send(self.to_ip(probe))
+            }
+            TcpTimer::Idle => {
+                tracing::info!("Connection idle, closing");
+                self.timers.cancel(TcpTimer::Keepalive);
+                self.state = State::FinWait1;
+                TimerOutcome::Send(self.send_fin())
+            }
+        }
+    }
+
+    /// Cancels everything this connection still has scheduled.
+    pub fn cancel_timers(&mut self) {
+        self.timers.cancel_all();
+    }
+
+    fn close(&mut self) -> TimerOutcome {
+        self.cancel_timers();
+        TimerOutcome::Closed
+    }
+
+    fn send_fin(&mut self) -> NetworkBuffer {
+        let sequence = self.sequence.server_sequence;
+        let fin = self.segment(
+            TcpControl::FIN | TcpControl::ACK,
+            sequence,
+            NetworkBuffer::empty(),
+        );
+        self.sequence.server_sequence = self.sequence.server_sequence.wrapping_add(1);
+        self.track(&fin, sequence, self.sequence.server_sequence);
+
+        self.to_ip(fin)
+    }
+
+    fn ack(&self) -> NetworkBuffer {
+        self.segment(
+            TcpControl::ACK,
             self.sequence.server_sequence,
+            NetworkBuffer::empty(),
+        )
+    }
+
+    fn segment(&self, control: TcpControl, sequence: u32, data: NetworkBuffer) -> NetworkBuffer {
+        TcpHeaderWriter::new(
+            self.endpoints.local.port(),
+            self.endpoints.remote.port(),
+            sequence,
             self.sequence.client_sequence,
         )
-        .set(TcpControl::FIN)
-        .calc_checksum(msg.inner())
-        .to_buf();
-        let ip = IpHeaderWriter::new(
-            msg.inner().destination(),
-            msg.inner().source(),
-            crate::proto::Protocol::TCP,
-            64,
-            tcp,
+        .window(self.receive_window)
+        .set(control)
+        .data(data)
+        .calc_checksum_for(self.endpoints.local.ip(), self.endpoints.remote.ip())
+        .into_buf()
+    }
+
+    fn to_ip(&self, segment: NetworkBuffer) -> NetworkBuffer {
+        ip::write_packet(
+            self.endpoints.local.ip(),
+            self.endpoints.remote.ip(),
+            Protocol::TCP,
+            TTL,
+            segment,
         )
-        .to_buf();
+    }
 
-        let nic = self.nic.clone();
-        std::thread::spawn(move || {
-            std::thread::sleep(std::time::Duration::from_secs(1));
-            tracing::info!("SENDING FIN");
-            nic.send(&ip).unwrap();
+    /// Keeps a copy of a segment, starting at `sequence`, until the peer
+    /// acks `end_sequence`.
+    fn track(&mut self, segment: &NetworkBuffer, sequence: u32, end_sequence: u32) {
+        self.unacked.push_back(Unacked {
+            segment: segment.as_slice().into(),
+            sequence,
+            end_sequence,
         });
+
+        if self.unacked.len() == 1 {
+            self.retransmits = 0;
+            self.timers.set(TcpTimer::Retransmit, INITIAL_RTO);
+        }
+    }
+
+    fn on_ack(&mut self, ack: u32, window: u16) {
+        // An older ACK says nothing about the window anymore.
+        if sequence_reached(ack, self.oldest_unacked()) {
+            self.send_window = window;
+        }
+
+        let before = self.unacked.len();
+        self.unacked
+            .retain(|unacked| !sequence_reached(ack, unacked.end_sequence));
+
+        if self.unacked.len() == before {
+            return;
+        }
+
+        self.retransmits = 0;
+        if self.unacked.is_empty() {
+            self.timers.cancel(TcpTimer::Retransmit);
+        } else {
+            self.timers.set(TcpTimer::Retransmit, INITIAL_RTO);
+        }
+    }
+
+    fn on_activity(&mut self, has_data: bool) {
+        self.keepalive_probes = 0;
+        self.timers.set(TcpTimer::Keepalive, KEEPALIVE_IDLE);
+        if has_data {
+            self.timers.set(TcpTimer::Idle, IDLE_TIMEOUT);
+        }
+    }
+
+    fn enter_time_wait(&mut self) {
+        self.state = State::TimeWait;
+        self.timers.cancel(TcpTimer::Retransmit);
+        self.timers.set(TcpTimer::TimeWait, TIME_WAIT);
     }
 }
 
 pub enum TcpControlMessage<'a> {
-    None(Tcp<Ip<'a>>),
+    None(Tcp<IpPacket<'a>>),
     Intercepted(NetworkBuffer),
     Closed,
 }
 
+pub enum TimerOutcome {
+    Send(NetworkBuffer),
+    None,
+    Closed,
+}
+
 #[derive(Default, Debug)]
 pub struct TcpSequences {
     client_sequence: u32,
     server_sequence: u32,
 }
 
-impl<'a> Handler<Tcp<Ip<'a>>> for TcpState {
+#[derive(Debug, Clone, Copy)]
+struct Endpoints {
+    local: SocketAddr,
+    remote: SocketAddr,
+}
+
+struct Unacked {
+    segment: NetworkBuffer,
+    sequence: u32,
+    end_sequence: u32,
+}
+
+/// The timers a single connection has running, at most one of each kind.
+struct TcpTimers {
+    quad: Quad,
+    timers: Timers<Timer>,
+    active: HashMap<TcpTimer, TimerId>,
+}
+
+impl TcpTimers {
+    fn new(quad: Quad, timers: Timers<Timer>) -> Self {
+        Self {
+            quad,
+            timers,
+            active: HashMap::new(),
+        }
+    }
+
+    /// (Re)starts a timer, replacing one of the same kind if it's running.
+    fn set(&mut self, timer: TcpTimer, delay: Duration) {
+        self.cancel(timer);
+        let id = self.timers.schedule(delay, Timer::Tcp(self.quad, timer));
+        self.active.insert(timer, id);
+    }
+
+    fn cancel(&mut self, timer: TcpTimer) {
+        if let Some(id) = self.active.remove(&timer) {
+            self.timers.cancel(id);
+        }
+    }
+
+    fn fired(&mut self, timer: TcpTimer) {
+        self.active.remove(&timer);
+    }
+
+    fn cancel_all(&mut self) {
+        for (_, id) in self.active.drain() {
+            self.timers.cancel(id);
+        }
+    }
+}
+
+/// `sequence >= target`, allowing for wrap around.
+fn sequence_reached(sequence: u32, target: u32) -> bool {
+    sequence.wrapping_sub(target) as i32 >= 0
+}
+
+impl<'a> Handler<Tcp<IpPacket<'a>>> for TcpState {
     type ReturnType = TcpControlMessage<'a>;
-    fn handle(&mut self, msg: Tcp<Ip<'a>>) -> anyhow::Result<Self::ReturnType> {
+    fn handle(&mut self, msg: Tcp<IpPacket<'a>>) -> anyhow::Result<Self::ReturnType> {
         let tcp_control = msg.control();
 
+        if tcp_control.contains(TcpControl::ACK) {
+            self.on_ack(msg.ack_number(), msg.window());
+        }
+
+        // We only take the next segment in order, anything else is a
+        // duplicate or came early. The ACK tells the peer where we are.
+        let has_sequence = !msg.buf().is_empty() || tcp_control.contains(TcpControl::FIN);
+        if let State::Established = self.state
+            && has_sequence
+            && msg.sequence_number() != self.sequence.client_sequence
+        {
+            tracing::debug!(
+                sequence = msg.sequence_number(),
+                expected = self.sequence.client_sequence,
+                "Dropping out of order segment"
+            );
+            return Ok(TcpControlMessage::Intercepted(self.ack()));
+        }
+
+        if let State::Established = self.state {
+            self.on_activity(!msg.buf().is_empty());
+        }
+
         match self.state {
             State::Listen if tcp_control.contains(TcpControl::SYN) => {
                 tracing::info!("Received SYN while listening, Sending Syn/Ack");
                 self.state = State::SynRecv;
-                self.sequence.client_sequence = msg.sequence_number() + 1;
-                self.sequence.server_sequence = 0;
+                self.send_window = msg.window();
+                self.sequence.client_sequence = msg.sequence_number().wrapping_add(1);
+                self.sequence.server_sequence = self.isn;
+
+                // We announce what fits our MTU, and send what fits theirs.
+                let announced = self.mss;
+                let peer_mss = msg.mss().map_or(PEER_DEFAULT_MSS, usize::from);
+                self.mss = announced.min(peer_mss.max(MIN_PEER_MSS));
 
                 let header = TcpHeaderWriter::new(
                     msg.destination_port(),
@@ -110,29 +565,47 @@ impl<'a> Handler<Tcp<Ip<'a>>> for TcpState {
                     self.sequence.server_sequence,
                     self.sequence.client_sequence,
                 )
+                .window(self.receive_window)
                 .set(TcpControl::SYN | TcpControl::ACK)
-                .calc_checksum(msg.inner());
+                .mss(announced.min(u16::MAX as usize) as u16)
+                .calc_checksum(msg.inner())
+                .into_buf();
 
-                self.sequence.server_sequence += 1;
+                self.sequence.server_sequence = self.isn.wrapping_add(1);
+                self.track(&header, self.isn, self.sequence.server_sequence);
 
-                Ok(TcpControlMessage::Intercepted(header.to_buf()))
+                Ok(TcpControlMessage::Intercepted(header))
             }
             State::SynRecv if tcp_control.contains(TcpControl::ACK) => {
-                tracing::info!("Received ACK of Syn, moving to established");
-
-                if msg.sequence_number() == self.sequence.client_sequence
-                    && msg.ack_number() == self.sequence.server_sequence
-                {
-                    tracing::info!("It acked!");
+                // An ACK of anything but our SYN is refused, and we keep
+                // waiting for the right one (RFC 793).
+                if msg.ack_number() != self.sequence.server_sequence {
+                    tracing::debug!(
+                        ack = msg.ack_number(),
+                        expected = self.sequence.server_sequence,
+                        "Refusing ACK of something we never sent"
+                    );
+                    let reset = TcpHeaderWriter::new(
+                        msg.destination_port(),
+                        msg.source_port(),
+                        msg.ack_number(),
+                        0,
+                    )
+                    .set(TcpControl::RST)
+                    .calc_checksum(msg.inner())
+                    .into_buf();
+                    return Ok(TcpControlMessage::Intercepted(reset));
                 }
 
+                tracing::info!("Received ACK of Syn, moving to established");
                 self.state = State::Established;
+                self.on_activity(true);
 
                 Ok(TcpControlMessage::Intercepted(NetworkBuffer::empty()))
             }
             State::Established if tcp_control.contains(TcpControl::FIN) => {
                 tracing::info!("RECEIVED FIN");
-                self.sequence.client_sequence += 1;
+                self.sequence.client_sequence = self.sequence.client_sequence.wrapping_add(1);
 
                 let buf = TcpHeaderWriter::new(
                     msg.destination_port(),
@@ -140,60 +613,115 @@ impl<'a> Handler<Tcp<Ip<'a>>> for TcpState {
                     self.sequence.server_sequence,
                     self.sequence.client_sequence,
                 )
+                .window(self.receive_window)
                 .set(TcpControl::ACK)
                 .calc_checksum(msg.inner())
-                .to_buf();
+                .into_buf();
+
+                self.timers.cancel(TcpTimer::Keepalive);
+                self.timers.cancel(TcpTimer::Idle);
 
-                self.state = State::LastAck;
-                self.send_fin(msg);
+                // A socket closes its side whenever the application is done with it.
+                if self.socket.is_some() {
+                    self.state = State::CloseWait;
+                } else {
+                    self.state = State::LastAck;
+                    self.timers.set(TcpTimer::Fin, FIN_DELAY);
+                }
                 Ok(TcpControlMessage::Intercepted(buf))
             }
             State::Established => {
                 let data_length = msg.buf().len();
 
+                // The peer retransmits once the application made room.
+                if data_length > self.receive_window as usize {
+                    tracing::debug!(
+                        len = data_length,
+                        window = self.receive_window,
+                        "Dropping data beyond our window"
+                    );
+                    return Ok(TcpControlMessage::Intercepted(self.ack()));
+                }
+
                 if data_length > 0 {
+                    self.receive_window -= data_length as u16;
                     self.requires_ack = true;
-                    self.sequence.client_sequence += (data_length) as u32;
+                    self.sequence.client_sequence = self
+                        .sequence
+                        .client_sequence
+                        .wrapping_add(data_length as u32);
                     Ok(TcpControlMessage::None(msg))
                 } else {
                     Ok(TcpControlMessage::Intercepted(NetworkBuffer::empty()))
                 }
             }
+            State::CloseWait => {
+                if tcp_control.contains(TcpControl::FIN) {
+                    return Ok(TcpControlMessage::Intercepted(self.ack()));
+                }
+                Ok(TcpControlMessage::Intercepted(NetworkBuffer::empty()))
+            }
             State::LastAck => {
                 tracing::info!("Received TCP Frame while in LastAck, should be close");
 
-                if msg.sequence_number() == (self.sequence.client_sequence)
-                    && msg.ack_number() == (self.sequence.server_sequence + 1)
-                {
+                let fin_sent = !self.timers.active.contains_key(&TcpTimer::Fin);
+                if fin_sent && self.unacked.is_empty() {
                     tracing::info!("It Closed!");
+                    self.cancel_timers();
+                    return Ok(TcpControlMessage::Closed);
                 }
-                Ok(TcpControlMessage::Closed)
+
+                // Our ACK of their FIN got lost, send it again.
+                if tcp_control.contains(TcpControl::FIN) {
+                    return Ok(TcpControlMessage::Intercepted(self.ack()));
+                }
+                Ok(TcpControlMessage::Intercepted(NetworkBuffer::empty()))
+            }
+            State::FinWait1 | State::FinWait2 | State::Closing | State::TimeWait => {
+                let fin_acked = self.unacked.is_empty();
+
+                if tcp_control.contains(TcpControl::FIN) {
+                    if let State::FinWait1 | State::FinWait2 = self.state {
+                        self.sequence.client_sequence =
+                            self.sequence.client_sequence.wrapping_add(1);
+                    }
+
+                    match self.state {
+                        State::FinWait1 if !fin_acked => self.state = State::Closing,
+                        State::Closing => {}
+                        _ => self.enter_time_wait(),
+                    }
+
+                    return Ok(TcpControlMessage::Intercepted(self.ack()));
+                }
+
+                match self.state {
+                    State::FinWait1 if fin_acked => self.state = State::FinWait2,
+                    State::Closing if fin_acked => self.enter_time_wait(),
+                    _ => {}
+                }
+
+                Ok(TcpControlMessage::Intercepted(NetworkBuffer::empty()))
             }
             State::Listen | State::SynRecv => {
-                tracing::info!("UNEXPECTED STATE");
-                // std::thread::sleep(std::time::Duration::from_secs(30));
+                tracing::info!(?tcp_control, state = ?self.state, "Unexpected segment");
+                self.cancel_timers();
                 Ok(TcpControlMessage::Closed)
-
-                // other => bail!(
-                //     "Received TcpControl {:?} when in state: {:?}",
-                //     tcp_control,
-                //     other
-                // ),
             }
         }
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default)]
 enum State {
+    #[default]
     Listen,
     SynRecv,
     Established,
+    CloseWait,
     LastAck,
-}
-
-impl Default for State {
-    fn default() -> Self {
-        Self::Listen
-    }
+    FinWait1,
+    FinWait2,
+    Closing,
+    TimeWait,
 }
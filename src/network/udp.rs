@@ -1,23 +1,117 @@
-use crate::proto::{
-    NetworkBuffer, ProtocolBuffer,
-    udp::{Udp, UdpHeaderWriter},
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use crate::{
+    proto::{
+        NetworkBuffer, Protocol, ProtocolBuffer,
+        icmp::Unreachable,
+        ip::{self, IpPacket},
+        udp::{Udp, UdpHeaderWriter},
+    },
+    socket::Sockets,
 };
 
 use super::Handler;
 
-pub struct UdpHandler;
+const TTL: u8 = 64;
+
+/// Hands datagrams to whichever `UdpSocket` is bound to their port.
+pub struct UdpHandler {
+    /// Where datagrams applications send come from.
+    address: Ipv4Addr,
+    ipv6_address: Option<Ipv6Addr>,
+    sockets: Sockets,
+}
+
+impl UdpHandler {
+    pub fn new(address: Ipv4Addr, sockets: Sockets) -> Self {
+        Self {
+            address,
+            ipv6_address: None,
+            sockets,
+        }
+    }
+
+    /// Lets applications send to IPv6 destinations, from `address`.
+    pub fn ipv6(mut self, address: Ipv6Addr) -> Self {
+        self.ipv6_address = Some(address);
+        self
+    }
+
+    /// Sends whatever applications wrote to their sockets, as complete IP packets.
+    pub fn poll(&mut self) -> Vec<NetworkBuffer> {
+        self.sockets
+            .udp_take()
+            .into_iter()
+            .filter_map(|datagram| {
+                let destination = datagram.destination.ip();
+                let source = match datagram.source {
+                    Some(source) if source.is_ipv4() != destination.is_ipv4() => None,
+                    Some(source) => Some(source),
+                    None => self.source_for(destination),
+                };
+                let Some(source) = source else {
+                    tracing::warn!(%destination, "No local address to send from, dropping datagram");
+                    return None;
+                };
+
+                let udp = UdpHeaderWriter::new(datagram.source_port, datagram.destination.port())
+                    .data(datagram.data.into())
+                    .calc_checksum_for(source, destination)
+                    .into_buf();
+
+                Some(ip::write_packet(source, destination, Protocol::UDP, TTL, udp))
+            })
+            .collect()
+    }
+
+    fn source_for(&self, destination: IpAddr) -> Option<IpAddr> {
+        match destination {
+            IpAddr::V4(_) => Some(self.address.into()),
+            IpAddr::V6(_) => self.ipv6_address.map(IpAddr::from),
+        }
+    }
+
+    pub fn has_pending(&self) -> bool {
+        self.sockets.udp_has_pending()
+    }
+
+    /// Whether a socket takes datagrams to `local`.
+    pub fn is_bound(&self, local: SocketAddr) -> bool {
+        self.sockets.udp_bound(local)
+    }
+
+    /// Passes a destination unreachable about a datagram sent from `local`
+    /// to `remote` on to the socket that sent it.
+    pub fn on_unreachable(&self, local: SocketAddr, remote: SocketAddr, reason: Unreachable) {
+        let error = match reason {
+            Unreachable::Port | Unreachable::Protocol => io::ErrorKind::ConnectionRefused,
+            Unreachable::Host => io::ErrorKind::HostUnreachable,
+            Unreachable::Network => io::ErrorKind::NetworkUnreachable,
+            _ => return,
+        };
+        if !self.sockets.udp_error(local, remote, error) {
+            tracing::debug!(%local, %remote, "ICMP error for nothing a socket sent lately");
+        }
+    }
+}
 
-impl<P: ProtocolBuffer> Handler<P> for UdpHandler {
+impl Handler<IpPacket<'_>> for UdpHandler {
     type ReturnType = NetworkBuffer;
 
-    fn handle(&mut self, msg: P) -> anyhow::Result<Self::ReturnType> {
-        let udp_msg = Udp::parse(msg)?;
+    fn handle(&mut self, ip: IpPacket) -> anyhow::Result<Self::ReturnType> {
+        let (from, local) = (ip.source(), ip.destination());
+        let udp_msg = Udp::parse(ip)?;
         tracing::info!("UdpHeader: {}", udp_msg);
 
-        let buf = UdpHeaderWriter::new(udp_msg.destination_port(), udp_msg.source_port())
-            .data(udp_msg.buf().into())
-            .to_buf();
+        let local = SocketAddr::new(local, udp_msg.destination_port());
+        let from = SocketAddr::new(from, udp_msg.source_port());
+        if !self.sockets.udp_received(local, from, udp_msg.buf()) {
+            tracing::warn!(%local, %from, "Dropping UDP datagram to unbound port");
+        }
 
-        Ok(buf)
+        Ok(NetworkBuffer::empty())
     }
 }
@@ -9,6 +9,12 @@ struct BufferInner {
     buffer: [u8; 1024],
 }
 
+impl Default for OutOfBandBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl OutOfBandBuffer {
     pub fn new() -> Self {
         let inner = Box::new(BufferInner {
@@ -0,0 +1,215 @@
+use std::{
+    io::{self, Read, Write},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Microsecond resolution pcap magic, as written by us and by most tools.
+const MAGIC: u32 = 0xa1b2_c3d4;
+/// Nanosecond resolution pcap magic.
+const MAGIC_NANOS: u32 = 0xa1b2_3c4d;
+const VERSION_MAJOR: u16 = 2;
+const VERSION_MINOR: u16 = 4;
+const SNAPLEN: u32 = 65535;
+/// The largest record we read, what tcpdump captures at most. Anything
+/// bigger is a corrupt file, not a packet.
+const MAX_CAPTURED: usize = 262_144;
+pub const LINKTYPE_ETHERNET: u32 = 1;
+/// Raw IP, the packet starts directly with the IPv4/IPv6 header.
+pub const LINKTYPE_RAW: u32 = 101;
+
+const GLOBAL_HEADER_LEN: usize = 24;
+const RECORD_HEADER_LEN: usize = 16;
+
+/// Writes packets in the classic libpcap format, readable by Wireshark and tcpdump.
+pub struct PcapWriter<W: Write> {
+    inner: W,
+}
+
+impl<W: Write> PcapWriter<W> {
+    #[cfg(test)]
+    pub fn new(inner: W) -> io::Result<Self> {
+        Self::with_linktype(inner, LINKTYPE_RAW)
+    }
+
+    pub fn with_linktype(mut inner: W, linktype: u32) -> io::Result<Self> {
+        let mut header = [0u8; GLOBAL_HEADER_LEN];
+        header[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        header[4..6].copy_from_slice(&VERSION_MAJOR.to_le_bytes());
+        header[6..8].copy_from_slice(&VERSION_MINOR.to_le_bytes());
+        // thiszone and sigfigs are always 0
+        header[16..20].copy_from_slice(&SNAPLEN.to_le_bytes());
+        header[20..24].copy_from_slice(&linktype.to_le_bytes());
+        inner.write_all(&header)?;
+
+        Ok(Self { inner })
+    }
+
+    pub fn write_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.write_packet_at(timestamp, packet)
+    }
+
+    /// Writes a packet with a timestamp given as time since the unix epoch.
+    pub fn write_packet_at(&mut self, timestamp: Duration, packet: &[u8]) -> io::Result<()> {
+        let captured = packet.len().min(SNAPLEN as usize);
+
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        header[0..4].copy_from_slice(&(timestamp.as_secs() as u32).to_le_bytes());
+        header[4..8].copy_from_slice(&timestamp.subsec_micros().to_le_bytes());
+        header[8..12].copy_from_slice(&(captured as u32).to_le_bytes());
+        header[12..16].copy_from_slice(&(packet.len() as u32).to_le_bytes());
+
+        self.inner.write_all(&header)?;
+        self.inner.write_all(&packet[..captured])?;
+        // Flush every packet so a capture is usable even if we die mid-run.
+        self.inner.flush()
+    }
+
+    #[cfg(test)]
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+pub struct PcapPacket {
+    /// Time since the unix epoch.
+    // Replays feed packets as fast as the stack takes them, only the tests
+    // look at when they were captured.
+    #[allow(dead_code)]
+    pub timestamp: Duration,
+    pub data: Vec<u8>,
+}
+
+/// Reads packets from a libpcap file, in either byte order and either timestamp resolution.
+pub struct PcapReader<R: Read> {
+    inner: R,
+    big_endian: bool,
+    nanos: bool,
+    linktype: u32,
+}
+
+impl<R: Read> PcapReader<R> {
+    pub fn new(mut inner: R) -> io::Result<Self> {
+        let mut header = [0u8; GLOBAL_HEADER_LEN];
+        inner.read_exact(&mut header)?;
+
+        let magic = [header[0], header[1], header[2], header[3]];
+        let (big_endian, nanos) = if u32::from_le_bytes(magic) == MAGIC {
+            (false, false)
+        } else if u32::from_be_bytes(magic) == MAGIC {
+            (true, false)
+        } else if u32::from_le_bytes(magic) == MAGIC_NANOS {
+            (false, true)
+        } else if u32::from_be_bytes(magic) == MAGIC_NANOS {
+            (true, true)
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Not a pcap file, magic: {:x?}", magic),
+            ));
+        };
+
+        let mut s = Self {
+            inner,
+            big_endian,
+            nanos,
+            linktype: 0,
+        };
+        s.linktype = s.read_u32(&header[20..24]);
+
+        Ok(s)
+    }
+
+    pub fn linktype(&self) -> u32 {
+        self.linktype
+    }
+
+    /// Returns the next packet, or `None` at the end of the capture.
+    pub fn next_packet(&mut self) -> io::Result<Option<PcapPacket>> {
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        match self.inner.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let seconds = self.read_u32(&header[0..4]) as u64;
+        let fraction = self.read_u32(&header[4..8]);
+        let captured = self.read_u32(&header[8..12]) as usize;
+        if captured > MAX_CAPTURED {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Record of {} bytes, corrupt capture", captured),
+            ));
+        }
+
+        let subsec = if self.nanos {
+            Duration::from_nanos(fraction as u64)
+        } else {
+            Duration::from_micros(fraction as u64)
+        };
+
+        let mut data = vec![0; captured];
+        self.inner.read_exact(&mut data)?;
+
+        Ok(Some(PcapPacket {
+            timestamp: Duration::from_secs(seconds) + subsec,
+            data,
+        }))
+    }
+
+    fn read_u32(&self, d: &[u8]) -> u32 {
+        let bytes = [d[0], d[1], d[2], d[3]];
+        if self.big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_packets() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer
+            .write_packet_at(Duration::from_micros(1_500_000), &[0x45, 1, 2, 3])
+            .unwrap();
+        writer
+            .write_packet_at(Duration::from_secs(2), &[0x45; 40])
+            .unwrap();
+        let bytes = writer.into_inner();
+
+        let mut reader = PcapReader::new(bytes.as_slice()).unwrap();
+        assert_eq!(reader.linktype(), LINKTYPE_RAW);
+
+        let first = reader.next_packet().unwrap().unwrap();
+        assert_eq!(first.timestamp, Duration::from_micros(1_500_000));
+        assert_eq!(first.data, [0x45, 1, 2, 3]);
+
+        let second = reader.next_packet().unwrap().unwrap();
+        assert_eq!(second.timestamp, Duration::from_secs(2));
+        assert_eq!(second.data, [0x45; 40]);
+
+        assert!(reader.next_packet().unwrap().is_none());
+    }
+
+    #[test]
+    fn rejects_oversized_records() {
+        let mut writer = PcapWriter::new(Vec::new()).unwrap();
+        writer.write_packet_at(Duration::ZERO, &[0x45; 4]).unwrap();
+        let mut bytes = writer.into_inner();
+        // The captured length of the only record.
+        bytes[GLOBAL_HEADER_LEN + 8..GLOBAL_HEADER_LEN + 12]
+            .copy_from_slice(&u32::MAX.to_le_bytes());
+
+        let mut reader = PcapReader::new(bytes.as_slice()).unwrap();
+        let error = reader.next_packet().err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,133 @@
+use std::{fmt::Display, net::Ipv4Addr};
+
+use crate::utils;
+
+use super::{
+    NetworkBuffer, ProtocolBuffer,
+    error::{Layer, ParseError},
+    ethernet::{EtherType, MacAddr},
+};
+
+/// Ethernet hardware addresses and IPv4 protocol addresses, the only kind
+/// of ARP packet we deal in.
+const ARP_LEN: usize = 28;
+const HARDWARE_ETHERNET: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ArpOperation {
+    Request,
+    Reply,
+    Unknown(u16),
+}
+
+impl From<u16> for ArpOperation {
+    fn from(value: u16) -> Self {
+        match value {
+            1 => Self::Request,
+            2 => Self::Reply,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+impl From<ArpOperation> for u16 {
+    fn from(value: ArpOperation) -> Self {
+        match value {
+            ArpOperation::Request => 1,
+            ArpOperation::Reply => 2,
+            ArpOperation::Unknown(val) => val,
+        }
+    }
+}
+
+pub struct Arp<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ProtocolBuffer for Arp<'a> {
+    fn buf(&self) -> &[u8] {
+        &self.data[ARP_LEN..]
+    }
+}
+
+impl<'a> Arp<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        ParseError::check_length(Layer::Arp, ARP_LEN, bytes.len())?;
+
+        let s = Self { data: bytes };
+        if s.hardware_type() != HARDWARE_ETHERNET
+            || s.protocol_type() != EtherType::Ipv4
+            || s.data[4] != 6
+            || s.data[5] != 4
+        {
+            return Err(ParseError::Unsupported { layer: Layer::Arp });
+        }
+
+        Ok(s)
+    }
+
+    pub fn hardware_type(&self) -> u16 {
+        utils::read_u16(&self.data[0..2])
+    }
+
+    pub fn protocol_type(&self) -> EtherType {
+        utils::read_u16(&self.data[2..4]).into()
+    }
+
+    pub fn operation(&self) -> ArpOperation {
+        utils::read_u16(&self.data[6..8]).into()
+    }
+
+    pub fn sender_mac(&self) -> MacAddr {
+        MacAddr(self.data[8..14].try_into().unwrap())
+    }
+
+    pub fn sender_ip(&self) -> Ipv4Addr {
+        Ipv4Addr::from(utils::read_u32(&self.data[14..18]))
+    }
+
+    pub fn target_mac(&self) -> MacAddr {
+        MacAddr(self.data[18..24].try_into().unwrap())
+    }
+
+    pub fn target_ip(&self) -> Ipv4Addr {
+        Ipv4Addr::from(utils::read_u32(&self.data[24..28]))
+    }
+}
+
+pub struct ArpWriter {
+    buf: NetworkBuffer,
+}
+
+impl ArpWriter {
+    pub fn new(
+        operation: ArpOperation,
+        sender: (MacAddr, Ipv4Addr),
+        target: (MacAddr, Ipv4Addr),
+    ) -> Self {
+        let mut buf = NetworkBuffer::new(ARP_LEN);
+        buf.extend_from_slice(&HARDWARE_ETHERNET.to_be_bytes());
+        buf.extend_from_slice(&u16::from(EtherType::Ipv4).to_be_bytes());
+        buf.extend_from_slice(&[6, 4]);
+        buf.extend_from_slice(&u16::from(operation).to_be_bytes());
+        buf.extend_from_slice(&sender.0.0);
+        buf.extend_from_slice(&sender.1.octets());
+        buf.extend_from_slice(&target.0.0);
+        buf.extend_from_slice(&target.1.octets());
+
+        Self { buf }
+    }
+
+    pub fn into_buf(self) -> NetworkBuffer {
+        self.buf
+    }
+}
+
+impl<'a> Display for Arp<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "ARP")?;
+        writeln!(f, "- Op: {:?}", self.operation())?;
+        writeln!(f, "- Sender: {} {}", self.sender_mac(), self.sender_ip())?;
+        writeln!(f, "- Target: {} {}", self.target_mac(), self.target_ip())
+    }
+}
@@ -0,0 +1,110 @@
+use std::fmt::Display;
+
+/// Where in the stack a packet turned out to be malformed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Layer {
+    Ethernet,
+    Arp,
+    /// IPv4 or IPv6.
+    Ip,
+    Tcp,
+    Udp,
+    Icmp,
+    Http,
+}
+
+/// Why a header from the wire was rejected. Nothing past the checks in
+/// `parse` is read unchecked, so a packet that parses can't panic later.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseError {
+    /// Fewer bytes than the fixed part of the header.
+    Truncated {
+        layer: Layer,
+        needed: usize,
+        available: usize,
+    },
+    /// A version other than the one being parsed.
+    Version { layer: Layer, version: u8 },
+    /// A header length below the minimum, or past the end of the packet.
+    HeaderLength {
+        layer: Layer,
+        length: usize,
+        available: usize,
+    },
+    /// A length field that disagrees with what actually arrived.
+    Length {
+        layer: Layer,
+        length: usize,
+        available: usize,
+    },
+    /// Well formed, but nothing we speak.
+    Unsupported { layer: Layer },
+    /// Not following the protocol's syntax, like a text protocol without
+    /// its line breaks.
+    Malformed { layer: Layer },
+}
+
+impl ParseError {
+    pub fn layer(&self) -> Layer {
+        match *self {
+            Self::Truncated { layer, .. }
+            | Self::Version { layer, .. }
+            | Self::HeaderLength { layer, .. }
+            | Self::Length { layer, .. }
+            | Self::Unsupported { layer }
+            | Self::Malformed { layer } => layer,
+        }
+    }
+
+    pub(super) fn check_length(layer: Layer, needed: usize, available: usize) -> Result<(), Self> {
+        if available < needed {
+            return Err(Self::Truncated {
+                layer,
+                needed,
+                available,
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Truncated {
+                layer,
+                needed,
+                available,
+            } => write!(
+                f,
+                "{:?}: Expected at least {} bytes, got {}",
+                layer, needed, available
+            ),
+            Self::Version { layer, version } => {
+                write!(f, "{:?}: Unexpected version {}", layer, version)
+            }
+            Self::HeaderLength {
+                layer,
+                length,
+                available,
+            } => write!(
+                f,
+                "{:?}: Invalid header length {} with {} bytes",
+                layer, length, available
+            ),
+            Self::Length {
+                layer,
+                length,
+                available,
+            } => write!(
+                f,
+                "{:?}: Length field says {} bytes, got {}",
+                layer, length, available
+            ),
+            Self::Unsupported { layer } => write!(f, "{:?}: Unsupported packet", layer),
+            Self::Malformed { layer } => write!(f, "{:?}: Malformed packet", layer),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
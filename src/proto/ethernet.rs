@@ -0,0 +1,156 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{Context, Result};
+
+use crate::utils;
+
+use super::{
+    NetworkBuffer, ProtocolBuffer,
+    error::{Layer, ParseError},
+};
+
+pub const ETHERNET_HEADER_LEN: usize = 14;
+/// Frames shorter than this get padded, without the frame check sequence.
+const MIN_FRAME_LEN: usize = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct MacAddr(pub [u8; 6]);
+
+impl MacAddr {
+    pub const BROADCAST: MacAddr = MacAddr([0xff; 6]);
+    pub const ZERO: MacAddr = MacAddr([0; 6]);
+}
+
+impl Display for MacAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(f, "{a:02x}:{b:02x}:{c:02x}:{d:02x}:{e:02x}:{g:02x}")
+    }
+}
+
+impl FromStr for MacAddr {
+    type Err = anyhow::Error;
+
+    /// `02:00:00:00:00:01`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut mac = [0; 6];
+        let mut parts = s.split(':');
+        for byte in &mut mac {
+            let part = parts
+                .next()
+                .with_context(|| format!("Too few octets in MAC address: {}", s))?;
+            *byte = u8::from_str_radix(part, 16)
+                .with_context(|| format!("Invalid octet in MAC address: {}", s))?;
+        }
+
+        if parts.next().is_some() {
+            anyhow::bail!("Too many octets in MAC address: {}", s)
+        }
+        Ok(Self(mac))
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for MacAddr {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EtherType {
+    Ipv4,
+    Arp,
+    Unknown(u16),
+}
+
+impl From<u16> for EtherType {
+    fn from(value: u16) -> Self {
+        match value {
+            0x0800 => Self::Ipv4,
+            0x0806 => Self::Arp,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+impl From<EtherType> for u16 {
+    fn from(value: EtherType) -> Self {
+        match value {
+            EtherType::Ipv4 => 0x0800,
+            EtherType::Arp => 0x0806,
+            EtherType::Unknown(val) => val,
+        }
+    }
+}
+
+/// An Ethernet II frame, as a TAP device hands it over.
+pub struct Ethernet<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> ProtocolBuffer for Ethernet<'a> {
+    fn buf(&self) -> &[u8] {
+        self.payload()
+    }
+}
+
+impl<'a> Ethernet<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        ParseError::check_length(Layer::Ethernet, ETHERNET_HEADER_LEN, bytes.len())?;
+        Ok(Self { data: bytes })
+    }
+
+    pub fn destination(&self) -> MacAddr {
+        MacAddr(self.data[0..6].try_into().unwrap())
+    }
+
+    pub fn source(&self) -> MacAddr {
+        MacAddr(self.data[6..12].try_into().unwrap())
+    }
+
+    pub fn ether_type(&self) -> EtherType {
+        utils::read_u16(&self.data[12..14]).into()
+    }
+
+    /// Everything after the header, padding included.
+    pub fn payload(&self) -> &'a [u8] {
+        &self.data[ETHERNET_HEADER_LEN..]
+    }
+}
+
+pub struct EthernetWriter {
+    buf: NetworkBuffer,
+}
+
+impl EthernetWriter {
+    pub fn new(destination: MacAddr, source: MacAddr, ether_type: EtherType) -> Self {
+        let mut buf = NetworkBuffer::new(MIN_FRAME_LEN);
+        buf.extend_from_slice(&destination.0);
+        buf.extend_from_slice(&source.0);
+        buf.extend_from_slice(&u16::from(ether_type).to_be_bytes());
+
+        Self { buf }
+    }
+
+    pub fn data(mut self, data: &[u8]) -> Self {
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    pub fn into_buf(mut self) -> NetworkBuffer {
+        if self.buf.len() < MIN_FRAME_LEN {
+            self.buf.resize(MIN_FRAME_LEN, 0);
+        }
+        self.buf
+    }
+}
+
+impl<'a> Display for Ethernet<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Ethernet")?;
+        writeln!(f, "- Src: {}", self.source())?;
+        writeln!(f, "- Dest: {}", self.destination())?;
+        writeln!(f, "- Type: {:?}", self.ether_type())
+    }
+}
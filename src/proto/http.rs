@@ -1,6 +1,9 @@
 use std::fmt::Display;
 
-use super::{NetworkBuffer, ProtocolBuffer};
+use super::{
+    NetworkBuffer, ProtocolBuffer,
+    error::{Layer, ParseError},
+};
 
 #[derive(Default, Clone, Copy)]
 struct Ref {
@@ -11,7 +14,7 @@ struct Ref {
 pub struct HttpReq<P: ProtocolBuffer> {
     inner: P,
     data: Ref,
-    method: Ref,
+    method: http::Method,
     path: Ref,
     version: Ref,
     headers: Vec<Ref>,
@@ -33,60 +36,27 @@ pub struct PackedHttpResp<P: ProtocolBuffer> {
 const SEPARATOR: [u8; 2] = [b'\r', b'\n'];
 
 impl<P: ProtocolBuffer> HttpReq<P> {
-    pub fn into_inner(self) -> P {
-        self.inner
-    }
-
-    pub fn parse(p: P) -> Self {
-        let buffer = p.buf();
-        let mut lines = memchr::memmem::find_iter(buffer, &SEPARATOR);
-        let first_line_end = lines.next().unwrap();
-
-        let first_line = &buffer[..first_line_end];
-        let mut split = memchr::memchr_iter(b' ', first_line);
-        let (method, index) = next(0, &mut split, first_line_end);
-        let (path, index) = next(index, &mut split, first_line_end);
-        let (version, _index) = next(index, &mut split, first_line_end);
-
-        let mut line_index = first_line_end + SEPARATOR.len();
-
-        let mut headers = vec![];
-        for line in lines {
-            let line_buf = &buffer[line_index..line];
-            if line_buf.is_empty() {
-                break;
-            }
-
-            let length = line - line_index;
-            let r = Ref {
-                start: line_index as u32,
-                length: length as u32,
-            };
-            headers.push(r);
-            line_index = line + SEPARATOR.len();
-            // let sep = memchr::memchr(b':', line_buf).unwrap();
-        }
-
-        let length = buffer.len() - line_index;
-        let data = Ref {
-            start: line_index as u32,
-            length: length as u32,
-        };
+    pub fn parse(p: P) -> Result<Self, ParseError> {
+        let Head {
+            first: [method, path, version],
+            headers,
+            data,
+        } = Head::parse(p.buf())?;
+        let method = http::Method::from_bytes(slice(p.buf(), method))
+            .map_err(|_| ParseError::Malformed { layer: Layer::Http })?;
 
-        Self {
+        Ok(Self {
             inner: p,
             data,
             method,
             path,
             version,
             headers,
-        }
+        })
     }
 
     pub fn method(&self) -> http::Method {
-        let start = self.method.start as usize;
-        let end = start + self.method.length as usize;
-        http::Method::from_bytes(&self.inner.buf()[start..end]).unwrap()
+        self.method.clone()
     }
 
     pub fn path(&self) -> &str {
@@ -106,44 +76,97 @@ impl<P: ProtocolBuffer> HttpReq<P> {
     }
 
     fn read(&self, data_ref: Ref) -> &str {
-        let start = data_ref.start as usize;
-        let end = start + data_ref.length as usize;
-        unsafe { std::str::from_utf8_unchecked(&self.inner.buf()[start..end]) }
+        // SAFETY: `parse` checked the whole buffer is UTF-8, and parts
+        // only ever start and end at ASCII.
+        unsafe { std::str::from_utf8_unchecked(slice(self.inner.buf(), data_ref)) }
     }
 }
 
 impl HttpResp {
     pub fn ok() -> Self {
-        const RESPONSE: &[u8; 38] = b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
-
-        let mut buf = NetworkBuffer::new(38);
-        buf.extend_from_slice(RESPONSE);
+        Self::from_static(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n")
+    }
 
-        let inner = PackedHttpResp::parse(buf);
+    pub fn bad_request() -> Self {
+        Self::from_static(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+    }
 
+    fn from_static(response: &'static [u8]) -> Self {
+        let inner = PackedHttpResp::parse(NetworkBuffer::from(response)).unwrap();
         Self { inner }
     }
 
-    pub fn to_buf(self) -> NetworkBuffer {
+    pub fn into_buf(self) -> NetworkBuffer {
         self.inner.inner
     }
 }
 
 impl<P: ProtocolBuffer> PackedHttpResp<P> {
-    pub fn into_inner(self) -> P {
-        self.inner
+    pub fn parse(p: P) -> Result<Self, ParseError> {
+        let Head {
+            first: [version, code, code_status],
+            headers,
+            data,
+        } = Head::parse(p.buf())?;
+
+        Ok(Self {
+            inner: p,
+            data,
+            version,
+            code,
+            code_status,
+            headers,
+        })
+    }
+
+    pub fn code(&self) -> &str {
+        self.read(self.code)
+    }
+
+    pub fn code_status(&self) -> &str {
+        self.read(self.code_status)
+    }
+
+    pub fn version(&self) -> &str {
+        self.read(self.version)
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = &str> {
+        self.headers.iter().map(|&h| self.read(h))
+    }
+
+    pub fn data(&self) -> &str {
+        self.read(self.data)
     }
 
-    pub fn parse(p: P) -> Self {
-        let buffer = p.buf();
+    fn read(&self, data_ref: Ref) -> &str {
+        // SAFETY: as for requests.
+        unsafe { std::str::from_utf8_unchecked(slice(self.inner.buf(), data_ref)) }
+    }
+}
+
+/// What requests and responses look alike in: a first line of three parts,
+/// header lines up to an empty one, and the data after that.
+struct Head {
+    first: [Ref; 3],
+    headers: Vec<Ref>,
+    data: Ref,
+}
+
+impl Head {
+    fn parse(buffer: &[u8]) -> Result<Self, ParseError> {
+        let malformed = ParseError::Malformed { layer: Layer::Http };
+        // Every part is read as a `str` later on.
+        std::str::from_utf8(buffer).map_err(|_| malformed)?;
+
         let mut lines = memchr::memmem::find_iter(buffer, &SEPARATOR);
-        let first_line_end = lines.next().unwrap();
+        let first_line_end = lines.next().ok_or(malformed)?;
 
         let first_line = &buffer[..first_line_end];
         let mut split = memchr::memchr_iter(b' ', first_line);
-        let (version, index) = next(0, &mut split, first_line_end);
-        let (code, index) = next(index, &mut split, first_line_end);
-        let (code_status, _index) = next(index, &mut split, first_line_end);
+        let (first, index) = next(0, &mut split, first_line_end);
+        let (second, index) = next(index, &mut split, first_line_end);
+        let (third, _index) = next(index, &mut split, first_line_end);
 
         let mut line_index = first_line_end + SEPARATOR.len();
 
@@ -151,6 +174,7 @@ impl<P: ProtocolBuffer> PackedHttpResp<P> {
         for line in lines {
             let line_buf = &buffer[line_index..line];
             if line_buf.is_empty() {
+                line_index += SEPARATOR.len();
                 break;
             }
 
@@ -161,7 +185,6 @@ impl<P: ProtocolBuffer> PackedHttpResp<P> {
             };
             headers.push(r);
             line_index = line + SEPARATOR.len();
-            // let sep = memchr::memchr(b':', line_buf).unwrap();
         }
 
         let length = buffer.len() - line_index;
@@ -170,49 +193,19 @@ impl<P: ProtocolBuffer> PackedHttpResp<P> {
             length: length as u32,
         };
 
-        Self {
-            inner: p,
-            data,
-            version,
-            code,
-            code_status,
+        Ok(Self {
+            first: [first, second, third],
             headers,
-        }
-    }
-
-    pub fn code(&self) -> &str {
-        self.read(self.code)
-    }
-
-    pub fn code_status(&self) -> &str {
-        self.read(self.code_status)
-    }
-
-    pub fn version(&self) -> &str {
-        self.read(self.version)
-    }
-
-    pub fn headers(&self) -> impl Iterator<Item = &str> {
-        self.headers.iter().map(|&h| self.read(h))
-    }
-
-    pub fn data(&self) -> &str {
-        self.read(self.data)
-    }
-
-    fn read(&self, data_ref: Ref) -> &str {
-        let start = data_ref.start as usize;
-        let end = start + data_ref.length as usize;
-
-        unsafe { std::str::from_utf8_unchecked(&self.inner.buf()[start..end]) }
+            data,
+        })
     }
 }
 
+/// The part of the first line from `index` to the next space, empty once
+/// the line has run out of them.
 fn next(index: u32, reader: &mut memchr::Memchr<'_>, line_end: usize) -> (Ref, u32) {
-    let next = match reader.next() {
-        Some(next) => next,
-        None => line_end,
-    };
+    let index = index.min(line_end as u32);
+    let next = reader.next().unwrap_or(line_end);
 
     let r = Ref {
         start: index,
@@ -222,6 +215,11 @@ fn next(index: u32, reader: &mut memchr::Memchr<'_>, line_end: usize) -> (Ref, u
     (r, index)
 }
 
+fn slice(buffer: &[u8], data_ref: Ref) -> &[u8] {
+    let start = data_ref.start as usize;
+    &buffer[start..start + data_ref.length as usize]
+}
+
 impl<P: ProtocolBuffer> Display for HttpReq<P> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "HttpReq")?;
@@ -249,3 +247,42 @@ impl<P: ProtocolBuffer> Display for PackedHttpResp<P> {
         writeln!(f, "Data: {}", self.data())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::application::Api;
+
+    use super::*;
+
+    fn parse(request: &[u8]) -> Result<HttpReq<NetworkBuffer>, ParseError> {
+        HttpReq::parse(NetworkBuffer::from(request))
+    }
+
+    #[test]
+    fn malformed_requests_are_errors() {
+        let request = parse(b"POST /req HTTP/1.1\r\nHost: a\r\n\r\n{}").unwrap();
+        assert_eq!(request.method(), http::Method::POST);
+        assert_eq!(request.path(), "/req");
+        assert_eq!(request.headers().collect::<Vec<_>>(), ["Host: a"]);
+        assert_eq!(request.data(), "{}");
+
+        let malformed = ParseError::Malformed { layer: Layer::Http };
+        for request in [
+            &b"GET / HTTP/1.1"[..],
+            b"G\xffT / HTTP/1.1\r\n\r\n",
+            b"GET / HTTP/1.1\r\nHost: \xff\r\n\r\n",
+            b"G(T / HTTP/1.1\r\n\r\n",
+            b"\r\n",
+        ] {
+            assert_eq!(parse(request).err(), Some(malformed));
+        }
+
+        // Too few parts leaves the rest empty rather than out of bounds.
+        let request = parse(b"GET\r\n\r\n").unwrap();
+        assert_eq!((request.path(), request.version()), ("", ""));
+
+        let request = parse(b"POST /req HTTP/1.1\r\n\r\nnot json").unwrap();
+        let response = PackedHttpResp::parse(Api.on_request(&request).into_buf()).unwrap();
+        assert_eq!(response.code(), "400");
+    }
+}
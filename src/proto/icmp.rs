@@ -1,7 +1,45 @@
-use std::fmt::Display;
+use std::{fmt::Display, net::Ipv4Addr};
 
-use super::ProtocolBuffer;
-use anyhow::Result;
+use crate::utils;
+
+use super::{
+    NetworkBuffer, Protocol, ProtocolBuffer,
+    error::{Layer, ParseError},
+    ip::Ip,
+};
+
+/// Type, code, checksum and the four bytes every message has after them.
+const ICMP_HEADER_LEN: usize = 8;
+/// Originate, receive and transmit times of a timestamp message.
+const TIMESTAMPS_LEN: usize = 12;
+/// How much of the data after the IP header errors quote, at least.
+const QUOTED_DATA_LEN: usize = 8;
+
+pub const ECHO_REPLY: u8 = 0;
+pub const DESTINATION_UNREACHABLE: u8 = 3;
+pub const REDIRECT: u8 = 5;
+pub const ECHO_REQUEST: u8 = 8;
+pub const TIME_EXCEEDED: u8 = 11;
+pub const PARAMETER_PROBLEM: u8 = 12;
+pub const TIMESTAMP_REQUEST: u8 = 13;
+pub const TIMESTAMP_REPLY: u8 = 14;
+
+pub const NETWORK_UNREACHABLE: u8 = 0;
+pub const HOST_UNREACHABLE: u8 = 1;
+/// Destination Unreachable code for a transport protocol we don't speak.
+pub const PROTOCOL_UNREACHABLE: u8 = 2;
+/// Destination Unreachable code for a port nobody is bound to.
+pub const PORT_UNREACHABLE: u8 = 3;
+/// Destination Unreachable code for a packet too big for the next hop
+/// that mustn't be fragmented.
+pub const FRAGMENTATION_NEEDED: u8 = 4;
+pub const SOURCE_ROUTE_FAILED: u8 = 5;
+/// Destination Unreachable code for a packet a filter turned away (RFC 1812).
+pub const ADMINISTRATIVELY_PROHIBITED: u8 = 13;
+/// Time Exceeded code for a TTL that ran out on the way.
+pub const TTL_EXCEEDED: u8 = 0;
+/// Time Exceeded code for fragments that never all arrived.
+pub const REASSEMBLY_TIME_EXCEEDED: u8 = 1;
 
 pub struct Icmp<P: ProtocolBuffer> {
     inner: P,
@@ -14,10 +52,16 @@ impl<P: ProtocolBuffer> ProtocolBuffer for Icmp<P> {
 }
 
 impl<P: ProtocolBuffer> Icmp<P> {
-    pub fn parse(proto: P) -> Result<Self> {
+    pub fn parse(proto: P) -> Result<Self, ParseError> {
+        ParseError::check_length(Layer::Icmp, ICMP_HEADER_LEN, proto.buf().len())?;
         Ok(Self { inner: proto })
     }
 
+    /// Whether the checksum over the whole message adds up.
+    pub fn checksum_valid(&self) -> bool {
+        utils::checksum_valid(utils::add_slice(0, self.inner.buf()))
+    }
+
     pub fn icmp_type(&self) -> u8 {
         self.inner.buf()[0]
     }
@@ -25,6 +69,450 @@ impl<P: ProtocolBuffer> Icmp<P> {
     pub fn icmp_code(&self) -> u8 {
         self.inner.buf()[1]
     }
+
+    /// Whatever follows the header.
+    #[cfg(test)]
+    pub fn payload(&self) -> &[u8] {
+        &self.inner.buf()[ICMP_HEADER_LEN..]
+    }
+
+    pub fn message(&self) -> Result<IcmpMessage<'_>, ParseError> {
+        IcmpMessage::parse(self.inner.buf())
+    }
+}
+
+/// One ICMP message, with whatever its type carries parsed out.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IcmpMessage<'a> {
+    EchoReply(Echo<'a>),
+    EchoRequest(Echo<'a>),
+    DestinationUnreachable {
+        reason: Unreachable,
+        quote: Quote<'a>,
+    },
+    Redirect {
+        reason: Redirect,
+        /// Where to send packets for the quoted destination instead.
+        gateway: Ipv4Addr,
+        quote: Quote<'a>,
+    },
+    TimeExceeded {
+        reason: TimeExceeded,
+        quote: Quote<'a>,
+    },
+    ParameterProblem {
+        /// 0 if `pointer` points at the problem, RFC 1108 and 1812 add
+        /// missing options and bad lengths.
+        code: u8,
+        /// The offset of the offending byte in the quoted header.
+        pointer: u8,
+        quote: Quote<'a>,
+    },
+    TimestampRequest(Timestamp),
+    TimestampReply(Timestamp),
+    Unknown {
+        icmp_type: u8,
+        code: u8,
+        rest: [u8; 4],
+        data: &'a [u8],
+    },
+}
+
+/// The body of an Echo Request, which the reply copies whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Echo<'a> {
+    pub identifier: u16,
+    pub sequence: u16,
+    pub data: &'a [u8],
+}
+
+/// The body of a Timestamp Request or Reply, in milliseconds since midnight UT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timestamp {
+    pub identifier: u16,
+    pub sequence: u16,
+    pub originate: u32,
+    pub receive: u32,
+    pub transmit: u32,
+}
+
+/// What a Destination Unreachable message says couldn't be reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Unreachable {
+    Network,
+    Host,
+    Protocol,
+    Port,
+    /// Routers that follow RFC 1191 say what the next hop takes, older
+    /// ones leave it 0.
+    FragmentationNeeded {
+        next_hop_mtu: u16,
+    },
+    SourceRouteFailed,
+    AdministrativelyProhibited,
+    Other(u8),
+}
+
+impl Unreachable {
+    fn parse(code: u8, rest: &[u8]) -> Self {
+        match code {
+            NETWORK_UNREACHABLE => Self::Network,
+            HOST_UNREACHABLE => Self::Host,
+            PROTOCOL_UNREACHABLE => Self::Protocol,
+            PORT_UNREACHABLE => Self::Port,
+            FRAGMENTATION_NEEDED => Self::FragmentationNeeded {
+                next_hop_mtu: utils::read_u16(&rest[2..]),
+            },
+            SOURCE_ROUTE_FAILED => Self::SourceRouteFailed,
+            ADMINISTRATIVELY_PROHIBITED => Self::AdministrativelyProhibited,
+            other => Self::Other(other),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match *self {
+            Self::Network => NETWORK_UNREACHABLE,
+            Self::Host => HOST_UNREACHABLE,
+            Self::Protocol => PROTOCOL_UNREACHABLE,
+            Self::Port => PORT_UNREACHABLE,
+            Self::FragmentationNeeded { .. } => FRAGMENTATION_NEEDED,
+            Self::SourceRouteFailed => SOURCE_ROUTE_FAILED,
+            Self::AdministrativelyProhibited => ADMINISTRATIVELY_PROHIBITED,
+            Self::Other(code) => code,
+        }
+    }
+}
+
+/// Which packets a Redirect message is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Redirect {
+    Network,
+    Host,
+    TosNetwork,
+    TosHost,
+    Other(u8),
+}
+
+impl From<u8> for Redirect {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::Network,
+            1 => Self::Host,
+            2 => Self::TosNetwork,
+            3 => Self::TosHost,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl From<Redirect> for u8 {
+    fn from(value: Redirect) -> Self {
+        match value {
+            Redirect::Network => 0,
+            Redirect::Host => 1,
+            Redirect::TosNetwork => 2,
+            Redirect::TosHost => 3,
+            Redirect::Other(val) => val,
+        }
+    }
+}
+
+/// What ran out of time, according to a Time Exceeded message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeExceeded {
+    /// The TTL, on the way.
+    InTransit,
+    /// Waiting for the rest of the fragments.
+    Reassembly,
+    Other(u8),
+}
+
+impl From<u8> for TimeExceeded {
+    fn from(value: u8) -> Self {
+        match value {
+            TTL_EXCEEDED => Self::InTransit,
+            REASSEMBLY_TIME_EXCEEDED => Self::Reassembly,
+            other => Self::Other(other),
+        }
+    }
+}
+
+impl From<TimeExceeded> for u8 {
+    fn from(value: TimeExceeded) -> Self {
+        match value {
+            TimeExceeded::InTransit => TTL_EXCEEDED,
+            TimeExceeded::Reassembly => REASSEMBLY_TIME_EXCEEDED,
+            TimeExceeded::Other(val) => val,
+        }
+    }
+}
+
+/// What an error message quotes of the packet it's about: the IP header and
+/// the first 8 bytes after it, enough for the sender to find the ports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quote<'a>(&'a [u8]);
+
+impl<'a> Quote<'a> {
+    /// Quotes as much of `ip` as RFC 792 asks for.
+    pub fn of(ip: &Ip<'a>) -> Self {
+        let end = ip.header_length() + ip.remainder().len().min(QUOTED_DATA_LEN);
+        Self(&ip.packet()[..end])
+    }
+
+    pub fn bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    /// The quoted IP header, its `remainder` being whatever of the data
+    /// was quoted.
+    pub fn ip(&self) -> Result<Ip<'a>, ParseError> {
+        Ip::parse_quoted(self.0)
+    }
+
+    /// What the quoted data says about the transport the packet was for.
+    pub fn transport(&self) -> Result<QuotedTransport, ParseError> {
+        let ip = self.ip()?;
+        let data = ip.remainder();
+        ParseError::check_length(Layer::Icmp, QUOTED_DATA_LEN, data.len())?;
+
+        let (source_port, destination_port) = (utils::read_u16(data), utils::read_u16(&data[2..]));
+        Ok(match ip.protocol() {
+            Protocol::TCP => QuotedTransport::Tcp {
+                source_port,
+                destination_port,
+                sequence: utils::read_u32(&data[4..]),
+            },
+            Protocol::UDP => QuotedTransport::Udp {
+                source_port,
+                destination_port,
+            },
+            Protocol::ICMP => QuotedTransport::Icmp {
+                icmp_type: data[0],
+                code: data[1],
+                identifier: utils::read_u16(&data[4..]),
+                sequence: utils::read_u16(&data[6..]),
+            },
+            other => QuotedTransport::Other(other),
+        })
+    }
+}
+
+/// The start of the transport header in a quote, as far as 8 bytes go.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotedTransport {
+    Tcp {
+        source_port: u16,
+        destination_port: u16,
+        sequence: u32,
+    },
+    Udp {
+        source_port: u16,
+        destination_port: u16,
+    },
+    Icmp {
+        icmp_type: u8,
+        code: u8,
+        identifier: u16,
+        sequence: u16,
+    },
+    Other(Protocol),
+}
+
+impl<'a> IcmpMessage<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        ParseError::check_length(Layer::Icmp, ICMP_HEADER_LEN, bytes.len())?;
+        let (icmp_type, code) = (bytes[0], bytes[1]);
+        let rest = &bytes[4..ICMP_HEADER_LEN];
+        let data = &bytes[ICMP_HEADER_LEN..];
+        let (identifier, sequence) = (utils::read_u16(rest), utils::read_u16(&rest[2..]));
+        let echo = Echo {
+            identifier,
+            sequence,
+            data,
+        };
+        let timestamp = || {
+            ParseError::check_length(Layer::Icmp, TIMESTAMPS_LEN, data.len())?;
+            Ok(Timestamp {
+                identifier,
+                sequence,
+                originate: utils::read_u32(data),
+                receive: utils::read_u32(&data[4..]),
+                transmit: utils::read_u32(&data[8..]),
+            })
+        };
+        let quote = Quote(data);
+
+        let message = match icmp_type {
+            // Echo and timestamp messages only have code 0, anything else
+            // is left as it came for nobody to answer.
+            ECHO_REPLY | ECHO_REQUEST | TIMESTAMP_REQUEST | TIMESTAMP_REPLY if code != 0 => {
+                Self::Unknown {
+                    icmp_type,
+                    code,
+                    rest: rest.try_into().unwrap(),
+                    data,
+                }
+            }
+            ECHO_REPLY => Self::EchoReply(echo),
+            ECHO_REQUEST => Self::EchoRequest(echo),
+            DESTINATION_UNREACHABLE => Self::DestinationUnreachable {
+                reason: Unreachable::parse(code, rest),
+                quote,
+            },
+            REDIRECT => Self::Redirect {
+                reason: code.into(),
+                gateway: utils::read_u32(rest).into(),
+                quote,
+            },
+            TIME_EXCEEDED => Self::TimeExceeded {
+                reason: code.into(),
+                quote,
+            },
+            PARAMETER_PROBLEM => Self::ParameterProblem {
+                code,
+                pointer: rest[0],
+                quote,
+            },
+            TIMESTAMP_REQUEST => Self::TimestampRequest(timestamp()?),
+            TIMESTAMP_REPLY => Self::TimestampReply(timestamp()?),
+            _ => Self::Unknown {
+                icmp_type,
+                code,
+                rest: rest.try_into().unwrap(),
+                data,
+            },
+        };
+        Ok(message)
+    }
+
+    pub fn icmp_type(&self) -> u8 {
+        match self {
+            Self::EchoReply(_) => ECHO_REPLY,
+            Self::EchoRequest(_) => ECHO_REQUEST,
+            Self::DestinationUnreachable { .. } => DESTINATION_UNREACHABLE,
+            Self::Redirect { .. } => REDIRECT,
+            Self::TimeExceeded { .. } => TIME_EXCEEDED,
+            Self::ParameterProblem { .. } => PARAMETER_PROBLEM,
+            Self::TimestampRequest(_) => TIMESTAMP_REQUEST,
+            Self::TimestampReply(_) => TIMESTAMP_REPLY,
+            Self::Unknown { icmp_type, .. } => *icmp_type,
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match *self {
+            Self::DestinationUnreachable { reason, .. } => reason.code(),
+            Self::Redirect { reason, .. } => reason.into(),
+            Self::TimeExceeded { reason, .. } => reason.into(),
+            Self::ParameterProblem { code, .. } | Self::Unknown { code, .. } => code,
+            _ => 0,
+        }
+    }
+
+    /// The packet an error message is about.
+    pub fn quote(&self) -> Option<Quote<'a>> {
+        match *self {
+            Self::DestinationUnreachable { quote, .. }
+            | Self::Redirect { quote, .. }
+            | Self::TimeExceeded { quote, .. }
+            | Self::ParameterProblem { quote, .. } => Some(quote),
+            _ => None,
+        }
+    }
+
+    /// The message as it goes on the wire, checksum and all.
+    pub fn into_buf(self) -> NetworkBuffer {
+        let mut times = [0; TIMESTAMPS_LEN];
+        let (rest, data): ([u8; 4], &[u8]) = match self {
+            Self::EchoReply(echo) | Self::EchoRequest(echo) => {
+                (pair(echo.identifier, echo.sequence), echo.data)
+            }
+            Self::DestinationUnreachable { reason, quote } => {
+                let next_hop_mtu = match reason {
+                    Unreachable::FragmentationNeeded { next_hop_mtu } => next_hop_mtu,
+                    _ => 0,
+                };
+                (pair(0, next_hop_mtu), quote.0)
+            }
+            Self::Redirect { gateway, quote, .. } => (gateway.octets(), quote.0),
+            Self::TimeExceeded { quote, .. } => ([0; 4], quote.0),
+            Self::ParameterProblem { pointer, quote, .. } => ([pointer, 0, 0, 0], quote.0),
+            Self::TimestampRequest(timestamp) | Self::TimestampReply(timestamp) => {
+                times[..4].copy_from_slice(&timestamp.originate.to_be_bytes());
+                times[4..8].copy_from_slice(&timestamp.receive.to_be_bytes());
+                times[8..].copy_from_slice(&timestamp.transmit.to_be_bytes());
+                (pair(timestamp.identifier, timestamp.sequence), &times)
+            }
+            Self::Unknown { rest, data, .. } => (rest, data),
+        };
+
+        IcmpWriter::new(self.icmp_type(), self.code())
+            .rest(rest)
+            .data(data)
+            .into_buf()
+    }
+}
+
+fn pair(first: u16, second: u16) -> [u8; 4] {
+    let [a, b] = first.to_be_bytes();
+    let [c, d] = second.to_be_bytes();
+    [a, b, c, d]
+}
+
+pub struct IcmpWriter {
+    buf: NetworkBuffer,
+}
+
+impl IcmpWriter {
+    pub fn new(icmp_type: u8, code: u8) -> Self {
+        let mut buf = NetworkBuffer::new_zeroed(ICMP_HEADER_LEN);
+        buf[0] = icmp_type;
+        buf[1] = code;
+        Self { buf }
+    }
+
+    /// The four bytes after the checksum, whatever the type uses them for.
+    pub fn rest(mut self, rest: [u8; 4]) -> Self {
+        self.buf[4..8].copy_from_slice(&rest);
+        self
+    }
+
+    pub fn data(mut self, data: &[u8]) -> Self {
+        self.buf.extend_from_slice(data);
+        self
+    }
+
+    /// Checksums the message, which covers nothing but the message itself.
+    pub fn into_buf(mut self) -> NetworkBuffer {
+        let checksum = utils::ones_complement(utils::add_slice(0, &self.buf)).to_be();
+        self.buf[2..4].copy_from_slice(&checksum.to_be_bytes());
+        self.buf
+    }
+}
+
+/// Whether RFC 1812 lets us send an error about `ip`: never about other
+/// errors, fragments but the first, packets to many destinations, or from
+/// an address that isn't one host's (4.3.2.7).
+pub fn may_report(ip: &Ip) -> bool {
+    let destination = ip.destination();
+    if ip.fragment_offset() != 0 || destination.is_broadcast() || destination.is_multicast() {
+        return false;
+    }
+    let source = ip.source();
+    // 0/8 is this network, 240/4 class E, the limited broadcast among it.
+    let first = source.octets()[0];
+    if first == 0 || first >= 240 || source.is_multicast() || source.is_loopback() {
+        return false;
+    }
+    if ip.protocol() == Protocol::ICMP {
+        return Icmp::parse(*ip).is_ok_and(|message| {
+            matches!(
+                message.icmp_type(),
+                ECHO_REPLY | ECHO_REQUEST | TIMESTAMP_REQUEST | TIMESTAMP_REPLY
+            )
+        });
+    }
+    true
 }
 
 impl<P: ProtocolBuffer> Display for Icmp<P> {
@@ -32,8 +520,186 @@ impl<P: ProtocolBuffer> Display for Icmp<P> {
         writeln!(f, "ICMP")?;
         writeln!(f, "- Type: {}", self.icmp_type())?;
         writeln!(f, "- Code: {}", self.icmp_code())?;
-        writeln!(f, "- Remainder: {:?}", &self.inner.buf()[2..])?;
+        match self.message() {
+            Ok(message) => writeln!(f, "- Message: {}", message)?,
+            Err(e) => writeln!(f, "- Message: {}", e)?,
+        }
 
         writeln!(f, "{}", self.inner)
     }
 }
+
+impl Display for IcmpMessage<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EchoReply(echo) | Self::EchoRequest(echo) => write!(
+                f,
+                "{} id {} seq {}, {} bytes",
+                if self.icmp_type() == ECHO_REPLY {
+                    "Echo Reply"
+                } else {
+                    "Echo Request"
+                },
+                echo.identifier,
+                echo.sequence,
+                echo.data.len()
+            ),
+            Self::DestinationUnreachable { reason, quote } => {
+                write!(f, "Destination Unreachable ({:?}) about {}", reason, quote)
+            }
+            Self::Redirect {
+                reason,
+                gateway,
+                quote,
+            } => write!(f, "Redirect ({:?}) to {} about {}", reason, gateway, quote),
+            Self::TimeExceeded { reason, quote } => {
+                write!(f, "Time Exceeded ({:?}) about {}", reason, quote)
+            }
+            Self::ParameterProblem { pointer, quote, .. } => {
+                write!(f, "Parameter Problem at {} about {}", pointer, quote)
+            }
+            Self::TimestampRequest(timestamp) => write!(f, "Timestamp Request {:?}", timestamp),
+            Self::TimestampReply(timestamp) => write!(f, "Timestamp Reply {:?}", timestamp),
+            Self::Unknown {
+                icmp_type, code, ..
+            } => write!(f, "Type {} code {}", icmp_type, code),
+        }
+    }
+}
+
+impl Display for Quote<'_> {
+    /// `UDP 10.0.0.2:50000 -> 10.0.0.1:53`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let Ok(ip) = self.ip() else {
+            return write!(f, "{} bytes", self.0.len());
+        };
+        let (source, destination) = (ip.source(), ip.destination());
+        match self.transport() {
+            Ok(
+                QuotedTransport::Tcp {
+                    source_port,
+                    destination_port,
+                    ..
+                }
+                | QuotedTransport::Udp {
+                    source_port,
+                    destination_port,
+                },
+            ) => write!(
+                f,
+                "{:?} {}:{} -> {}:{}",
+                ip.protocol(),
+                source,
+                source_port,
+                destination,
+                destination_port
+            ),
+            _ => write!(f, "{:?} {} -> {}", ip.protocol(), source, destination),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proto::{ip::IpHeaderWriter, udp::UdpHeaderWriter};
+
+    #[test]
+    fn messages_round_trip_and_quote_their_packet() {
+        let (client, server) = (Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(10, 0, 0, 1));
+        let udp = UdpHeaderWriter::new(50000, 53)
+            .data(b"a query longer than the quote".as_slice().into())
+            .calc_checksum_for(client.into(), server.into())
+            .into_buf();
+        let packet = IpHeaderWriter::new(client, server, Protocol::UDP, 64, udp).into_buf();
+        let quote = Quote::of(&Ip::parse(&packet).unwrap());
+
+        let messages = [
+            IcmpMessage::EchoRequest(Echo {
+                identifier: 7,
+                sequence: 1,
+                data: b"ping",
+            }),
+            IcmpMessage::DestinationUnreachable {
+                reason: Unreachable::FragmentationNeeded { next_hop_mtu: 1280 },
+                quote,
+            },
+            IcmpMessage::Redirect {
+                reason: Redirect::Host,
+                gateway: Ipv4Addr::new(10, 0, 0, 254),
+                quote,
+            },
+            IcmpMessage::TimeExceeded {
+                reason: TimeExceeded::InTransit,
+                quote,
+            },
+            IcmpMessage::ParameterProblem {
+                code: 0,
+                pointer: 9,
+                quote,
+            },
+            IcmpMessage::ParameterProblem {
+                code: 2,
+                pointer: 0,
+                quote,
+            },
+            IcmpMessage::TimestampReply(Timestamp {
+                identifier: 7,
+                sequence: 2,
+                originate: 1,
+                receive: 2,
+                transmit: 3,
+            }),
+        ];
+        for message in messages {
+            let icmp = Icmp::parse(message.into_buf()).unwrap();
+            assert!(icmp.checksum_valid());
+            assert_eq!(icmp.message().unwrap(), message);
+        }
+
+        // Not a ping anyone should answer, but it comes out as it went in.
+        let odd_echo = IcmpWriter::new(ECHO_REQUEST, 1)
+            .rest([0, 7, 0, 1])
+            .into_buf();
+        let message = IcmpMessage::parse(&odd_echo).unwrap();
+        assert!(matches!(message, IcmpMessage::Unknown { code: 1, .. }));
+        assert_eq!(&message.into_buf()[..], &odd_echo[..]);
+
+        let ip = quote.ip().unwrap();
+        assert_eq!((ip.source(), ip.destination()), (client, server));
+        assert_eq!(ip.remainder().len(), 8);
+        assert_eq!(
+            quote.transport().unwrap(),
+            QuotedTransport::Udp {
+                source_port: 50000,
+                destination_port: 53
+            }
+        );
+        assert_eq!(quote.to_string(), "UDP 10.0.0.2:50000 -> 10.0.0.1:53");
+    }
+
+    #[test]
+    fn errors_go_only_to_single_hosts() {
+        let server = Ipv4Addr::new(10, 0, 0, 1);
+        let about = |source: Ipv4Addr| {
+            let udp = UdpHeaderWriter::new(50000, 53)
+                .calc_checksum_for(source.into(), server.into())
+                .into_buf();
+            IpHeaderWriter::new(source, server, Protocol::UDP, 64, udp).into_buf()
+        };
+
+        assert!(may_report(
+            &Ip::parse(&about(Ipv4Addr::new(10, 0, 0, 2))).unwrap()
+        ));
+        for source in [
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::new(0, 1, 2, 3),
+            Ipv4Addr::BROADCAST,
+            Ipv4Addr::new(224, 0, 0, 1),
+            Ipv4Addr::LOCALHOST,
+            Ipv4Addr::new(240, 0, 0, 1),
+        ] {
+            assert!(!may_report(&Ip::parse(&about(source)).unwrap()), "{source}");
+        }
+    }
+}
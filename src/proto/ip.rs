@@ -1,48 +1,105 @@
-use std::fmt::Display;
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv4Addr},
+};
 
 use crate::utils;
 
-use super::{NetworkBuffer, Protocol, ProtocolBuffer};
+use super::{
+    NetworkBuffer, Protocol, ProtocolBuffer,
+    error::{Layer, ParseError},
+    ip_options::{self, IpOption},
+    ipv6::{Ipv6, Ipv6HeaderWriter},
+};
 
 use anyhow::Result;
 
 const IP_HEADER_LEN_MIN: usize = 20;
+const DONT_FRAGMENT: u8 = 0b0100_0000;
+const MORE_FRAGMENTS: u8 = 0b0010_0000;
 
 impl<'a> ProtocolBuffer for Ip<'a> {
     fn buf(&self) -> &[u8] {
-        &self.remainder()
+        self.remainder()
     }
 }
 
+#[derive(Clone, Copy)]
 pub struct Ip<'a> {
     data: &'a [u8],
 }
 
 impl<'a> Ip<'a> {
-    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
-        if bytes.len() < IP_HEADER_LEN_MIN {
-            anyhow::bail!("Expected at least 20 bytes")
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        Self::parse_header(bytes, false)
+    }
+
+    /// The header an ICMP error quotes, which is followed by only as much
+    /// of the data as the sender of the error kept. `remainder` is that much.
+    pub fn parse_quoted(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        Self::parse_header(bytes, true)
+    }
+
+    fn parse_header(bytes: &'a [u8], truncated: bool) -> Result<Self, ParseError> {
+        ParseError::check_length(Layer::Ip, IP_HEADER_LEN_MIN, bytes.len())?;
+
+        let version = bytes[0] >> 4;
+        if version != 4 {
+            return Err(ParseError::Version {
+                layer: Layer::Ip,
+                version,
+            });
         }
 
-        let s = Self { data: bytes };
+        let header_length = (bytes[0] & 0xf) as usize * 4;
+        if header_length < IP_HEADER_LEN_MIN || header_length > bytes.len() {
+            return Err(ParseError::HeaderLength {
+                layer: Layer::Ip,
+                length: header_length,
+                available: bytes.len(),
+            });
+        }
 
-        if s.header_length() > s.data.len() {
-            anyhow::bail!(
-                "Bytes recv less than header length: HeaderLen: {}, DataLen: {}",
-                s.header_length(),
-                s.data.len()
-            )
+        // Anything past the total length is link layer padding.
+        let total_length = utils::read_u16(&bytes[2..4]) as usize;
+        if total_length < header_length || (total_length > bytes.len() && !truncated) {
+            return Err(ParseError::Length {
+                layer: Layer::Ip,
+                length: total_length,
+                available: bytes.len(),
+            });
         }
 
-        Ok(s)
+        Ok(Self {
+            data: &bytes[..total_length.min(bytes.len())],
+        })
     }
 }
 
 impl<'a> Ip<'a> {
     pub fn header_length(&self) -> usize {
-        let len = unsafe { *self.data.get_unchecked(0) & 0xf };
         // Length is represented as 32 bit words, so 4 bytes
-        (len * 4) as usize
+        (self.data[0] & 0xf) as usize * 4
+    }
+
+    /// Whether the header checksum adds up.
+    pub fn checksum_valid(&self) -> bool {
+        utils::checksum_valid(utils::add_slice(0, self.header()))
+    }
+
+    /// The options between the fixed header and the end of the header.
+    pub fn options(&self) -> impl Iterator<Item = Result<IpOption, ParseError>> + 'a {
+        ip_options::parse_options(&self.data[IP_HEADER_LEN_MIN..self.header_length()])
+    }
+
+    /// The whole packet, header and all.
+    pub fn packet(&self) -> &'a [u8] {
+        self.data
+    }
+
+    /// The header bytes, options included.
+    pub fn header(&self) -> &'a [u8] {
+        &self.data[..self.header_length()]
     }
 
     pub fn remainder(&self) -> &'a [u8] {
@@ -53,32 +110,42 @@ impl<'a> Ip<'a> {
         utils::read_u16(&self.data[2..4])
     }
 
-    pub fn ttl(&self) -> u8 {
-        self.data[8]
+    pub fn identification(&self) -> u16 {
+        utils::read_u16(&self.data[4..6])
     }
 
-    pub fn protocol(&self) -> Protocol {
-        self.data[9].into()
+    pub fn dont_fragment(&self) -> bool {
+        self.data[6] & DONT_FRAGMENT != 0
     }
 
-    pub fn checksum(&self) -> u16 {
-        utils::read_u16(&self.data[10..12])
+    pub fn more_fragments(&self) -> bool {
+        self.data[6] & MORE_FRAGMENTS != 0
     }
 
-    pub fn source(&self) -> u32 {
-        utils::read_u32(&self.data[12..])
+    /// Where this fragment's data goes in the original datagram, in 8 byte units.
+    pub fn fragment_offset(&self) -> u16 {
+        utils::read_u16(&self.data[6..8]) & 0x1fff
     }
 
-    pub fn source2(&self) -> [u8; 4] {
-        self.data[12..16].try_into().unwrap()
+    /// Whether this is one piece of a fragmented datagram.
+    pub fn is_fragment(&self) -> bool {
+        self.more_fragments() || self.fragment_offset() != 0
     }
 
-    pub fn destination(&self) -> u32 {
-        utils::read_u32(&self.data[16..])
+    pub fn ttl(&self) -> u8 {
+        self.data[8]
     }
 
-    pub fn destination2(&self) -> [u8; 4] {
-        self.data[16..20].try_into().unwrap()
+    pub fn protocol(&self) -> Protocol {
+        self.data[9].into()
+    }
+
+    pub fn source(&self) -> Ipv4Addr {
+        utils::read_u32(&self.data[12..]).into()
+    }
+
+    pub fn destination(&self) -> Ipv4Addr {
+        utils::read_u32(&self.data[16..]).into()
     }
 }
 
@@ -89,8 +156,8 @@ pub struct IpHeaderWriter {
 impl IpHeaderWriter {
     const VERSION_AND_LEN: u8 = 0b01000101;
     pub fn new(
-        source: u32,
-        destination: u32,
+        source: Ipv4Addr,
+        destination: Ipv4Addr,
         protocol: Protocol,
         time_to_live: u8,
         data: NetworkBuffer,
@@ -100,31 +167,137 @@ impl IpHeaderWriter {
         // buf[1] = 0; // type of service
         buf[2..4].copy_from_slice(&((20 + data.len()) as u16).to_be_bytes());
         // identification buf[4..6]
-        // flags
-        buf[6] = 0b0100_0000; //dont fragment
-                              // fragment
+        // flags and fragment offset buf[6..8]
         buf[8] = time_to_live;
         buf[9] = protocol.into();
-        buf[12..16].copy_from_slice(&source.to_be_bytes());
-        buf[16..20].copy_from_slice(&destination.to_be_bytes());
-
-        let mut s = Self { buf };
-        let checksum = s.checksum();
-        // Not really sure what endianness we're here tbh.
-        s.buf[10..12].copy_from_slice(&checksum.to_ne_bytes());
+        buf[12..16].copy_from_slice(&source.octets());
+        buf[16..20].copy_from_slice(&destination.octets());
+        write_header_checksum(&mut buf[..20]);
 
         // Copy inner data
-        s.buf[20..].copy_from_slice(&data);
-        s
+        buf[20..].copy_from_slice(&data);
+        Self { buf }
+    }
+
+    /// Sets Don't Fragment, for packets that rather get dropped than split up.
+    pub fn dont_fragment(mut self) -> Self {
+        self.buf[6] |= DONT_FRAGMENT;
+        self.write_checksum();
+        self
+    }
+
+    /// Puts `options` after the fixed header, padded to a whole word.
+    #[cfg(test)]
+    pub fn options(mut self, options: &[IpOption]) -> Self {
+        let mut bytes = Vec::new();
+        for option in options {
+            option.write(&mut bytes);
+        }
+        bytes.resize(bytes.len().next_multiple_of(4), 0);
+        assert!(
+            bytes.len() <= ip_options::MAX_OPTIONS_LEN,
+            "{} bytes of options don't fit in the header",
+            bytes.len()
+        );
+
+        let end = self.header_length();
+        let header_length = end + bytes.len();
+        self.buf.splice(end..end, bytes);
+        self.buf[0] = 0x40 | (header_length / 4) as u8;
+        let total_length = self.buf.len() as u16;
+        self.buf[2..4].copy_from_slice(&total_length.to_be_bytes());
+        self.write_checksum();
+        self
+    }
+
+    fn header_length(&self) -> usize {
+        (self.buf[0] & 0xf) as usize * 4
+    }
+
+    fn write_checksum(&mut self) {
+        let header_length = self.header_length();
+        write_header_checksum(&mut self.buf[..header_length]);
     }
 
-    pub fn to_buf(self) -> NetworkBuffer {
+    pub fn into_buf(self) -> NetworkBuffer {
         self.buf
     }
+}
+
+/// Gives an IPv4 packet the identification its fragments will carry.
+pub fn set_identification(packet: &mut [u8], identification: u16) {
+    let header_length = (packet[0] & 0xf) as usize * 4;
+    packet[4..6].copy_from_slice(&identification.to_be_bytes());
+    write_header_checksum(&mut packet[..header_length]);
+}
 
-    pub fn checksum(&self) -> u16 {
-        // func returns big endiann, just switch back
-        utils::ones_complement(utils::add_slice(0, &self.buf[..20]))
+/// Takes one hop off the TTL of an IPv4 packet being forwarded, patching
+/// the checksum instead of computing it over again.
+pub fn decrement_ttl(packet: &mut [u8]) {
+    let old = utils::read_u16(&packet[8..10]);
+    packet[8] -= 1;
+    let new = utils::read_u16(&packet[8..10]);
+    let checksum = utils::update_checksum(utils::read_u16(&packet[10..12]), old, new);
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+}
+
+/// Fills in the checksum of an IPv4 header, options included.
+pub fn write_header_checksum(header: &mut [u8]) {
+    header[10..12].fill(0);
+    let checksum = utils::ones_complement(utils::add_slice(0, header));
+    // Not really sure what endianness we're here tbh.
+    header[10..12].copy_from_slice(&checksum.to_ne_bytes());
+}
+
+impl<'a> Ip<'a> {
+    /// Splits the packet into fragments of at most `mtu` bytes. Only the
+    /// first one carries every option, the others the ones marked to be
+    /// copied. Fails when Don't Fragment is set.
+    pub fn fragment(&self, mtu: usize) -> Result<Vec<NetworkBuffer>> {
+        if self.dont_fragment() {
+            anyhow::bail!("Packet of {} bytes can't be fragmented", self.data.len())
+        }
+
+        let first_header = self.header();
+        let mut other_header = first_header[..IP_HEADER_LEN_MIN].to_vec();
+        other_header.extend(ip_options::copied_options(
+            &first_header[IP_HEADER_LEN_MIN..],
+        ));
+        other_header.resize(other_header.len().next_multiple_of(4), 0);
+        other_header[0] = 0x40 | (other_header.len() / 4) as u8;
+
+        let data = self.remainder();
+        // A fragment that was fragmented further keeps its place in the original.
+        let base = self.fragment_offset() as usize * 8;
+
+        let mut fragments = Vec::new();
+        let mut offset = 0;
+        while offset < data.len() {
+            let header = match offset {
+                0 => first_header,
+                _ => &other_header,
+            };
+            let room = mtu.saturating_sub(header.len()) / 8 * 8;
+            if room == 0 {
+                anyhow::bail!("MTU of {} leaves no room for fragment data", mtu)
+            }
+            let end = (offset + room).min(data.len());
+            let more = end < data.len() || self.more_fragments();
+
+            let length = header.len() + end - offset;
+            let mut buf = NetworkBuffer::new(length);
+            buf.extend_from_slice(header);
+            buf.extend_from_slice(&data[offset..end]);
+            buf[2..4].copy_from_slice(&(length as u16).to_be_bytes());
+            let flags = ((base + offset) / 8) as u16 | if more { 0x2000 } else { 0 };
+            buf[6..8].copy_from_slice(&flags.to_be_bytes());
+            write_header_checksum(&mut buf[..header.len()]);
+
+            fragments.push(buf);
+            offset = end;
+        }
+
+        Ok(fragments)
     }
 }
 
@@ -133,9 +306,114 @@ impl<'a> Display for Ip<'a> {
         writeln!(f, "IP")?;
         writeln!(f, "- HeaderLen: {}", self.header_length())?;
         writeln!(f, "- TotalLen: {}", self.total_length())?;
+        writeln!(f, "- Id: {}", self.identification())?;
+        if self.is_fragment() {
+            writeln!(
+                f,
+                "- Fragment: Offset: {}, MF: {}",
+                self.fragment_offset() as usize * 8,
+                self.more_fragments()
+            )?;
+        }
         writeln!(f, "- TTL: {}", self.ttl())?;
         writeln!(f, "- Protocol: {:?}", self.protocol())?;
-        writeln!(f, "- Source: {:?}", self.source2())?;
-        writeln!(f, "- Destination: {:?}", self.destination2())
+        for option in self.options() {
+            match option {
+                Ok(IpOption::EndOfList | IpOption::NoOperation) => {}
+                Ok(option) => writeln!(f, "- Option: {}", option)?,
+                Err(e) => writeln!(f, "- Option: {}", e)?,
+            }
+        }
+        writeln!(f, "- Source: {}", self.source())?;
+        writeln!(f, "- Destination: {}", self.destination())
+    }
+}
+
+/// An IPv4 or IPv6 packet, whichever the version nibble says. This is what
+/// the transport layers are handed, so they serve both families alike.
+#[derive(Clone, Copy)]
+pub enum IpPacket<'a> {
+    V4(Ip<'a>),
+    V6(Ipv6<'a>),
+}
+
+impl<'a> ProtocolBuffer for IpPacket<'a> {
+    fn buf(&self) -> &[u8] {
+        self.remainder()
+    }
+}
+
+impl<'a> IpPacket<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        ParseError::check_length(Layer::Ip, 1, bytes.len())?;
+        match bytes[0] >> 4 {
+            4 => Ok(Self::V4(Ip::parse(bytes)?)),
+            6 => Ok(Self::V6(Ipv6::parse(bytes)?)),
+            version => Err(ParseError::Version {
+                layer: Layer::Ip,
+                version,
+            }),
+        }
+    }
+
+    pub fn remainder(&self) -> &'a [u8] {
+        match self {
+            Self::V4(ip) => ip.remainder(),
+            Self::V6(ip) => ip.remainder(),
+        }
+    }
+
+    pub fn protocol(&self) -> Protocol {
+        match self {
+            Self::V4(ip) => ip.protocol(),
+            Self::V6(ip) => ip.protocol(),
+        }
+    }
+
+    pub fn source(&self) -> IpAddr {
+        match self {
+            Self::V4(ip) => ip.source().into(),
+            Self::V6(ip) => ip.source().into(),
+        }
+    }
+
+    pub fn destination(&self) -> IpAddr {
+        match self {
+            Self::V4(ip) => ip.destination().into(),
+            Self::V6(ip) => ip.destination().into(),
+        }
+    }
+}
+
+/// Wraps `data` in an IPv4 or IPv6 header, depending on the addresses.
+pub fn write_packet(
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: Protocol,
+    time_to_live: u8,
+    data: NetworkBuffer,
+) -> NetworkBuffer {
+    match (source, destination) {
+        (IpAddr::V4(source), IpAddr::V4(destination)) => {
+            let writer = IpHeaderWriter::new(source, destination, protocol, time_to_live, data);
+            // TCP sizes its segments to the path, everything else may be split up on the way.
+            match protocol {
+                Protocol::TCP => writer.dont_fragment().into_buf(),
+                _ => writer.into_buf(),
+            }
+        }
+        (IpAddr::V6(source), IpAddr::V6(destination)) => {
+            Ipv6HeaderWriter::new(source, destination, protocol, time_to_live, data).into_buf()
+        }
+        _ => unreachable!("{} and {} are of different families", source, destination),
+    }
+}
+
+impl<'a> Display for IpPacket<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::V4(ip) => ip.fmt(f),
+            Self::V6(ip) => ip.fmt(f),
+        }
     }
 }
@@ -0,0 +1,349 @@
+use std::{fmt::Display, net::Ipv4Addr};
+
+use crate::utils;
+
+use super::error::{Layer, ParseError};
+
+const END_OF_LIST: u8 = 0;
+const NO_OPERATION: u8 = 1;
+const RECORD_ROUTE: u8 = 7;
+const TIMESTAMP: u8 = 68;
+const ROUTER_ALERT: u8 = 148;
+
+/// Options with this bit set go into every fragment, not just the first.
+const COPIED: u8 = 0x80;
+/// What fits between the fixed header and the largest IHL.
+#[cfg(test)]
+pub const MAX_OPTIONS_LEN: usize = 40;
+
+/// One IPv4 option. Record Route and Timestamp keep every slot the sender
+/// made room for, `filled` says how many of those are in use.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IpOption {
+    EndOfList,
+    NoOperation,
+    RecordRoute {
+        route: Vec<Ipv4Addr>,
+        filled: usize,
+    },
+    Timestamp {
+        overflow: u8,
+        flag: TimestampFlag,
+        /// Only `TimestampFlag::TimestampsOnly` leaves the address out.
+        slots: Vec<(Option<Ipv4Addr>, u32)>,
+        filled: usize,
+    },
+    RouterAlert(u16),
+    Unknown {
+        kind: u8,
+        data: Vec<u8>,
+    },
+}
+
+/// What a Timestamp option asks routers to record.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TimestampFlag {
+    TimestampsOnly,
+    WithAddresses,
+    Prespecified,
+    Unknown(u8),
+}
+
+impl From<u8> for TimestampFlag {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Self::TimestampsOnly,
+            1 => Self::WithAddresses,
+            3 => Self::Prespecified,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+impl From<TimestampFlag> for u8 {
+    fn from(value: TimestampFlag) -> Self {
+        match value {
+            TimestampFlag::TimestampsOnly => 0,
+            TimestampFlag::WithAddresses => 1,
+            TimestampFlag::Prespecified => 3,
+            TimestampFlag::Unknown(val) => val,
+        }
+    }
+}
+
+impl IpOption {
+    #[cfg(test)]
+    pub fn kind(&self) -> u8 {
+        match self {
+            Self::EndOfList => END_OF_LIST,
+            Self::NoOperation => NO_OPERATION,
+            Self::RecordRoute { .. } => RECORD_ROUTE,
+            Self::Timestamp { .. } => TIMESTAMP,
+            Self::RouterAlert(_) => ROUTER_ALERT,
+            Self::Unknown { kind, .. } => *kind,
+        }
+    }
+
+    fn parse(kind: u8, option: &[u8]) -> Result<Self, ParseError> {
+        let body = option.get(2..).unwrap_or_default();
+        let invalid = || ParseError::Length {
+            layer: Layer::Ip,
+            length: option.len(),
+            available: option.len(),
+        };
+
+        let option = match kind {
+            END_OF_LIST => Self::EndOfList,
+            NO_OPERATION => Self::NoOperation,
+            RECORD_ROUTE => {
+                let (&pointer, route) = body.split_first().ok_or_else(invalid)?;
+                if pointer < 4 || route.len() % 4 != 0 {
+                    return Err(invalid());
+                }
+                let route: Vec<_> = route
+                    .chunks_exact(4)
+                    .map(|a| Ipv4Addr::from(utils::read_u32(a)))
+                    .collect();
+                let filled = ((pointer as usize - 4) / 4).min(route.len());
+                Self::RecordRoute { route, filled }
+            }
+            TIMESTAMP => {
+                let [pointer, flags, slots @ ..] = body else {
+                    return Err(invalid());
+                };
+                let flag = TimestampFlag::from(flags & 0xf);
+                let size = match flag {
+                    TimestampFlag::TimestampsOnly => 4,
+                    _ => 8,
+                };
+                if *pointer < 5 || slots.len() % size != 0 {
+                    return Err(invalid());
+                }
+                let slots: Vec<_> = slots
+                    .chunks_exact(size)
+                    .map(|slot| match size {
+                        4 => (None, utils::read_u32(slot)),
+                        _ => (
+                            Some(Ipv4Addr::from(utils::read_u32(slot))),
+                            utils::read_u32(&slot[4..]),
+                        ),
+                    })
+                    .collect();
+                let filled = ((*pointer as usize - 5) / size).min(slots.len());
+                Self::Timestamp {
+                    overflow: flags >> 4,
+                    flag,
+                    slots,
+                    filled,
+                }
+            }
+            ROUTER_ALERT => match body {
+                [a, b] => Self::RouterAlert(u16::from_be_bytes([*a, *b])),
+                _ => return Err(invalid()),
+            },
+            kind => Self::Unknown {
+                kind,
+                data: body.to_vec(),
+            },
+        };
+
+        Ok(option)
+    }
+
+    /// Appends the option as it goes on the wire.
+    #[cfg(test)]
+    pub fn write(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        match self {
+            Self::EndOfList | Self::NoOperation => {
+                buf.push(self.kind());
+                return;
+            }
+            Self::RecordRoute { route, filled } => {
+                buf.extend_from_slice(&[RECORD_ROUTE, 0, (4 + filled * 4) as u8]);
+                for address in route {
+                    buf.extend_from_slice(&address.octets());
+                }
+            }
+            Self::Timestamp {
+                overflow,
+                flag,
+                slots,
+                filled,
+            } => {
+                let size = match flag {
+                    TimestampFlag::TimestampsOnly => 4,
+                    _ => 8,
+                };
+                let pointer = (5 + filled * size) as u8;
+                buf.extend_from_slice(&[TIMESTAMP, 0, pointer, overflow << 4 | u8::from(*flag)]);
+                for (address, timestamp) in slots {
+                    if let Some(address) = address {
+                        buf.extend_from_slice(&address.octets());
+                    }
+                    buf.extend_from_slice(&timestamp.to_be_bytes());
+                }
+            }
+            Self::RouterAlert(value) => {
+                buf.extend_from_slice(&[ROUTER_ALERT, 4]);
+                buf.extend_from_slice(&value.to_be_bytes());
+                return;
+            }
+            Self::Unknown { kind, data } => {
+                buf.extend_from_slice(&[*kind, 0]);
+                buf.extend_from_slice(data);
+            }
+        }
+        buf[start + 1] = (buf.len() - start) as u8;
+    }
+}
+
+/// Every option in `options`, the bytes between the fixed header and the
+/// end of the header. Stops after End of Option List.
+pub fn parse_options(options: &[u8]) -> impl Iterator<Item = Result<IpOption, ParseError>> + '_ {
+    raw_options(options).map(|option| {
+        let (kind, option) = option?;
+        IpOption::parse(kind, option)
+    })
+}
+
+/// The options in `options` that go into every fragment, as they were.
+pub fn copied_options(options: &[u8]) -> Vec<u8> {
+    raw_options(options)
+        .map_while(Result::ok)
+        .filter(|(kind, _)| kind & COPIED != 0)
+        .flat_map(|(_, option)| option.iter().copied())
+        .collect()
+}
+
+/// Splits `options` into each option's type and bytes, length included.
+fn raw_options(mut options: &[u8]) -> impl Iterator<Item = Result<(u8, &[u8]), ParseError>> {
+    let mut done = false;
+    std::iter::from_fn(move || {
+        if done {
+            return None;
+        }
+        let &kind = options.first()?;
+
+        let length = match kind {
+            END_OF_LIST => {
+                done = true;
+                1
+            }
+            NO_OPERATION => 1,
+            _ => match options.get(1) {
+                Some(&length) if length >= 2 && length as usize <= options.len() => length as usize,
+                _ => {
+                    done = true;
+                    return Some(Err(ParseError::Length {
+                        layer: Layer::Ip,
+                        length: options.get(1).map_or(0, |&l| l as usize),
+                        available: options.len(),
+                    }));
+                }
+            },
+        };
+
+        let (option, rest) = options.split_at(length);
+        options = rest;
+        Some(Ok((kind, option)))
+    })
+}
+
+impl Display for IpOption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::EndOfList => write!(f, "EOL"),
+            Self::NoOperation => write!(f, "NOP"),
+            Self::RecordRoute { route, filled } => {
+                write!(f, "RecordRoute: {:?}", &route[..*filled])?;
+                write!(f, " ({} free)", route.len() - filled)
+            }
+            Self::Timestamp {
+                overflow,
+                flag,
+                slots,
+                filled,
+            } => {
+                write!(f, "Timestamp {:?}:", flag)?;
+                for (address, timestamp) in &slots[..*filled] {
+                    match address {
+                        Some(address) => write!(f, " {}@{}", address, timestamp)?,
+                        None => write!(f, " {}", timestamp)?,
+                    }
+                }
+                write!(
+                    f,
+                    " ({} free, {} overflowed)",
+                    slots.len() - filled,
+                    overflow
+                )
+            }
+            Self::RouterAlert(value) => write!(f, "RouterAlert: {}", value),
+            Self::Unknown { kind, data } => write!(f, "Unknown {}: {:?}", kind, data),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::proto::{
+        Protocol,
+        ip::{Ip, IpHeaderWriter},
+    };
+
+    use super::*;
+
+    #[test]
+    fn writes_and_parses_options() {
+        let options = [
+            IpOption::RouterAlert(0),
+            IpOption::RecordRoute {
+                route: vec![Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::UNSPECIFIED],
+                filled: 1,
+            },
+            IpOption::Timestamp {
+                overflow: 2,
+                flag: TimestampFlag::WithAddresses,
+                slots: vec![(Some(Ipv4Addr::new(10, 0, 0, 2)), 1234)],
+                filled: 1,
+            },
+        ];
+
+        let packet = IpHeaderWriter::new(
+            Ipv4Addr::new(10, 0, 0, 2),
+            Ipv4Addr::new(10, 0, 0, 1),
+            Protocol::UDP,
+            64,
+            vec![7; 8].into(),
+        )
+        .options(&options)
+        .dont_fragment()
+        .into_buf();
+
+        let ip = Ip::parse(&packet).unwrap();
+        // 4 + 11 + 12 bytes, padded with End of Option List.
+        assert_eq!(ip.header_length(), 48);
+        assert_eq!(ip.total_length(), 56);
+        assert_eq!(ip.remainder(), [7; 8]);
+        assert!(ip.checksum_valid());
+
+        let parsed: Vec<_> = ip.options().map(Result::unwrap).collect();
+        assert_eq!(parsed[..3], options);
+        assert_eq!(parsed[3..], [IpOption::EndOfList]);
+        assert!(
+            ip.to_string()
+                .contains("- Option: RecordRoute: [10.0.0.2] (1 free)")
+        );
+
+        // Only Router Alert has the copied bit.
+        let copied = copied_options(&ip.header()[20..]);
+        assert_eq!(copied, [ROUTER_ALERT, 4, 0, 0]);
+
+        // An option running past the header is an error, not a panic.
+        let mut bytes = Vec::new();
+        options[1].write(&mut bytes);
+        bytes.truncate(5);
+        assert!(parse_options(&bytes).next().unwrap().is_err());
+    }
+}
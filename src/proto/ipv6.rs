@@ -0,0 +1,268 @@
+use std::{fmt::Display, net::Ipv6Addr};
+
+use crate::utils;
+
+use super::{
+    NetworkBuffer, Protocol, ProtocolBuffer,
+    error::{Layer, ParseError},
+};
+
+const IPV6_HEADER_LEN: usize = 40;
+
+const HOP_BY_HOP: u8 = 0;
+const ROUTING: u8 = 43;
+const FRAGMENT: u8 = 44;
+const AUTHENTICATION: u8 = 51;
+const DESTINATION_OPTIONS: u8 = 60;
+
+impl<'a> ProtocolBuffer for Ipv6<'a> {
+    fn buf(&self) -> &[u8] {
+        self.remainder()
+    }
+}
+
+/// An IPv6 packet. Parsing walks the extension headers, so `protocol` and
+/// `remainder` are those of the upper layer.
+#[derive(Clone, Copy)]
+pub struct Ipv6<'a> {
+    data: &'a [u8],
+    // Where the upper layer header starts, past every extension header.
+    payload_offset: usize,
+    protocol: u8,
+}
+
+/// One extension header between the fixed header and the upper layer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExtensionHeader {
+    HopByHop,
+    Routing,
+    Fragment {
+        offset: u16,
+        more: bool,
+        identification: u32,
+    },
+    Authentication,
+    DestinationOptions,
+}
+
+impl<'a> Ipv6<'a> {
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, ParseError> {
+        ParseError::check_length(Layer::Ip, IPV6_HEADER_LEN, bytes.len())?;
+
+        let version = bytes[0] >> 4;
+        if version != 6 {
+            return Err(ParseError::Version {
+                layer: Layer::Ip,
+                version,
+            });
+        }
+
+        // Anything past the payload length is link layer padding.
+        let length = IPV6_HEADER_LEN + utils::read_u16(&bytes[4..6]) as usize;
+        if length > bytes.len() {
+            return Err(ParseError::Length {
+                layer: Layer::Ip,
+                length,
+                available: bytes.len(),
+            });
+        }
+
+        let mut s = Self {
+            data: &bytes[..length],
+            payload_offset: IPV6_HEADER_LEN,
+            protocol: bytes[6],
+        };
+
+        for header in s.extension_headers() {
+            let (_, next, end) = header?;
+            s.protocol = next;
+            s.payload_offset = end;
+        }
+
+        Ok(s)
+    }
+
+    /// Every extension header in order, with the header that follows it
+    /// and where that one starts.
+    pub fn extension_headers(
+        &self,
+    ) -> impl Iterator<Item = Result<(ExtensionHeader, u8, usize), ParseError>> + 'a {
+        let data = self.data;
+        let mut next = data[6];
+        let mut offset = IPV6_HEADER_LEN;
+
+        std::iter::from_fn(move || {
+            let kind = next;
+            let header = data.get(offset..offset + 8);
+            let truncated = |needed| ParseError::Truncated {
+                layer: Layer::Ip,
+                needed,
+                available: data.len(),
+            };
+
+            let (extension, length) = match (kind, header) {
+                (HOP_BY_HOP | ROUTING | DESTINATION_OPTIONS | FRAGMENT | AUTHENTICATION, None) => {
+                    return Some(Err(truncated(offset + 8)));
+                }
+                (HOP_BY_HOP, Some(h)) => (ExtensionHeader::HopByHop, (h[1] as usize + 1) * 8),
+                (ROUTING, Some(h)) => (ExtensionHeader::Routing, (h[1] as usize + 1) * 8),
+                (DESTINATION_OPTIONS, Some(h)) => {
+                    (ExtensionHeader::DestinationOptions, (h[1] as usize + 1) * 8)
+                }
+                (AUTHENTICATION, Some(h)) => {
+                    (ExtensionHeader::Authentication, (h[1] as usize + 2) * 4)
+                }
+                (FRAGMENT, Some(h)) => {
+                    let offset_and_flags = utils::read_u16(&h[2..4]);
+                    let fragment = ExtensionHeader::Fragment {
+                        offset: offset_and_flags >> 3,
+                        more: offset_and_flags & 1 == 1,
+                        identification: utils::read_u32(&h[4..8]),
+                    };
+                    (fragment, 8)
+                }
+                // Upper layer, or No Next Header.
+                _ => return None,
+            };
+
+            if offset + length > data.len() {
+                return Some(Err(truncated(offset + length)));
+            }
+
+            next = data[offset];
+            offset += length;
+            Some(Ok((extension, next, offset)))
+        })
+    }
+
+    /// Whether this is one piece of a fragmented packet, which we don't reassemble.
+    pub fn is_fragment(&self) -> bool {
+        self.extension_headers().any(|header| {
+            matches!(header, Ok((ExtensionHeader::Fragment { offset, more, .. }, ..)) if offset != 0 || more)
+        })
+    }
+
+    pub fn header_length(&self) -> usize {
+        self.payload_offset
+    }
+
+    pub fn remainder(&self) -> &'a [u8] {
+        &self.data[self.payload_offset..]
+    }
+
+    pub fn payload_length(&self) -> u16 {
+        utils::read_u16(&self.data[4..6])
+    }
+
+    pub fn hop_limit(&self) -> u8 {
+        self.data[7]
+    }
+
+    /// The upper layer protocol, after any extension headers.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol.into()
+    }
+
+    pub fn source(&self) -> Ipv6Addr {
+        Ipv6Addr::from(<[u8; 16]>::try_from(&self.data[8..24]).unwrap())
+    }
+
+    pub fn destination(&self) -> Ipv6Addr {
+        Ipv6Addr::from(<[u8; 16]>::try_from(&self.data[24..40]).unwrap())
+    }
+}
+
+pub struct Ipv6HeaderWriter {
+    buf: NetworkBuffer,
+}
+
+impl Ipv6HeaderWriter {
+    pub fn new(
+        source: Ipv6Addr,
+        destination: Ipv6Addr,
+        protocol: Protocol,
+        hop_limit: u8,
+        data: NetworkBuffer,
+    ) -> Self {
+        let mut buf = NetworkBuffer::new(IPV6_HEADER_LEN + data.len());
+        // Version 6, no traffic class or flow label.
+        buf.extend_from_slice(&[0x60, 0, 0, 0]);
+        buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        buf.extend_from_slice(&[protocol.into(), hop_limit]);
+        buf.extend_from_slice(&source.octets());
+        buf.extend_from_slice(&destination.octets());
+        buf.extend_from_slice(&data);
+
+        Self { buf }
+    }
+
+    pub fn into_buf(self) -> NetworkBuffer {
+        self.buf
+    }
+}
+
+impl<'a> Display for Ipv6<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "IPv6")?;
+        writeln!(f, "- HeaderLen: {}", self.header_length())?;
+        writeln!(f, "- PayloadLen: {}", self.payload_length())?;
+        writeln!(f, "- HopLimit: {}", self.hop_limit())?;
+        for (header, ..) in self.extension_headers().flatten() {
+            writeln!(f, "- Extension: {:?}", header)?;
+        }
+        writeln!(f, "- Protocol: {:?}", self.protocol())?;
+        writeln!(f, "- Source: {}", self.source())?;
+        writeln!(f, "- Destination: {}", self.destination())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn walks_extension_headers_to_the_upper_layer() {
+        let source = "fd00::2".parse().unwrap();
+        let destination = "fd00::1".parse().unwrap();
+
+        // Hop-by-Hop with a PadN option, then a first fragment, then UDP.
+        let mut payload = vec![FRAGMENT, 0, 1, 4, 0, 0, 0, 0];
+        payload.extend_from_slice(&[17, 0, 0, 1, 0, 0, 0, 42]);
+        payload.extend_from_slice(b"udp header and data");
+
+        let mut packet = Ipv6HeaderWriter::new(
+            source,
+            destination,
+            Protocol::Unknown(HOP_BY_HOP),
+            64,
+            payload.into(),
+        )
+        .into_buf();
+        // Link layer padding is not part of the packet.
+        packet.extend_from_slice(&[0; 6]);
+
+        let ip = Ipv6::parse(&packet).unwrap();
+        assert_eq!(ip.protocol(), Protocol::UDP);
+        assert_eq!(ip.header_length(), 56);
+        assert_eq!(ip.remainder(), b"udp header and data");
+        assert_eq!((ip.source(), ip.destination()), (source, destination));
+        assert!(ip.is_fragment());
+
+        let headers: Vec<_> = ip.extension_headers().map(|h| h.unwrap().0).collect();
+        assert_eq!(
+            headers,
+            [
+                ExtensionHeader::HopByHop,
+                ExtensionHeader::Fragment {
+                    offset: 0,
+                    more: true,
+                    identification: 42
+                },
+            ]
+        );
+
+        // A Hop-by-Hop header claiming more than there is.
+        packet[41] = 9;
+        assert!(Ipv6::parse(&packet[..packet.len() - 6]).is_err());
+    }
+}
@@ -3,18 +3,29 @@ use std::{
     ops::{Deref, DerefMut},
 };
 
+pub mod arp;
+pub mod error;
+pub mod ethernet;
 pub mod http;
 pub mod icmp;
 pub mod ip;
+pub mod ip_options;
+pub mod ipv6;
+pub mod pool;
 pub mod tcp;
 pub mod udp;
 
+pub use error::{Layer, ParseError};
+
+// Spelled the way the RFCs and every capture tool print them.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Protocol {
     ICMP,
     GatewayToGateway,
     TCP,
     UDP,
+    ICMPv6,
     Unknown(u8),
 }
 
@@ -25,6 +36,7 @@ impl From<u8> for Protocol {
             3 => Self::GatewayToGateway,
             6 => Self::TCP,
             17 => Self::UDP,
+            58 => Self::ICMPv6,
             other => Self::Unknown(other),
         }
     }
@@ -37,6 +49,7 @@ impl From<Protocol> for u8 {
             Protocol::GatewayToGateway => 3,
             Protocol::TCP => 6,
             Protocol::UDP => 17,
+            Protocol::ICMPv6 => 58,
             Protocol::Unknown(val) => val,
         }
     }
@@ -46,15 +59,25 @@ pub trait ProtocolBuffer: Display {
     fn buf(&self) -> &[u8];
 }
 
+/// Lets a parser borrow a layer it doesn't need to hand back.
+impl<P: ProtocolBuffer> ProtocolBuffer for &P {
+    fn buf(&self) -> &[u8] {
+        (*self).buf()
+    }
+}
+
+/// Backing memory comes from `pool` and goes back there on drop.
 pub struct NetworkBuffer(Vec<u8>);
 
 impl NetworkBuffer {
     pub fn new(capacity: usize) -> Self {
-        Self(Vec::with_capacity(capacity))
+        Self(pool::take(capacity))
     }
 
     pub fn new_zeroed(capacity: usize) -> Self {
-        Self(vec![0; capacity])
+        let mut buf = Self::new(capacity);
+        buf.resize(capacity, 0);
+        buf
     }
 
     pub fn empty() -> Self {
@@ -62,7 +85,13 @@ impl NetworkBuffer {
     }
 
     pub fn extend(&mut self, other: NetworkBuffer) {
-        self.0.extend(other.0);
+        self.0.extend_from_slice(&other.0);
+    }
+}
+
+impl Drop for NetworkBuffer {
+    fn drop(&mut self) {
+        pool::give(std::mem::take(&mut self.0));
     }
 }
 
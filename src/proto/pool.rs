@@ -0,0 +1,40 @@
+use std::cell::RefCell;
+
+/// Buffers kept around for reuse. Packets come and go at the same rate, so a
+/// modest free list is enough to keep the allocator out of the hot path.
+const MAX_POOLED: usize = 1024;
+/// Anything bigger is left to the allocator rather than pinned in the pool.
+const MAX_CAPACITY: usize = 64 * 1024;
+
+thread_local! {
+    /// One per thread, so taking and giving back never waits on a lock. The
+    /// stack's loop builds most buffers and drops most of them too, buffers
+    /// dropped on another thread end up in that thread's pool.
+    static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// An empty buffer with room for at least `capacity` bytes.
+pub(super) fn take(capacity: usize) -> Vec<u8> {
+    let mut buf = POOL
+        .try_with(|pool| pool.borrow_mut().pop())
+        .ok()
+        .flatten()
+        .unwrap_or_default();
+    buf.reserve(capacity);
+    buf
+}
+
+pub(super) fn give(mut buf: Vec<u8>) {
+    if buf.capacity() == 0 || buf.capacity() > MAX_CAPACITY {
+        return;
+    }
+
+    buf.clear();
+    // Buffers dropped while the thread exits go back to the allocator.
+    let _ = POOL.try_with(|pool| {
+        let mut pool = pool.borrow_mut();
+        if pool.len() < MAX_POOLED {
+            pool.push(buf);
+        }
+    });
+}
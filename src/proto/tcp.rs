@@ -1,9 +1,21 @@
-use std::fmt::Display;
+use std::{fmt::Display, net::IpAddr};
 
 use crate::utils;
 
-use super::{NetworkBuffer, ProtocolBuffer};
-use anyhow::Result;
+use super::{
+    NetworkBuffer, Protocol, ProtocolBuffer,
+    error::{Layer, ParseError},
+    ip::{self, IpPacket},
+};
+
+const TCP_HEADER_LEN_MIN: usize = 20;
+const RESET_TTL: u8 = 64;
+
+const END_OF_OPTIONS: u8 = 0;
+const NO_OPERATION: u8 = 1;
+/// Maximum segment size, the most data the sender of a SYN takes in one segment.
+const MSS_OPTION: u8 = 2;
+const MSS_OPTION_LEN: u8 = 4;
 
 pub struct Tcp<P: ProtocolBuffer> {
     inner: P,
@@ -17,14 +29,31 @@ impl<P: ProtocolBuffer> ProtocolBuffer for Tcp<P> {
 }
 
 impl<P: ProtocolBuffer> Tcp<P> {
-    pub fn parse(proto: P) -> Result<Self> {
-        Ok(Self { inner: proto })
+    pub fn parse(proto: P) -> Result<Self, ParseError> {
+        let available = proto.buf().len();
+        ParseError::check_length(Layer::Tcp, TCP_HEADER_LEN_MIN, available)?;
+
+        let s = Self { inner: proto };
+        let header_length = s.header_length();
+        if header_length < TCP_HEADER_LEN_MIN || header_length > available {
+            return Err(ParseError::HeaderLength {
+                layer: Layer::Tcp,
+                length: header_length,
+                available,
+            });
+        }
+
+        Ok(s)
     }
 
     pub fn inner(&self) -> &P {
         &self.inner
     }
 
+    pub fn into_inner(self) -> P {
+        self.inner
+    }
+
     pub fn source_port(&self) -> u16 {
         utils::read_u16(self.inner.buf())
     }
@@ -42,13 +71,12 @@ impl<P: ProtocolBuffer> Tcp<P> {
     }
 
     pub fn header_length(&self) -> usize {
-        let len = unsafe { *self.inner.buf().get_unchecked(12) >> 4 };
         // Length is represented as 32 bit words, so 4 bytes
-        (len * 4) as usize
+        (self.inner.buf()[12] >> 4) as usize * 4
     }
 
     pub fn control(&self) -> TcpControl {
-        TcpControl::from_bits_retain(unsafe { *self.inner.buf().get_unchecked(13) })
+        TcpControl::from_bits_retain(self.inner.buf()[13])
     }
 
     pub fn window(&self) -> u16 {
@@ -58,8 +86,85 @@ impl<P: ProtocolBuffer> Tcp<P> {
     pub fn urgent_pointer(&self) -> u16 {
         utils::read_u16(&self.inner.buf()[18..])
     }
+
+    /// The maximum segment size option, if the segment carries a valid one.
+    pub fn mss(&self) -> Option<u16> {
+        let mut options = &self.inner.buf()[TCP_HEADER_LEN_MIN..self.header_length()];
+        while let [kind, rest @ ..] = options {
+            match *kind {
+                END_OF_OPTIONS => return None,
+                NO_OPERATION => options = rest,
+                kind => {
+                    let length = *rest.first()? as usize;
+                    if length < 2 || length > options.len() {
+                        return None;
+                    }
+                    if kind == MSS_OPTION && length == MSS_OPTION_LEN as usize {
+                        return Some(utils::read_u16(&options[2..]));
+                    }
+                    options = &options[length..];
+                }
+            }
+        }
+        None
+    }
 }
 
+impl Tcp<IpPacket<'_>> {
+    /// Whether the checksum over the pseudo header and segment adds up.
+    pub fn checksum_valid(&self) -> bool {
+        let segment = self.inner.remainder();
+        let sum = utils::add_pseudo_header(
+            0,
+            self.inner.source(),
+            self.inner.destination(),
+            Protocol::TCP.into(),
+            segment.len(),
+        );
+        utils::checksum_valid(utils::add_slice(sum, segment))
+    }
+
+    /// The RST refusing this segment (RFC 793), as a whole packet back to
+    /// where it came from. Resets themselves are never answered.
+    pub fn reset(&self) -> Option<NetworkBuffer> {
+        let control = self.control();
+        if control.contains(TcpControl::RST) {
+            return None;
+        }
+
+        let writer = if control.contains(TcpControl::ACK) {
+            TcpHeaderWriter::new(
+                self.destination_port(),
+                self.source_port(),
+                self.ack_number(),
+                0,
+            )
+            .set(TcpControl::RST)
+        } else {
+            // SYN and FIN take up a sequence number each.
+            let length = self.buf().len()
+                + control.contains(TcpControl::SYN) as usize
+                + control.contains(TcpControl::FIN) as usize;
+            let ack = self.sequence_number().wrapping_add(length as u32);
+            TcpHeaderWriter::new(self.destination_port(), self.source_port(), 0, ack)
+                .set(TcpControl::RST | TcpControl::ACK)
+        };
+
+        let (source, destination) = (self.inner.destination(), self.inner.source());
+        let segment = writer.calc_checksum_for(source, destination).into_buf();
+        Some(ip::write_packet(
+            source,
+            destination,
+            Protocol::TCP,
+            RESET_TTL,
+            segment,
+        ))
+    }
+}
+
+/// The window of segments that don't say otherwise, like RSTs.
+const DEFAULT_WINDOW: u16 = 1024;
+
 pub struct TcpHeaderWriter {
     buf: NetworkBuffer,
 }
@@ -80,11 +185,27 @@ impl TcpHeaderWriter {
         buf.extend_from_slice(&ack_number.to_be_bytes()); // Ack Number
         buf.push(Self::DEFAULT_SIZE); // 4bit total header len in 4 byte sizes. eg. 5 == 20 bytes.
         buf.resize(20, 0);
-        buf[14..16].copy_from_slice(&1024u16.to_be_bytes());
+        buf[14..16].copy_from_slice(&DEFAULT_WINDOW.to_be_bytes());
 
         Self { buf }
     }
 
+    /// How much more data we take beyond what this acks.
+    pub fn window(mut self, window: u16) -> Self {
+        self.buf[14..16].copy_from_slice(&window.to_be_bytes());
+        self
+    }
+
+    /// Announces the most data we take in one segment, only SYNs do. Goes
+    /// before the data.
+    pub fn mss(mut self, mss: u16) -> Self {
+        debug_assert_eq!(self.buf.len(), TCP_HEADER_LEN_MIN, "Options go before data");
+        self.buf.extend_from_slice(&[MSS_OPTION, MSS_OPTION_LEN]);
+        self.buf.extend_from_slice(&mss.to_be_bytes());
+        self.buf[12] = ((self.buf.len() / 4) as u8) << 4;
+        self
+    }
+
     pub fn data(mut self, data: NetworkBuffer) -> Self {
         if !data.is_empty() {
             self.buf.extend(data);
@@ -95,21 +216,20 @@ impl TcpHeaderWriter {
     }
 
     pub fn set(mut self, control: TcpControl) -> Self {
-        let current = TcpControl::from_bits_retain(unsafe { *self.buf.get_unchecked(13) });
+        let current = TcpControl::from_bits_retain(self.buf[13]);
         self.buf[13] = current.union(control).bits();
         self
     }
 
-    pub fn calc_checksum(mut self, ip_header: &super::ip::Ip<'_>) -> Self {
-        let ip_header_sum = {
-            let length = self.buf.len() as u16;
-            let mut sum = 0;
-            sum = utils::add_4bytes(sum, ip_header.destination2());
-            sum = utils::add_4bytes(sum, ip_header.source2());
-            sum = utils::add_2bytes(sum, [0, ip_header.protocol().into()]);
-            sum = utils::add_2bytes(sum, length.to_be_bytes());
-            sum
-        };
+    pub fn calc_checksum(self, ip_header: &IpPacket<'_>) -> Self {
+        self.calc_checksum_for(ip_header.source(), ip_header.destination())
+    }
+
+    /// Checksum over the pseudo header for the given addresses, for segments
+    /// that aren't a reply to some received packet.
+    pub fn calc_checksum_for(mut self, source: IpAddr, destination: IpAddr) -> Self {
+        let ip_header_sum =
+            utils::add_pseudo_header(0, source, destination, Protocol::TCP.into(), self.buf.len());
 
         let checksum = utils::ones_complement(utils::add_slice(ip_header_sum, &self.buf)).to_be();
         self.buf[16..18].copy_from_slice(&checksum.to_be_bytes());
@@ -117,7 +237,7 @@ impl TcpHeaderWriter {
         self
     }
 
-    pub fn to_buf(self) -> NetworkBuffer {
+    pub fn into_buf(self) -> NetworkBuffer {
         self.buf
     }
 }
@@ -154,7 +274,7 @@ bitflags! {
 
 #[cfg(test)]
 mod tests {
-    use crate::proto::ip::{Ip, IpHeaderWriter};
+    use crate::proto::ip::{IpHeaderWriter, IpPacket};
 
     use super::*;
 
@@ -167,21 +287,20 @@ mod tests {
         const SN: u32 = 5;
         const AN: u32 = 10;
 
-        let data = NetworkBuffer::empty();
         let ip_buf = IpHeaderWriter::new(
-            u32::from_be_bytes(S_ADDR),
-            u32::from_be_bytes(D_ADDR),
+            S_ADDR.into(),
+            D_ADDR.into(),
             crate::proto::Protocol::TCP,
             64,
             NetworkBuffer::empty(),
         )
-        .to_buf();
-        let ip = Ip::parse(&ip_buf).unwrap();
+        .into_buf();
+        let ip = IpPacket::parse(&ip_buf).unwrap();
 
         let tcp = TcpHeaderWriter::new(SP, DP, SN, AN)
             .set(TcpControl::ACK)
             .calc_checksum(&ip)
-            .to_buf();
+            .into_buf();
 
         let mut tcp2 = etherparse::TcpHeader::new(SP, DP, SN, 1024);
         tcp2.acknowledgment_number = AN;
@@ -199,4 +318,27 @@ mod tests {
 
         assert_eq!(tcp.as_slice(), buf.as_slice())
     }
+
+    #[test]
+    fn reads_back_the_mss_option() {
+        let syn = TcpHeaderWriter::new(50000, 80, 0, 0)
+            .set(TcpControl::SYN)
+            .mss(1400)
+            .data(b"x".as_slice().into())
+            .into_buf();
+        let tcp = Tcp::parse(syn).unwrap();
+        assert_eq!(tcp.header_length(), 24);
+        assert_eq!((tcp.mss(), tcp.buf()), (Some(1400), b"x".as_slice()));
+
+        // Padding first, then an option that runs past the header.
+        let mut options = TcpHeaderWriter::new(50000, 80, 0, 0).mss(1400).into_buf();
+        options[20..24].copy_from_slice(&[NO_OPERATION, MSS_OPTION, 8, 0]);
+        assert_eq!(Tcp::parse(options).unwrap().mss(), None);
+        assert_eq!(
+            Tcp::parse(TcpHeaderWriter::new(1, 2, 0, 0).into_buf())
+                .unwrap()
+                .mss(),
+            None
+        );
+    }
 }
@@ -1,12 +1,18 @@
-use std::fmt::Display;
+use std::{fmt::Display, net::IpAddr};
 
 use crate::utils;
 
-use super::{NetworkBuffer, ProtocolBuffer};
+use super::{
+    NetworkBuffer, Protocol, ProtocolBuffer,
+    error::{Layer, ParseError},
+    ip::IpPacket,
+};
+
+const UDP_HEADER_LEN: usize = 8;
 
 impl<P: ProtocolBuffer> ProtocolBuffer for Udp<P> {
     fn buf(&self) -> &[u8] {
-        &self.inner.buf()[8..]
+        &self.inner.buf()[UDP_HEADER_LEN..self.length() as usize]
     }
 }
 
@@ -15,12 +21,21 @@ pub struct Udp<P: ProtocolBuffer> {
 }
 
 impl<P: ProtocolBuffer> Udp<P> {
-    pub fn parse(proto: P) -> anyhow::Result<Self> {
-        Ok(Self { inner: proto })
-    }
+    pub fn parse(proto: P) -> Result<Self, ParseError> {
+        let available = proto.buf().len();
+        ParseError::check_length(Layer::Udp, UDP_HEADER_LEN, available)?;
+
+        let s = Self { inner: proto };
+        let length = s.length() as usize;
+        if length < UDP_HEADER_LEN || length > available {
+            return Err(ParseError::Length {
+                layer: Layer::Udp,
+                length,
+                available,
+            });
+        }
 
-    pub fn inner(&self) -> &P {
-        &self.inner
+        Ok(s)
     }
 
     pub fn source_port(&self) -> u16 {
@@ -40,6 +55,26 @@ impl<P: ProtocolBuffer> Udp<P> {
     }
 }
 
+impl Udp<IpPacket<'_>> {
+    /// Whether the checksum over the pseudo header and datagram adds up. Zero
+    /// means the sender didn't compute one, which only IPv4 allows.
+    pub fn checksum_valid(&self) -> bool {
+        if self.checksum() == 0 {
+            return matches!(self.inner, IpPacket::V4(_));
+        }
+
+        let datagram = &self.inner.remainder()[..self.length() as usize];
+        let sum = utils::add_pseudo_header(
+            0,
+            self.inner.source(),
+            self.inner.destination(),
+            Protocol::UDP.into(),
+            datagram.len(),
+        );
+        utils::checksum_valid(utils::add_slice(sum, datagram))
+    }
+}
+
 pub struct UdpHeaderWriter {
     buf: NetworkBuffer,
 }
@@ -68,25 +103,20 @@ impl UdpHeaderWriter {
         }
     }
 
-    pub fn calc_checksum(mut self, ip_header: &super::ip::Ip<'_>) -> Self {
-        let ip_header_sum = {
-            let length = self.buf.len() as u16;
-            let mut sum = 0;
-            sum = utils::add_4bytes(sum, ip_header.destination2());
-            sum = utils::add_4bytes(sum, ip_header.source2());
-            sum = utils::add_2bytes(sum, [0, ip_header.protocol().into()]);
-            sum = utils::add_2bytes(sum, length.to_be_bytes());
-            sum
-        };
+    /// Checksum over the pseudo header for the given addresses, for datagrams
+    /// that aren't a reply to some received packet.
+    pub fn calc_checksum_for(mut self, source: IpAddr, destination: IpAddr) -> Self {
+        let ip_header_sum =
+            utils::add_pseudo_header(0, source, destination, Protocol::UDP.into(), self.buf.len());
 
         let checksum =
             utils::ones_complement_with_no_zero(utils::add_slice(ip_header_sum, &self.buf)).to_be();
-        self.buf[16..18].copy_from_slice(&checksum.to_be_bytes());
+        self.buf[6..8].copy_from_slice(&checksum.to_be_bytes());
 
         self
     }
 
-    pub fn to_buf(self) -> NetworkBuffer {
+    pub fn into_buf(self) -> NetworkBuffer {
         self.buf
     }
 }
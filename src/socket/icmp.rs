@@ -0,0 +1,342 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::Ipv4Addr,
+    time::Duration,
+};
+
+use crate::{proto::icmp::Unreachable, time::Instant};
+
+use super::Sockets;
+
+/// Replies a socket holds on to before the stack starts dropping them.
+const RECEIVE_QUEUE: usize = 64;
+/// The most an echo request can carry over IPv4.
+const MAX_PAYLOAD: usize = 65_507;
+/// How long a request waits for its answer before it's forgotten, well
+/// past what ping and traceroute wait for.
+const REPLY_DEADLINE: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub(super) struct IcmpSockets {
+    /// By the echo identifier the socket sends with.
+    bound: HashMap<u16, Bound>,
+    next_identifier: u16,
+}
+
+struct Bound {
+    local: Ipv4Addr,
+    tx: VecDeque<Probe>,
+    /// When the stack sent each sequence number still waiting for an answer.
+    sent: HashMap<u16, Instant>,
+    rx: VecDeque<Reply>,
+}
+
+impl Bound {
+    /// Forgets the requests nothing came back for in time.
+    fn expire(&mut self, now: Instant) {
+        self.sent
+            .retain(|_, sent| now.saturating_duration_since(*sent) < REPLY_DEADLINE);
+    }
+}
+
+struct Probe {
+    destination: Ipv4Addr,
+    ttl: u8,
+    sequence: u16,
+    data: Vec<u8>,
+}
+
+/// An echo request an application wants sent.
+pub struct Outgoing {
+    pub source: Ipv4Addr,
+    pub destination: Ipv4Addr,
+    pub ttl: u8,
+    pub identifier: u16,
+    pub sequence: u16,
+    pub data: Vec<u8>,
+}
+
+/// What came back for an echo request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reply {
+    /// Who answered, a router on the way for anything but `Echo`.
+    pub from: Ipv4Addr,
+    pub sequence: u16,
+    pub kind: ReplyKind,
+    /// From the stack sending the request to it taking in the answer.
+    pub rtt: Duration,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReplyKind {
+    /// The destination answered.
+    Echo,
+    /// The TTL ran out on the way.
+    TimeExceeded,
+    Unreachable(Unreachable),
+}
+
+/// The stack's side of ping sockets.
+impl Sockets {
+    /// Hands what came back for `sequence` to the socket sending as
+    /// `identifier`, false if nobody is waiting for it.
+    pub fn icmp_received(
+        &self,
+        now: Instant,
+        identifier: u16,
+        sequence: u16,
+        from: Ipv4Addr,
+        kind: ReplyKind,
+    ) -> bool {
+        let mut state = self.lock();
+        let Some(bound) = state.icmp.bound.get_mut(&identifier) else {
+            return false;
+        };
+        // Duplicates, late answers and answers to requests we never sent go nowhere.
+        bound.expire(now);
+        let Some(sent) = bound.sent.remove(&sequence) else {
+            return false;
+        };
+
+        if bound.rx.len() < RECEIVE_QUEUE {
+            bound.rx.push_back(Reply {
+                from,
+                sequence,
+                kind,
+                rtt: now.saturating_duration_since(sent),
+            });
+        } else {
+            tracing::warn!(
+                identifier,
+                sequence,
+                "Ping receive queue full, dropping reply"
+            );
+        }
+
+        self.notify();
+        true
+    }
+
+    pub fn icmp_has_pending(&self) -> bool {
+        self.lock().icmp.bound.values().any(|b| !b.tx.is_empty())
+    }
+
+    /// Takes every echo request applications queued, noting they went out at `now`.
+    pub fn icmp_take(&self, now: Instant) -> Vec<Outgoing> {
+        let mut state = self.lock();
+        let mut identifiers: Vec<_> = state.icmp.bound.keys().copied().collect();
+        // Keep the order stable, simulations depend on it.
+        identifiers.sort();
+
+        let mut out = vec![];
+        for identifier in identifiers {
+            let bound = state.icmp.bound.get_mut(&identifier).unwrap();
+            bound.expire(now);
+            for probe in bound.tx.drain(..) {
+                bound.sent.insert(probe.sequence, now);
+                out.push(Outgoing {
+                    source: bound.local,
+                    destination: probe.destination,
+                    ttl: probe.ttl,
+                    identifier,
+                    sequence: probe.sequence,
+                    data: probe.data,
+                });
+            }
+        }
+        out
+    }
+}
+
+/// Sends echo requests and receives what comes back for them, like the
+/// ping sockets of `socket(AF_INET, SOCK_DGRAM, IPPROTO_ICMP)`. Each socket
+/// gets an identifier of its own, replies are matched by it and the
+/// sequence number.
+pub struct PingSocket {
+    sockets: Sockets,
+    identifier: u16,
+    nonblocking: bool,
+    read_timeout: Option<Duration>,
+}
+
+impl PingSocket {
+    /// Sends from `local`, which has to be one of the stack's addresses for
+    /// replies to make it back.
+    pub fn bind(sockets: &Sockets, local: Ipv4Addr) -> io::Result<Self> {
+        let mut state = sockets.lock();
+        let icmp = &mut state.icmp;
+        if icmp.bound.len() > u16::MAX as usize {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+
+        let mut identifier = icmp.next_identifier;
+        while icmp.bound.contains_key(&identifier) {
+            identifier = identifier.wrapping_add(1);
+        }
+        icmp.next_identifier = identifier.wrapping_add(1);
+        icmp.bound.insert(
+            identifier,
+            Bound {
+                local,
+                tx: VecDeque::new(),
+                sent: HashMap::new(),
+                rx: VecDeque::new(),
+            },
+        );
+
+        Ok(Self {
+            sockets: sockets.clone(),
+            identifier,
+            nonblocking: false,
+            read_timeout: None,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn identifier(&self) -> u16 {
+        self.identifier
+    }
+
+    #[cfg(test)]
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    /// How long `recv` blocks before failing with `TimedOut`, forever if `None`.
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    /// Queues an echo request to `destination` that expires after `ttl` hops.
+    pub fn send_to(
+        &self,
+        destination: Ipv4Addr,
+        ttl: u8,
+        sequence: u16,
+        data: &[u8],
+    ) -> io::Result<()> {
+        if data.len() > MAX_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Echo request too large",
+            ));
+        }
+
+        let mut state = self.sockets.lock();
+        let bound = state.icmp.bound.get_mut(&self.identifier).unwrap();
+        bound.tx.push_back(Probe {
+            destination,
+            ttl,
+            sequence,
+            data: data.into(),
+        });
+
+        drop(state);
+        self.sockets.wake();
+        Ok(())
+    }
+
+    /// The next answer to one of our requests.
+    pub fn recv(&self) -> io::Result<Reply> {
+        let ready = |state: &mut super::SocketSet| {
+            let bound = state.icmp.bound.get_mut(&self.identifier)?;
+            bound.rx.pop_front().map(Ok)
+        };
+
+        match self.read_timeout {
+            Some(timeout) if !self.nonblocking => self.sockets.wait_timeout(timeout, ready),
+            _ => self.sockets.wait(self.nonblocking, ready),
+        }
+    }
+}
+
+impl Drop for PingSocket {
+    fn drop(&mut self) {
+        self.sockets.lock().icmp.bound.remove(&self.identifier);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        network::routing::RoutingTable,
+        socket::stack,
+        stack::sim::{LinkConfig, Simulation},
+    };
+
+    use super::*;
+
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const ROUTER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+
+    #[test]
+    fn replies_and_expired_probes_come_back_with_round_trips() {
+        let sockets = Sockets::default();
+        let mut socket = PingSocket::bind(&sockets, CLIENT).unwrap();
+        socket.set_nonblocking(true);
+        let mut other = PingSocket::bind(&sockets, CLIENT).unwrap();
+        assert_ne!(socket.identifier(), other.identifier());
+
+        let table = RoutingTable::default()
+            .interface(ROUTER, "10.0.0.0/24".parse().unwrap())
+            .interface(Ipv4Addr::new(10, 0, 1, 1), "10.0.1.0/24".parse().unwrap());
+        let router = stack(Sockets::default()).router(table);
+        let mut sim = Simulation::new(1, LinkConfig::default(), stack(sockets), router);
+
+        socket.send_to(ROUTER, 64, 1, b"hello").unwrap();
+        // Expires at the router on its way across.
+        socket
+            .send_to(Ipv4Addr::new(10, 0, 1, 5), 1, 2, b"")
+            .unwrap();
+        sim.run_for(Duration::from_millis(100));
+
+        let rtt = Duration::from_millis(20);
+        assert_eq!(
+            socket.recv().unwrap(),
+            Reply {
+                from: ROUTER,
+                sequence: 1,
+                kind: ReplyKind::Echo,
+                rtt,
+            }
+        );
+        assert_eq!(
+            socket.recv().unwrap(),
+            Reply {
+                from: ROUTER,
+                sequence: 2,
+                kind: ReplyKind::TimeExceeded,
+                rtt,
+            }
+        );
+        assert_eq!(socket.recv().unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+        other.set_read_timeout(Some(Duration::from_millis(1)));
+        assert_eq!(other.recv().unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn requests_are_forgotten_after_the_deadline() {
+        let sockets = Sockets::default();
+        let socket = PingSocket::bind(&sockets, CLIENT).unwrap();
+        let start = Instant::from_millis(0);
+        let late = start + REPLY_DEADLINE;
+        let received = |at, sequence| {
+            sockets.icmp_received(at, socket.identifier(), sequence, ROUTER, ReplyKind::Echo)
+        };
+
+        socket.send_to(ROUTER, 64, 1, b"").unwrap();
+        sockets.icmp_take(start);
+        socket.send_to(ROUTER, 64, 2, b"").unwrap();
+        sockets.icmp_take(late);
+
+        let sent = |sockets: &Sockets| sockets.lock().icmp.bound[&socket.identifier()].sent.len();
+        assert_eq!(sent(&sockets), 1);
+        assert!(!received(late, 1));
+        assert!(received(late, 2));
+        assert_eq!(sent(&sockets), 0);
+    }
+}
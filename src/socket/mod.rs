@@ -0,0 +1,141 @@
+use std::{
+    collections::HashMap,
+    net::{Ipv4Addr, SocketAddr},
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::Duration,
+};
+
+pub mod icmp;
+pub mod tcp;
+pub mod udp;
+
+pub use icmp::PingSocket;
+pub use tcp::{TcpListener, TcpStream};
+pub use udp::UdpSocket;
+
+type Waker = Arc<dyn Fn() + Send + Sync>;
+
+/// Socket state shared between the stack and the application threads using
+/// the socket API. The stack fills receive buffers and drains send buffers,
+/// applications block on the condvar until the stack has done something.
+#[derive(Clone, Default)]
+pub struct Sockets {
+    inner: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    state: Mutex<SocketSet>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct SocketSet {
+    tcp: tcp::TcpSockets,
+    udp: udp::UdpSockets,
+    icmp: icmp::IcmpSockets,
+    // Tells whoever drives the stack that a socket has work for it.
+    waker: Option<Waker>,
+}
+
+impl Sockets {
+    /// `waker` is called whenever an application queued something for the stack to send.
+    pub fn set_waker(&self, waker: impl Fn() + Send + Sync + 'static) {
+        self.lock().waker = Some(Arc::new(waker));
+    }
+
+    fn lock(&self) -> MutexGuard<'_, SocketSet> {
+        self.inner.state.lock().unwrap()
+    }
+
+    /// Blocks until `ready` returns something, or returns `WouldBlock` right
+    /// away when `nonblocking` is set.
+    fn wait<T>(
+        &self,
+        nonblocking: bool,
+        mut ready: impl FnMut(&mut SocketSet) -> Option<std::io::Result<T>>,
+    ) -> std::io::Result<T> {
+        let mut state = self.lock();
+        loop {
+            if let Some(result) = ready(&mut state) {
+                return result;
+            }
+
+            if nonblocking {
+                return Err(std::io::ErrorKind::WouldBlock.into());
+            }
+
+            state = self.inner.changed.wait(state).unwrap();
+        }
+    }
+
+    /// Like a blocking `wait`, failing with `TimedOut` once `timeout` has passed.
+    fn wait_timeout<T>(
+        &self,
+        timeout: Duration,
+        mut ready: impl FnMut(&mut SocketSet) -> Option<std::io::Result<T>>,
+    ) -> std::io::Result<T> {
+        let deadline = std::time::Instant::now() + timeout;
+        let mut state = self.lock();
+        loop {
+            if let Some(result) = ready(&mut state) {
+                return result;
+            }
+
+            let left = deadline.saturating_duration_since(std::time::Instant::now());
+            if left.is_zero() {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+
+            state = self.inner.changed.wait_timeout(state, left).unwrap().0;
+        }
+    }
+
+    /// Wakes up application threads blocked on a socket.
+    fn notify(&self) {
+        self.inner.changed.notify_all();
+    }
+
+    /// Wakes up the stack, with the lock already released.
+    fn wake(&self) {
+        let waker = self.lock().waker.clone();
+        if let Some(waker) = waker {
+            waker();
+        }
+    }
+}
+
+/// What a socket binds to when it's for every local address, like `0.0.0.0`.
+pub fn any(port: u16) -> SocketAddr {
+    SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port)
+}
+
+/// Where sockets keyed by what they're bound to take packets for `local`
+/// from: the one bound to exactly that address, or the one bound to all of them.
+pub(crate) fn bound_for<T>(
+    bound: &HashMap<SocketAddr, T>,
+    local: SocketAddr,
+) -> Option<SocketAddr> {
+    [local, any(local.port())]
+        .into_iter()
+        .find(|address| bound.contains_key(address))
+}
+
+/// A stack serving nothing but the sockets in `sockets`, on 10.0.0.1.
+#[cfg(test)]
+fn stack(sockets: Sockets) -> crate::stack::Stack {
+    use crate::network::{
+        icmp::IcmpHandler,
+        ip::IpHandler,
+        tcp::{Isn, TcpHandler},
+        udp::UdpHandler,
+    };
+
+    let timers = crate::time::Timers::default();
+    let ip = IpHandler {
+        icmp: IcmpHandler::new(timers.clone(), sockets.clone()),
+        udp: UdpHandler::new([10, 0, 0, 1].into(), sockets.clone()),
+        tcp: TcpHandler::new(timers.clone(), sockets).isn(Isn::Fixed(0)),
+    };
+    crate::stack::Stack::new(ip, timers)
+}
@@ -0,0 +1,714 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr},
+};
+
+use crate::network::tcp::Quad;
+
+use super::{Sockets, bound_for};
+
+/// Data a stream holds on to for the application, the most a window says
+/// without scaling.
+const RECEIVE_BUFFER: usize = u16::MAX as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StreamId(u64);
+
+#[derive(Default)]
+pub(super) struct TcpSockets {
+    // What a listener is bound to, to the established connections nobody has accepted yet.
+    listeners: HashMap<SocketAddr, VecDeque<StreamId>>,
+    streams: HashMap<StreamId, Stream>,
+    next_id: u64,
+}
+
+struct Stream {
+    quad: Quad,
+    rx: VecDeque<u8>,
+    tx: VecDeque<u8>,
+    /// The window we last told the peer about.
+    announced: usize,
+    /// Reads made enough room that the peer should hear about it.
+    window_update: bool,
+    /// The peer sent its FIN, reads return 0 once `rx` is drained.
+    peer_closed: bool,
+    /// The application is done reading.
+    read_shutdown: bool,
+    /// The application is done writing, our FIN goes out once `tx` is drained.
+    write_shutdown: bool,
+    fin_sent: bool,
+    /// The connection is gone.
+    closed: bool,
+    /// Nobody holds a `TcpStream` for this anymore.
+    dropped: bool,
+}
+
+impl Stream {
+    fn new(quad: Quad) -> Self {
+        Self {
+            quad,
+            rx: VecDeque::new(),
+            tx: VecDeque::new(),
+            announced: RECEIVE_BUFFER,
+            window_update: false,
+            peer_closed: false,
+            read_shutdown: false,
+            write_shutdown: false,
+            fin_sent: false,
+            closed: false,
+            dropped: false,
+        }
+    }
+
+    /// Whether there's data the peer's `window` takes, our FIN after
+    /// everything else, or a window update.
+    fn pending(&self, window: usize) -> bool {
+        let data = !self.tx.is_empty() && window > 0;
+        let fin = self.tx.is_empty() && self.write_shutdown && !self.fin_sent;
+        !self.closed && (data || fin || self.window_update)
+    }
+
+    fn free(&self) -> usize {
+        RECEIVE_BUFFER - self.rx.len()
+    }
+
+    fn release(&mut self) {
+        self.dropped = true;
+        self.write_shutdown = true;
+        self.read_shutdown = true;
+        self.rx.clear();
+    }
+}
+
+impl TcpSockets {
+    /// A stream that's gone isn't connected to anything.
+    fn stream(&mut self, id: StreamId) -> io::Result<&mut Stream> {
+        self.streams
+            .get_mut(&id)
+            .ok_or_else(|| io::ErrorKind::NotConnected.into())
+    }
+}
+
+/// The stack's side of TCP sockets.
+impl Sockets {
+    /// Whether a listener takes connections to `local`.
+    pub fn tcp_listening(&self, local: SocketAddr) -> bool {
+        bound_for(&self.lock().tcp.listeners, local).is_some()
+    }
+
+    /// Hands a freshly established connection to whoever listens on its address.
+    pub fn tcp_accept(&self, quad: Quad) -> Option<StreamId> {
+        let mut state = self.lock();
+        let tcp = &mut state.tcp;
+
+        let id = StreamId(tcp.next_id);
+        let listener = bound_for(&tcp.listeners, quad.local())?;
+        let backlog = tcp.listeners.get_mut(&listener)?;
+        backlog.push_back(id);
+        tcp.next_id += 1;
+        tcp.streams.insert(id, Stream::new(quad));
+
+        self.notify();
+        Some(id)
+    }
+
+    /// Room for more data on the stream `id`, the window the stack
+    /// announces from now on.
+    pub fn tcp_receive_window(&self, id: StreamId) -> usize {
+        let mut state = self.lock();
+        let Some(stream) = state.tcp.streams.get_mut(&id) else {
+            return RECEIVE_BUFFER;
+        };
+        stream.announced = stream.free();
+        stream.window_update = false;
+        stream.announced
+    }
+
+    /// Queues data the peer sent for the application, at most what the
+    /// window had room for.
+    pub fn tcp_received(&self, id: StreamId, data: &[u8]) {
+        let mut state = self.lock();
+        if let Some(stream) = state.tcp.streams.get_mut(&id)
+            && !stream.read_shutdown
+        {
+            let len = data.len().min(stream.free());
+            if len < data.len() {
+                tracing::warn!(?id, "TCP receive buffer full, dropping data");
+            }
+            stream.rx.extend(&data[..len]);
+            stream.announced = stream.announced.saturating_sub(data.len());
+        }
+        self.notify();
+    }
+
+    pub fn tcp_peer_closed(&self, id: StreamId) {
+        let mut state = self.lock();
+        if let Some(stream) = state.tcp.streams.get_mut(&id) {
+            stream.peer_closed = true;
+        }
+        self.notify();
+    }
+
+    /// The connection behind `id` is gone, for good or bad.
+    pub fn tcp_closed(&self, id: StreamId) {
+        let mut state = self.lock();
+        let tcp = &mut state.tcp;
+
+        if let Some(stream) = tcp.streams.get_mut(&id) {
+            stream.closed = true;
+            if stream.dropped {
+                tcp.streams.remove(&id);
+            }
+        }
+        self.notify();
+    }
+
+    /// `window` says how much the connection of a stream can send.
+    pub fn tcp_has_pending(&self, window: impl Fn(Quad) -> usize) -> bool {
+        self.lock()
+            .tcp
+            .streams
+            .values()
+            .any(|stream| stream.pending(window(stream.quad)))
+    }
+
+    /// Streams with data that fits `window`, or a FIN, waiting to go out.
+    pub fn tcp_pending(&self, window: impl Fn(Quad) -> usize) -> Vec<(StreamId, Quad)> {
+        let state = self.lock();
+        let mut pending: Vec<_> = state
+            .tcp
+            .streams
+            .iter()
+            .filter(|(_, stream)| stream.pending(window(stream.quad)))
+            .map(|(id, stream)| (*id, stream.quad))
+            .collect();
+        // Keep the order stable, simulations depend on it.
+        pending.sort_by_key(|(id, _)| id.0);
+        pending
+    }
+
+    /// Takes up to `max` bytes of what the application wrote, the rest
+    /// waits for the peer to ack more. Also whether it's time to send our FIN.
+    pub fn tcp_take(&self, id: StreamId, max: usize) -> (Vec<u8>, bool) {
+        let mut state = self.lock();
+        let Some(stream) = state.tcp.streams.get_mut(&id) else {
+            return (vec![], false);
+        };
+
+        let len = max.min(stream.tx.len());
+        let data = stream.tx.drain(..len).collect();
+        // Our FIN comes after the last of the data.
+        if !stream.tx.is_empty() {
+            return (data, false);
+        }
+        let fin = stream.write_shutdown && !stream.fin_sent;
+        stream.fin_sent |= fin;
+
+        self.notify();
+        (data, fin)
+    }
+}
+
+/// Listens for connections on a port of the stack, like `std::net::TcpListener`.
+pub struct TcpListener {
+    sockets: Sockets,
+    local: SocketAddr,
+    nonblocking: bool,
+}
+
+impl TcpListener {
+    /// Listens on `port` of every local address.
+    pub fn bind(sockets: &Sockets, port: u16) -> io::Result<Self> {
+        Self::bind_addr(sockets, super::any(port))
+    }
+
+    /// Listens on one local address only, which takes precedence over a
+    /// listener on the same port of every address.
+    pub fn bind_addr(sockets: &Sockets, local: SocketAddr) -> io::Result<Self> {
+        let mut state = sockets.lock();
+        if state.tcp.listeners.contains_key(&local) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        state.tcp.listeners.insert(local, VecDeque::new());
+
+        Ok(Self {
+            sockets: sockets.clone(),
+            local,
+            nonblocking: false,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    pub fn accept(&self) -> io::Result<(TcpStream, SocketAddr)> {
+        let id = self.sockets.wait(self.nonblocking, |state| {
+            let backlog = state.tcp.listeners.get_mut(&self.local)?;
+            backlog.pop_front().map(Ok)
+        })?;
+
+        let stream = TcpStream {
+            sockets: self.sockets.clone(),
+            id,
+            nonblocking: false,
+        };
+        let peer = stream.peer_addr()?;
+        Ok((stream, peer))
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let mut state = self.sockets.lock();
+        let tcp = &mut state.tcp;
+
+        // Connections nobody accepted get closed.
+        for id in tcp.listeners.remove(&self.local).unwrap_or_default() {
+            if let Some(stream) = tcp.streams.get_mut(&id) {
+                stream.release();
+            }
+        }
+
+        drop(state);
+        self.sockets.wake();
+    }
+}
+
+/// A connection on the stack, like `std::net::TcpStream`. Reads block until
+/// data arrives unless the stream is set to nonblocking, writes are queued
+/// and sent the next time the stack is polled.
+pub struct TcpStream {
+    sockets: Sockets,
+    id: StreamId,
+    nonblocking: bool,
+}
+
+impl TcpStream {
+    #[cfg(test)]
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.quad()?.remote())
+    }
+
+    #[cfg(test)]
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.quad()?.local())
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        let mut state = self.sockets.lock();
+        let stream = state.tcp.stream(self.id)?;
+        if stream.closed {
+            return Err(io::ErrorKind::NotConnected.into());
+        }
+
+        if let Shutdown::Read | Shutdown::Both = how {
+            stream.read_shutdown = true;
+            stream.rx.clear();
+        }
+        if let Shutdown::Write | Shutdown::Both = how {
+            stream.write_shutdown = true;
+        }
+
+        drop(state);
+        self.sockets.notify();
+        self.sockets.wake();
+        Ok(())
+    }
+
+    fn quad(&self) -> io::Result<Quad> {
+        Ok(self.sockets.lock().tcp.stream(self.id)?.quad)
+    }
+}
+
+impl Read for TcpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut window_update = false;
+        let read = self.sockets.wait(self.nonblocking, |state| {
+            let stream = match state.tcp.stream(self.id) {
+                Ok(stream) => stream,
+                Err(e) => return Some(Err(e)),
+            };
+
+            if !stream.rx.is_empty() {
+                let len = buf.len().min(stream.rx.len());
+                for (to, from) in buf.iter_mut().zip(stream.rx.drain(..len)) {
+                    *to = from;
+                }
+                // Like Linux, the peer hears about the window once it
+                // opened by half the buffer, not for every read.
+                let half = RECEIVE_BUFFER / 2;
+                if stream.announced < half && stream.free() >= half && !stream.closed {
+                    stream.window_update = true;
+                    window_update = true;
+                }
+                Some(Ok(len))
+            } else if stream.peer_closed || stream.read_shutdown {
+                Some(Ok(0))
+            } else if stream.closed {
+                Some(Err(io::ErrorKind::ConnectionReset.into()))
+            } else {
+                None
+            }
+        });
+
+        if window_update {
+            self.sockets.wake();
+        }
+        read
+    }
+}
+
+impl Write for TcpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.sockets.lock();
+        let stream = state.tcp.stream(self.id)?;
+
+        if stream.closed {
+            return Err(io::ErrorKind::ConnectionReset.into());
+        }
+        if stream.write_shutdown {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        stream.tx.extend(buf);
+
+        drop(state);
+        self.sockets.wake();
+        Ok(buf.len())
+    }
+
+    /// Waits until the stack has picked up everything written so far.
+    fn flush(&mut self) -> io::Result<()> {
+        self.sockets.wait(self.nonblocking, |state| {
+            let stream = match state.tcp.stream(self.id) {
+                Ok(stream) => stream,
+                Err(e) => return Some(Err(e)),
+            };
+            (stream.tx.is_empty() || stream.closed).then_some(Ok(()))
+        })
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut state = self.sockets.lock();
+        let tcp = &mut state.tcp;
+
+        match tcp.streams.get_mut(&self.id) {
+            Some(stream) if !stream.closed => stream.release(),
+            Some(_) => _ = tcp.streams.remove(&self.id),
+            None => {}
+        }
+
+        drop(state);
+        self.sockets.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use crate::{
+        proto::{
+            NetworkBuffer, Protocol, ProtocolBuffer,
+            icmp::{IcmpMessage, Quote, Unreachable},
+            ip::{Ip, IpHeaderWriter},
+            tcp::Tcp,
+            tcp::TcpControl,
+        },
+        socket::stack,
+        stack::{
+            peer::Peer,
+            sim::{LinkConfig, Side, Simulation},
+        },
+    };
+
+    use super::*;
+
+    const PEER: Peer = Peer {
+        address: Ipv4Addr::new(10, 0, 0, 2),
+        port: 50000,
+        server: Ipv4Addr::new(10, 0, 0, 1),
+        server_port: 4000,
+    };
+
+    /// Control bits, sequence number and payload of the last segment the server sent.
+    fn last_from_server(sim: &Simulation) -> (TcpControl, u32, Vec<u8>) {
+        let sent = sim.trace().iter().rfind(|t| t.from == Side::B).unwrap();
+        let tcp = Tcp::parse(Ip::parse(&sent.packet).unwrap()).unwrap();
+        (tcp.control(), tcp.sequence_number(), tcp.buf().to_vec())
+    }
+
+    /// A destination unreachable from the peer about the last segment the server sent.
+    fn unreachable_about_last(sim: &Simulation, reason: Unreachable) -> NetworkBuffer {
+        let sent = sim.trace().iter().rfind(|t| t.from == Side::B).unwrap();
+        let quote = Quote::of(&Ip::parse(&sent.packet).unwrap());
+        let message = IcmpMessage::DestinationUnreachable { reason, quote }.into_buf();
+        IpHeaderWriter::new(PEER.address, PEER.server, Protocol::ICMP, 64, message).into_buf()
+    }
+
+    #[test]
+    fn accepted_stream_reads_writes_and_closes() {
+        let sockets = Sockets::default();
+        let mut listener = TcpListener::bind(&sockets, 4000).unwrap();
+        listener.set_nonblocking(true);
+        assert!(TcpListener::bind(&sockets, 4000).is_err());
+
+        // Packets are injected by hand, it mustn't reset what it didn't open.
+        let client = stack(Sockets::default()).error_rate_limit(0, 0);
+        let mut sim = Simulation::new(1, LinkConfig::default(), client, stack(sockets));
+        let wait = Duration::from_millis(100);
+
+        sim.a.send(PEER.segment(TcpControl::SYN, 41, 0, &[]));
+        sim.run_for(wait);
+        assert!(matches!(listener.accept(), Err(e) if e.kind() == io::ErrorKind::WouldBlock));
+
+        sim.a.send(PEER.segment(TcpControl::ACK, 42, 1, &[]));
+        sim.a.send(PEER.segment(TcpControl::ACK, 42, 1, b"hello"));
+        sim.run_for(wait);
+
+        let (mut stream, peer) = listener.accept().unwrap();
+        stream.set_nonblocking(true);
+        assert_eq!(peer.to_string(), "10.0.0.2:50000");
+        assert_eq!(stream.local_addr().unwrap().to_string(), "10.0.0.1:4000");
+
+        let mut buf = [0; 16];
+        assert_eq!(stream.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(
+            stream.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        stream.write_all(b"world").unwrap();
+        sim.run_for(wait);
+        let psh_ack = TcpControl::PSH | TcpControl::ACK;
+        assert_eq!(last_from_server(&sim), (psh_ack, 1, b"world".to_vec()));
+
+        sim.a
+            .send(PEER.segment(TcpControl::FIN | TcpControl::ACK, 47, 6, &[]));
+        sim.run_for(wait);
+        assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+        // Dropping the stream closes our side as well.
+        drop(stream);
+        sim.run_for(wait);
+        let fin_ack = TcpControl::FIN | TcpControl::ACK;
+        assert_eq!(last_from_server(&sim), (fin_ack, 6, vec![]));
+    }
+
+    /// Sequence number, ack number and payload length of everything the server sent.
+    fn from_server(sim: &Simulation) -> Vec<(u32, u32, usize)> {
+        sim.trace()
+            .iter()
+            .filter(|t| t.from == Side::B)
+            .map(|t| {
+                let tcp = Tcp::parse(Ip::parse(&t.packet).unwrap()).unwrap();
+                (tcp.sequence_number(), tcp.ack_number(), tcp.buf().len())
+            })
+            .collect()
+    }
+
+    /// A connection from `PEER`, and the listener that accepted it.
+    fn accepted() -> (Simulation, TcpListener, TcpStream) {
+        let sockets = Sockets::default();
+        let listener = TcpListener::bind(&sockets, 4000).unwrap();
+        let client = stack(Sockets::default()).error_rate_limit(0, 0);
+        let mut sim = Simulation::new(1, LinkConfig::default(), client, stack(sockets));
+
+        sim.a.send(PEER.syn(41, 1460));
+        sim.run_for(Duration::from_millis(100));
+        sim.a.send(PEER.segment(TcpControl::ACK, 42, 1, &[]));
+        sim.run_for(Duration::from_millis(100));
+        let (mut stream, _) = listener.accept().unwrap();
+        stream.set_nonblocking(true);
+        (sim, listener, stream)
+    }
+
+    #[test]
+    fn accepted_streams_outlive_their_listener() {
+        let (mut sim, listener, mut stream) = accepted();
+        let wait = Duration::from_millis(100);
+        drop(listener);
+
+        sim.a.send(PEER.segment(TcpControl::ACK, 42, 1, b"hello"));
+        sim.run_for(wait);
+        let mut buf = [0; 16];
+        assert_eq!(stream.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+
+        stream.write_all(b"world").unwrap();
+        sim.run_for(wait);
+        let psh_ack = TcpControl::PSH | TcpControl::ACK;
+        assert_eq!(last_from_server(&sim), (psh_ack, 1, b"world".to_vec()));
+        let resets = sim.trace().iter().filter(|t| {
+            let tcp = Tcp::parse(Ip::parse(&t.packet).unwrap()).unwrap();
+            t.from == Side::B && tcp.control().contains(TcpControl::RST)
+        });
+        assert_eq!(resets.count(), 0);
+    }
+
+    /// Ack number and window of the last segment the server sent.
+    fn last_ack(sim: &Simulation) -> (u32, u16) {
+        let sent = sim.trace().iter().rfind(|t| t.from == Side::B).unwrap();
+        let tcp = Tcp::parse(Ip::parse(&sent.packet).unwrap()).unwrap();
+        (tcp.ack_number(), tcp.window())
+    }
+
+    #[test]
+    fn data_beyond_the_window_waits_for_the_application_to_read() {
+        let (mut sim, _listener, mut stream) = accepted();
+        let wait = Duration::from_millis(100);
+        assert_eq!(last_ack(&sim).1, u16::MAX);
+
+        let mut sequence = 42;
+        for _ in 0..65 {
+            sim.a
+                .send(PEER.segment(TcpControl::ACK, sequence, 1, &[7; 1000]));
+            sequence += 1000;
+        }
+        sim.run_for(wait);
+        assert_eq!(last_ack(&sim), (sequence, 535));
+
+        // Nothing past the window gets in, or acked.
+        sim.a
+            .send(PEER.segment(TcpControl::ACK, sequence, 1, &[7; 1000]));
+        sim.run_for(wait);
+        assert_eq!(last_ack(&sim), (sequence, 535));
+
+        let mut buf = vec![0; 40_000];
+        assert_eq!(stream.read(&mut buf).unwrap(), 40_000);
+        sim.run_for(wait);
+        assert_eq!(last_ack(&sim), (sequence, 40_535));
+    }
+
+    #[test]
+    fn duplicate_and_early_segments_are_dropped_and_acked() {
+        let (mut sim, _listener, mut stream) = accepted();
+        let wait = Duration::from_millis(100);
+
+        sim.a.send(PEER.segment(TcpControl::ACK, 42, 1, b"hello"));
+        sim.run_for(wait);
+        // The same again, then one that skips ahead of what we have.
+        sim.a.send(PEER.segment(TcpControl::ACK, 42, 1, b"hello"));
+        sim.a.send(PEER.segment(TcpControl::ACK, 52, 1, b"later"));
+        sim.a
+            .send(PEER.segment(TcpControl::FIN | TcpControl::ACK, 57, 1, &[]));
+        sim.run_for(wait);
+
+        let mut buf = [0; 16];
+        assert_eq!(stream.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"hello");
+        assert_eq!(
+            stream.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        let sent = from_server(&sim);
+        assert_eq!(sent[sent.len() - 4..], [(1, 47, 0); 4]);
+    }
+
+    #[test]
+    fn writes_wait_for_the_peer_to_open_its_window() {
+        let (mut sim, _listener, mut stream) = accepted();
+        let wait = Duration::from_millis(100);
+
+        // The peer takes 1024 bytes at a time.
+        stream.write_all(&[7; 3000]).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        sim.run_for(wait);
+        assert_eq!(from_server(&sim)[1..], [(1, 42, 1024)]);
+
+        sim.a.send(PEER.segment(TcpControl::ACK, 42, 1025, &[]));
+        sim.run_for(wait);
+        sim.a.send(PEER.segment(TcpControl::ACK, 42, 2049, &[]));
+        sim.run_for(wait);
+        // Our FIN goes out after the last of the data.
+        assert_eq!(
+            from_server(&sim)[1..],
+            [
+                (1, 42, 1024),
+                (1025, 42, 1024),
+                (2049, 42, 952),
+                (3001, 42, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn segments_fit_our_mtu_and_the_peer_mss() {
+        let sockets = Sockets::default();
+        let listener = TcpListener::bind(&sockets, 4000).unwrap();
+        let client = stack(Sockets::default()).error_rate_limit(0, 0);
+        let server = stack(sockets).mtu(1000);
+        let mut sim = Simulation::new(1, LinkConfig::default(), client, server);
+        let wait = Duration::from_millis(100);
+
+        for (port, mss) in [(50000, 1460), (50001, 500)] {
+            let peer = Peer { port, ..PEER };
+            sim.a.send(peer.syn(41, mss));
+            sim.run_for(wait);
+            let syn_ack = sim.trace().iter().rfind(|t| t.from == Side::B).unwrap();
+            let syn_ack = Tcp::parse(Ip::parse(&syn_ack.packet).unwrap()).unwrap();
+            assert_eq!(syn_ack.mss(), Some(960));
+
+            sim.a.send(peer.segment(TcpControl::ACK, 42, 1, &[]));
+            sim.run_for(wait);
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&[7; 1000]).unwrap();
+            sim.run_for(wait);
+        }
+
+        let sizes: Vec<_> = from_server(&sim)
+            .into_iter()
+            .map(|(_, _, len)| len)
+            .filter(|&len| len > 0)
+            .collect();
+        assert_eq!(sizes, [960, 40, 500, 500]);
+    }
+
+    #[test]
+    fn icmp_errors_shrink_segments_or_abort() {
+        let (mut sim, _listener, mut stream) = accepted();
+        let wait = Duration::from_millis(100);
+
+        stream.write_all(&[7; 1000]).unwrap();
+        sim.run_for(wait);
+        let too_big = Unreachable::FragmentationNeeded { next_hop_mtu: 576 };
+        sim.a.send(unreachable_about_last(&sim, too_big));
+        sim.run_for(wait);
+
+        let sent: Vec<_> = sim.trace().iter().filter(|t| t.from == Side::B).collect();
+        let resent: Vec<_> = sent[sent.len() - 2..]
+            .iter()
+            .map(|t| {
+                let tcp = Tcp::parse(Ip::parse(&t.packet).unwrap()).unwrap();
+                (tcp.sequence_number(), tcp.buf().len())
+            })
+            .collect();
+        assert_eq!(resent, [(1, 536), (537, 464)]);
+
+        // Once acked, errors quoting it are stale and ignored.
+        sim.a.send(PEER.segment(TcpControl::ACK, 42, 1001, &[]));
+        sim.run_for(wait);
+        sim.a.send(unreachable_about_last(&sim, Unreachable::Port));
+        sim.run_for(wait);
+        stream.write_all(b"more").unwrap();
+        sim.run_for(wait);
+
+        sim.a.send(unreachable_about_last(&sim, Unreachable::Port));
+        sim.run_for(wait);
+        let mut buf = [0; 16];
+        assert_eq!(
+            stream.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::ConnectionReset
+        );
+    }
+}
@@ -0,0 +1,376 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::{IpAddr, SocketAddr},
+};
+
+use super::{Sockets, bound_for};
+
+/// Datagrams a socket holds on to before the stack starts dropping them,
+/// the application is expected to keep up.
+const RECEIVE_QUEUE: usize = 64;
+/// The most a datagram can carry over IPv4.
+const MAX_PAYLOAD: usize = 65_507;
+/// Destinations a socket last sent to, ICMP errors about anyone else were
+/// made up or are long stale.
+const RECENT_DESTINATIONS: usize = 16;
+
+#[derive(Default)]
+pub(super) struct UdpSockets {
+    bound: HashMap<SocketAddr, Bound>,
+}
+
+#[derive(Default)]
+struct Bound {
+    rx: VecDeque<(SocketAddr, Vec<u8>)>,
+    tx: VecDeque<(SocketAddr, Vec<u8>)>,
+    /// Where the stack last sent datagrams from the socket, newest last.
+    recent: VecDeque<SocketAddr>,
+    /// What an ICMP error said about something we sent, and where to, for
+    /// the next call to return.
+    error: Option<(SocketAddr, io::ErrorKind)>,
+}
+
+impl Bound {
+    fn take_error(&mut self) -> Option<io::Error> {
+        let (remote, kind) = self.error.take()?;
+        Some(io::Error::new(kind, format!("Sending to {remote} failed")))
+    }
+}
+
+/// A datagram an application wants sent.
+pub struct Outgoing {
+    /// Only for sockets bound to one address, the stack picks one otherwise.
+    pub source: Option<IpAddr>,
+    pub source_port: u16,
+    pub destination: SocketAddr,
+    pub data: Vec<u8>,
+}
+
+/// The stack's side of UDP sockets.
+impl Sockets {
+    /// Queues a datagram on the socket bound to `local`, false if nobody is bound there.
+    pub fn udp_received(&self, local: SocketAddr, from: SocketAddr, data: &[u8]) -> bool {
+        let mut state = self.lock();
+        let Some(socket) = bound_for(&state.udp.bound, local) else {
+            return false;
+        };
+        let bound = state.udp.bound.get_mut(&socket).unwrap();
+
+        if bound.rx.len() < RECEIVE_QUEUE {
+            bound.rx.push_back((from, data.into()));
+        } else {
+            tracing::warn!(%local, %from, "UDP receive queue full, dropping datagram");
+        }
+
+        self.notify();
+        true
+    }
+
+    /// Whether a socket takes datagrams to `local`.
+    pub fn udp_bound(&self, local: SocketAddr) -> bool {
+        bound_for(&self.lock().udp.bound, local).is_some()
+    }
+
+    /// Has the next `send_to` or `recv_from` of the socket bound to `local`
+    /// fail with `error`, false if nobody is bound there or it hasn't sent
+    /// to `remote` lately.
+    pub fn udp_error(&self, local: SocketAddr, remote: SocketAddr, error: io::ErrorKind) -> bool {
+        let mut state = self.lock();
+        let Some(socket) = bound_for(&state.udp.bound, local) else {
+            return false;
+        };
+        let bound = state.udp.bound.get_mut(&socket).unwrap();
+        if !bound.recent.contains(&remote) {
+            return false;
+        }
+
+        tracing::debug!(%local, %remote, %error, "UDP socket got an ICMP error");
+        bound.error = Some((remote, error));
+        self.notify();
+        true
+    }
+
+    pub fn udp_has_pending(&self) -> bool {
+        self.lock().udp.bound.values().any(|b| !b.tx.is_empty())
+    }
+
+    /// Takes every datagram applications queued, in order of what their
+    /// sockets are bound to.
+    pub fn udp_take(&self) -> Vec<Outgoing> {
+        let mut state = self.lock();
+        let mut sockets: Vec<_> = state.udp.bound.keys().copied().collect();
+        // Keep the order stable, simulations depend on it.
+        sockets.sort();
+
+        let mut out = vec![];
+        for local in sockets {
+            let bound = state.udp.bound.get_mut(&local).unwrap();
+            let source = (!local.ip().is_unspecified()).then_some(local.ip());
+            for (destination, data) in bound.tx.drain(..) {
+                bound.recent.retain(|recent| *recent != destination);
+                if bound.recent.len() == RECENT_DESTINATIONS {
+                    bound.recent.pop_front();
+                }
+                bound.recent.push_back(destination);
+                out.push(Outgoing {
+                    source,
+                    source_port: local.port(),
+                    destination,
+                    data,
+                });
+            }
+        }
+        out
+    }
+}
+
+/// A UDP port on the stack, like `std::net::UdpSocket`. `recv_from` blocks
+/// until a datagram arrives unless the socket is set to nonblocking,
+/// `send_to` queues the datagram for the next time the stack is polled.
+pub struct UdpSocket {
+    sockets: Sockets,
+    local: SocketAddr,
+    nonblocking: bool,
+}
+
+impl UdpSocket {
+    /// Binds `port` of every local address.
+    #[cfg(test)]
+    pub fn bind(sockets: &Sockets, port: u16) -> io::Result<Self> {
+        Self::bind_addr(sockets, super::any(port))
+    }
+
+    /// Binds one local address only, which takes precedence over a socket
+    /// on the same port of every address and is what datagrams go out from.
+    pub fn bind_addr(sockets: &Sockets, local: SocketAddr) -> io::Result<Self> {
+        let mut state = sockets.lock();
+        if state.udp.bound.contains_key(&local) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        state.udp.bound.insert(local, Bound::default());
+
+        Ok(Self {
+            sockets: sockets.clone(),
+            local,
+            nonblocking: false,
+        })
+    }
+
+    #[cfg(test)]
+    pub fn set_nonblocking(&mut self, nonblocking: bool) {
+        self.nonblocking = nonblocking;
+    }
+
+    /// Reads one datagram into `buf`, whatever doesn't fit is discarded.
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        let (from, datagram) = self.sockets.wait(self.nonblocking, |state| {
+            let bound = state.udp.bound.get_mut(&self.local)?;
+            if let Some(error) = bound.take_error() {
+                return Some(Err(error));
+            }
+            bound.rx.pop_front().map(Ok)
+        })?;
+
+        let read = datagram.len().min(buf.len());
+        buf[..read].copy_from_slice(&datagram[..read]);
+        Ok((read, from))
+    }
+
+    pub fn send_to(&self, buf: &[u8], to: SocketAddr) -> io::Result<usize> {
+        if buf.len() > MAX_PAYLOAD {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Datagram too large",
+            ));
+        }
+
+        let mut state = self.sockets.lock();
+        let bound = state.udp.bound.get_mut(&self.local).unwrap();
+        if let Some(error) = bound.take_error() {
+            return Err(error);
+        }
+        bound.tx.push_back((to, buf.into()));
+
+        drop(state);
+        self.sockets.wake();
+        Ok(buf.len())
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.sockets.lock().udp.bound.remove(&self.local);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::Ipv4Addr, time::Duration};
+
+    use crate::{
+        proto::{
+            Protocol, ProtocolBuffer,
+            icmp::{self, Icmp, IcmpMessage, Quote, Unreachable},
+            ip::{Ip, IpHeaderWriter},
+            udp::Udp,
+        },
+        socket::stack,
+        stack::{
+            peer::Peer,
+            sim::{LinkConfig, Side, Simulation},
+        },
+    };
+
+    use super::*;
+
+    const PEER: Peer = Peer {
+        address: Ipv4Addr::new(10, 0, 0, 2),
+        port: 50000,
+        server: Ipv4Addr::new(10, 0, 0, 1),
+        server_port: 5000,
+    };
+
+    #[test]
+    fn receives_and_sends_datagrams() {
+        let sockets = Sockets::default();
+        let mut socket = UdpSocket::bind(&sockets, 5000).unwrap();
+        socket.set_nonblocking(true);
+        assert!(UdpSocket::bind(&sockets, 5000).is_err());
+
+        let client = stack(Sockets::default()).error_rate_limit(0, 0);
+        let mut sim = Simulation::new(1, LinkConfig::default(), client, stack(sockets));
+        let wait = Duration::from_millis(100);
+
+        sim.a.send(PEER.datagram(b"ping"));
+        // Nobody is bound there, the stack says so.
+        let lost = Peer {
+            server_port: 5001,
+            ..PEER
+        }
+        .datagram(b"lost");
+        sim.a.send(lost.to_vec().into());
+        sim.run_for(wait);
+
+        let mut buf = [0; 16];
+        let (read, from) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b"ping");
+        assert_eq!(from.to_string(), "10.0.0.2:50000");
+        assert!(
+            matches!(socket.recv_from(&mut buf), Err(e) if e.kind() == io::ErrorKind::WouldBlock)
+        );
+        let replies: Vec<_> = sim.trace().iter().filter(|t| t.from == Side::B).collect();
+        assert_eq!(replies.len(), 1);
+        let ip = Ip::parse(&replies[0].packet).unwrap();
+        let error = Icmp::parse(ip).unwrap();
+        assert_eq!(
+            (error.icmp_type(), error.icmp_code()),
+            (icmp::DESTINATION_UNREACHABLE, icmp::PORT_UNREACHABLE)
+        );
+        // Quoting addresses and ports, the identification is the client stack's.
+        assert_eq!(&error.payload()[12..], &lost[12..28]);
+
+        socket.send_to(b"pong", from).unwrap();
+        sim.run_for(wait);
+
+        let sent = sim.trace().iter().rfind(|t| t.from == Side::B).unwrap();
+        let (ip, payload) = etherparse::Ipv4Header::from_slice(&sent.packet).unwrap();
+        let (udp, data) = etherparse::UdpHeader::from_slice(payload).unwrap();
+        assert_eq!((udp.source_port, udp.destination_port), (5000, 50000));
+        assert_eq!(data, b"pong");
+        assert_eq!(
+            udp.checksum,
+            udp.calc_checksum_ipv4_raw(ip.source, ip.destination, data)
+                .unwrap()
+        );
+
+        let udp = Udp::parse(Ip::parse(&sent.packet).unwrap()).unwrap();
+        assert_eq!(udp.buf(), b"pong");
+    }
+
+    #[test]
+    fn large_datagrams_go_out_in_fragments() {
+        let client_sockets = Sockets::default();
+        let mut client = UdpSocket::bind(&client_sockets, 50000).unwrap();
+        client.set_nonblocking(true);
+        let sockets = Sockets::default();
+        let server = UdpSocket::bind(&sockets, 5000).unwrap();
+
+        let mut sim = Simulation::new(
+            1,
+            LinkConfig::default(),
+            stack(client_sockets),
+            stack(sockets).mtu(576),
+        );
+
+        let data: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        server
+            .send_to(&data, "10.0.0.2:50000".parse().unwrap())
+            .unwrap();
+        sim.run_for(Duration::from_millis(100));
+
+        let fragments: Vec<_> = sim.trace().iter().filter(|t| t.from == Side::B).collect();
+        assert_eq!(fragments.len(), 4);
+        assert!(fragments.iter().all(|t| t.packet.len() <= 576));
+
+        // The other side puts it back together.
+        let mut buf = [0; 4096];
+        let (read, from) = client.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..read], data);
+        assert_eq!(from.to_string(), "10.0.0.1:5000");
+    }
+
+    #[test]
+    fn refused_datagrams_fail_the_next_call() {
+        let client_sockets = Sockets::default();
+        let mut client = UdpSocket::bind(&client_sockets, 50000).unwrap();
+        client.set_nonblocking(true);
+        let mut sim = Simulation::new(
+            1,
+            LinkConfig::default(),
+            stack(client_sockets),
+            stack(Sockets::default()),
+        );
+
+        let server = "10.0.0.1:5000".parse().unwrap();
+        client.send_to(b"anyone?", server).unwrap();
+        sim.run_for(Duration::from_millis(100));
+
+        let mut buf = [0; 16];
+        let error = client.recv_from(&mut buf).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::ConnectionRefused);
+        assert!(error.to_string().contains("10.0.0.1:5000"));
+        // Reported once, the socket is usable afterwards.
+        assert_eq!(
+            client.recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+
+        // Errors about somewhere the socket never sent to are made up.
+        let never_sent = Peer {
+            address: Ipv4Addr::new(10, 0, 0, 2),
+            port: 50000,
+            server: Ipv4Addr::new(10, 0, 0, 1),
+            server_port: 5001,
+        }
+        .datagram(b"");
+        let message = IcmpMessage::DestinationUnreachable {
+            reason: Unreachable::Port,
+            quote: Quote::of(&Ip::parse(&never_sent).unwrap()),
+        };
+        let forged = IpHeaderWriter::new(
+            Ipv4Addr::new(10, 0, 0, 1),
+            Ipv4Addr::new(10, 0, 0, 2),
+            Protocol::ICMP,
+            64,
+            message.into_buf(),
+        );
+        sim.b.send(forged.into_buf());
+        sim.run_for(Duration::from_millis(100));
+        assert_eq!(
+            client.recv_from(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert!(client.send_to(b"again", server).is_ok());
+    }
+}
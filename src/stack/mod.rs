@@ -0,0 +1,707 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+};
+
+use crate::{
+    network::{
+        Handler, Timer,
+        ethernet::EthernetHandler,
+        firewall::{Action, Firewall, Rule},
+        ip::{IpHandler, Unreachable},
+        nat::Nat,
+        reassembly::Reassembler,
+        routing::{NextHop, RoutingTable},
+    },
+    proto::{
+        Layer, NetworkBuffer, ParseError, Protocol,
+        icmp::{self, Icmp, IcmpMessage, Quote, TimeExceeded},
+        ip::{self, Ip, IpHeaderWriter, IpPacket},
+        tcp::Tcp,
+        udp::Udp,
+    },
+    socket::Sockets,
+    time::{Instant, RateLimit, Timers},
+};
+
+pub mod peer;
+#[cfg(test)]
+pub mod sim;
+
+/// What Ethernet carries, unless told otherwise.
+const DEFAULT_MTU: usize = 1500;
+/// TTL of the errors we send about packets passing through.
+const ICMP_TTL: u8 = 64;
+/// ICMP errors and RSTs sent per second once the burst is used up.
+const ERROR_RATE: u32 = 100;
+/// ICMP errors and RSTs sent back to back before the rate kicks in.
+const ERROR_BURST: u32 = 50;
+
+/// Packets the stack dropped instead of handling, by reason.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Counters {
+    /// Headers that failed to parse, by the layer they failed at.
+    pub malformed: HashMap<Layer, u64>,
+    /// Checksums that didn't add up, by the layer they belong to.
+    pub bad_checksum: HashMap<Layer, u64>,
+    /// Packets to forward that no route matched.
+    pub no_route: u64,
+    /// Packets to forward whose TTL ran out.
+    pub ttl_exceeded: u64,
+    /// Packets to forward too big for the next hop that mustn't be fragmented.
+    pub too_big: u64,
+    /// Packets leaving through NAT that weren't TCP or UDP, or found no free port.
+    pub untranslatable: u64,
+    /// Packets the firewall denied or rejected.
+    pub filtered: u64,
+    /// Packets to an address that isn't ours while not forwarding, or TCP
+    /// segments and echo requests to a broadcast address.
+    pub not_ours: u64,
+    /// Packets for a protocol or port nobody here takes.
+    pub unreachable: u64,
+    /// ICMP errors and RSTs that weren't sent because of the rate limit.
+    pub rate_limited: u64,
+}
+
+/// The protocol stack without any IO attached. Packets are pushed in with
+/// `receive`, replies are pulled out with `poll_transmit` and time only
+/// moves when the caller says so, which makes it possible to drive the
+/// stack from a real device or from a simulation.
+///
+/// With a routing table the stack also forwards between several devices,
+/// each one an interface with an index of its own. Interface 0 is the
+/// one `receive` and `poll_transmit` talk to.
+pub struct Stack {
+    /// Only in TAP mode, packets are bare IP otherwise. Only ever on interface 0.
+    ethernet: Option<EthernetHandler>,
+    ip: IpHandler,
+    /// Which addresses are ours, and where the rest goes. Everything is
+    /// ours without one.
+    router: Option<RoutingTable>,
+    /// Whether packets that aren't for us are forwarded or dropped.
+    forwarding: bool,
+    /// IPv6 isn't routed, packets to anything but this aren't ours.
+    ipv6_address: Option<Ipv6Addr>,
+    /// Translates what's forwarded out of one of the interfaces.
+    nat: Option<Nat>,
+    /// Filters what arrives, whether it's for us or passing through.
+    firewall: Option<Firewall>,
+    fragments: Reassembler,
+    /// Largest IP packet a device takes, anything bigger goes out in
+    /// fragments. For interfaces without an MTU of their own.
+    mtu: usize,
+    /// Interfaces with an MTU of their own, by index.
+    interface_mtus: HashMap<usize, usize>,
+    /// Identification of the next IPv4 datagram that may be fragmented.
+    identification: u16,
+    /// Off when the device already checked checksums for us.
+    verify_checksums: bool,
+    /// Shared by every ICMP error and RST we send, as RFC 1812 asks.
+    errors: RateLimit,
+    timers: Timers<Timer>,
+    /// Packets to send, by interface.
+    transmit: Vec<VecDeque<NetworkBuffer>>,
+    counters: Counters,
+}
+
+impl Stack {
+    /// `timers` must be the same handle the handlers in `ip` schedule on.
+    pub fn new(ip: IpHandler, timers: Timers<Timer>) -> Self {
+        Self {
+            ethernet: None,
+            ip,
+            router: None,
+            forwarding: false,
+            ipv6_address: None,
+            nat: None,
+            firewall: None,
+            fragments: Reassembler::new(timers.clone()),
+            mtu: DEFAULT_MTU,
+            interface_mtus: HashMap::new(),
+            identification: 0,
+            verify_checksums: true,
+            errors: RateLimit::new(ERROR_RATE, ERROR_BURST),
+            timers,
+            transmit: vec![VecDeque::new()],
+            counters: Counters::default(),
+        }
+    }
+
+    /// Takes IPv6 packets sent to `address`.
+    pub fn ipv6_address(mut self, address: Ipv6Addr) -> Self {
+        self.ipv6_address = Some(address);
+        self
+    }
+
+    /// Speaks Ethernet on the device instead of bare IP.
+    pub fn ethernet(mut self, ethernet: EthernetHandler) -> Self {
+        self.ethernet = Some(ethernet);
+        self
+    }
+
+    /// Only takes packets to one of the table's local routes, dropping the
+    /// rest.
+    pub fn addresses(mut self, table: RoutingTable) -> Self {
+        self.transmit
+            .resize_with(table.interfaces().max(1), VecDeque::new);
+        self.router = Some(table);
+        self.forwarding = false;
+        self
+    }
+
+    /// Forwards packets that aren't for one of the table's local routes
+    /// out of the interface it picks, turning the stack into a router.
+    pub fn router(self, table: RoutingTable) -> Self {
+        let mut s = self.addresses(table);
+        s.forwarding = true;
+        s
+    }
+
+    /// Masquerades flows forwarded out of `interface` behind `address`.
+    /// Only takes effect in router mode.
+    pub fn nat(mut self, address: Ipv4Addr, interface: usize) -> Self {
+        self.nat = Some(Nat::new(address, interface, self.timers.clone()));
+        self
+    }
+
+    /// Filters arriving packets through `rules`, in order, before they're
+    /// handled or forwarded.
+    pub fn firewall(mut self, rules: Vec<Rule>, default: Action) -> Self {
+        self.firewall = Some(Firewall::new(rules, default, self.timers.clone()));
+        self
+    }
+
+    /// Sends at most `per_second` ICMP errors and RSTs a second, after
+    /// `burst` of them in a row.
+    pub fn error_rate_limit(mut self, per_second: u32, burst: u32) -> Self {
+        self.errors = RateLimit::new(per_second, burst);
+        self
+    }
+
+    /// How many packets each firewall rule decided on, in rule order.
+    pub fn rule_hits(&self) -> &[u64] {
+        self.firewall
+            .as_ref()
+            .map_or(&[], |firewall| firewall.hits())
+    }
+
+    /// The MTU of every interface that isn't given one of its own.
+    pub fn mtu(mut self, mtu: u16) -> Self {
+        self.mtu = mtu.into();
+        self.fit_tcp()
+    }
+
+    /// Fragments what goes out of `interface` to `mtu`, and asks senders
+    /// of forwarded packets that mustn't be fragmented for smaller ones.
+    pub fn interface_mtu(mut self, interface: usize, mtu: u16) -> Self {
+        self.interface_mtus.insert(interface, mtu.into());
+        self.fit_tcp()
+    }
+
+    /// TCP segments fit every interface, whichever the peer is behind.
+    fn fit_tcp(mut self) -> Self {
+        let smallest = self
+            .interface_mtus
+            .values()
+            .fold(self.mtu, |a, &b| a.min(b));
+        self.ip.tcp = self.ip.tcp.mtu(smallest.min(u16::MAX as usize) as u16);
+        self
+    }
+
+    fn mtu_of(&self, interface: usize) -> usize {
+        self.interface_mtus
+            .get(&interface)
+            .copied()
+            .unwrap_or(self.mtu)
+    }
+
+    /// Trusts the checksums of received packets instead of verifying them,
+    /// for devices that offload that.
+    pub fn trust_checksums(mut self, trust: bool) -> Self {
+        self.verify_checksums = !trust;
+        self
+    }
+
+    pub fn is_ethernet(&self) -> bool {
+        self.ethernet.is_some()
+    }
+
+    /// Packets dropped so far, by reason.
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    /// Handles a packet from the device. Malformed packets are counted and
+    /// dropped, only errors the stack can't carry on from are returned.
+    #[cfg(test)]
+    pub fn receive(&mut self, now: Instant, packet: &[u8]) -> anyhow::Result<()> {
+        self.receive_on(now, 0, packet)
+    }
+
+    /// Handles a packet from the device of `interface`.
+    pub fn receive_on(
+        &mut self,
+        now: Instant,
+        interface: usize,
+        packet: &[u8],
+    ) -> anyhow::Result<()> {
+        let Err(e) = self.process(now, interface, packet) else {
+            return Ok(());
+        };
+        let Some(error) = e.downcast_ref::<ParseError>() else {
+            return Err(e);
+        };
+
+        tracing::debug!(%error, "Dropping malformed packet");
+        *self.counters.malformed.entry(error.layer()).or_default() += 1;
+        Ok(())
+    }
+
+    fn process(&mut self, now: Instant, interface: usize, packet: &[u8]) -> anyhow::Result<()> {
+        self.timers.advance(now);
+
+        // Aliases are ours to answer ARP for, broadcasts aren't.
+        let router = self.router.as_ref();
+        let is_ours = |address| {
+            router.is_some_and(|router| router.is_local(address) && !router.is_broadcast(address))
+        };
+        let packet = match &mut self.ethernet {
+            Some(ethernet) if interface == 0 => match ethernet.receive(now, packet, is_ours)? {
+                Some(packet) => packet,
+                None => return Ok(()),
+            },
+            _ => packet,
+        };
+
+        let mut ip_header = IpPacket::parse(packet)?;
+        if self.verify_checksums
+            && let IpPacket::V4(ip) = &ip_header
+            && !ip.checksum_valid()
+        {
+            self.drop_corrupt(Layer::Ip);
+            return Ok(());
+        }
+
+        if let IpPacket::V6(_) = ip_header
+            && self
+                .ipv6_address
+                .is_none_or(|ours| ip_header.destination() != IpAddr::V6(ours))
+        {
+            self.counters.not_ours += 1;
+            tracing::debug!(destination = %ip_header.destination(), "Dropping packet for someone else");
+            return Ok(());
+        }
+
+        let next_hop = match (ip_header, &self.router) {
+            (IpPacket::V4(ip), Some(router)) => router.lookup(ip.destination()),
+            _ => Some(NextHop::Local),
+        };
+        if !self.forwarding && next_hop != Some(NextHop::Local) {
+            self.counters.not_ours += 1;
+            tracing::debug!(destination = %ip_header.destination(), "Dropping packet for someone else");
+            return Ok(());
+        }
+        let Some(next_hop) = next_hop else {
+            self.counters.no_route += 1;
+            tracing::debug!(destination = %ip_header.destination(), "No route");
+            return Ok(());
+        };
+
+        // Fragments are forwarded as they are, unless NAT or the firewall
+        // need the ports only the whole datagram has.
+        if let (NextHop::Interface(out), IpPacket::V4(ip)) = (next_hop, ip_header)
+            && self.nat.is_none()
+            && self.firewall.is_none()
+        {
+            self.forward(now, interface, out, ip);
+            return Ok(());
+        }
+
+        let datagram;
+        if let IpPacket::V4(ip) = &ip_header
+            && ip.is_fragment()
+        {
+            match self.fragments.insert(now, ip) {
+                Some(whole) => datagram = whole,
+                None => return Ok(()),
+            }
+            ip_header = IpPacket::parse(&datagram)?;
+        }
+
+        // Replies coming back through NAT go on as if they were sent to
+        // the inside address all along.
+        let mut next_hop = next_hop;
+        let translated;
+        if let IpPacket::V4(ip) = ip_header
+            && let Some(nat) = &mut self.nat
+            && interface == nat.outside()
+        {
+            let mut packet = NetworkBuffer::from(ip.packet().to_vec());
+            if nat.inbound(now, &mut packet) {
+                translated = packet;
+                let inside = Ip::parse(&translated)?;
+                ip_header = IpPacket::V4(inside);
+                let router = self.router.as_ref();
+                match router.and_then(|router| router.lookup(inside.destination())) {
+                    Some(hop) => next_hop = hop,
+                    None => {
+                        self.counters.no_route += 1;
+                        tracing::debug!(destination = %ip_header.destination(), "No route");
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        if let Some(firewall) = &mut self.firewall {
+            let action = firewall.filter(now, ip_header);
+            if action != Action::Allow {
+                self.counters.filtered += 1;
+                if action == Action::Reject {
+                    let reason = icmp::Unreachable::AdministrativelyProhibited;
+                    self.unreachable(now, interface, next_hop, ip_header, reason);
+                }
+                return Ok(());
+            }
+        }
+
+        if let (NextHop::Interface(out), IpPacket::V4(ip)) = (next_hop, ip_header) {
+            self.forward(now, interface, out, ip);
+            return Ok(());
+        }
+
+        // Connections are between two hosts, and every host on the subnet
+        // answering a ping at once is how smurf attacks work.
+        if let (IpPacket::V4(ip), Some(router)) = (ip_header, &self.router)
+            && router.is_broadcast(ip.destination())
+            && (ip.protocol() == Protocol::TCP || is_echo_request(ip))
+        {
+            self.counters.not_ours += 1;
+            tracing::debug!(destination = %ip_header.destination(), protocol = ?ip.protocol(), "Dropping packet to a broadcast address");
+            return Ok(());
+        }
+
+        if self.verify_checksums
+            && let Some(layer) = bad_checksum(ip_header)?
+        {
+            self.drop_corrupt(layer);
+            return Ok(());
+        }
+
+        if let Some(unreachable) = self.ip.unreachable(ip_header) {
+            self.counters.unreachable += 1;
+            tracing::debug!(?unreachable, destination = %ip_header.destination(), "Nobody takes packet");
+            let reason = match unreachable {
+                Unreachable::Protocol => icmp::Unreachable::Protocol,
+                // TCP gets a RST whatever the reason.
+                Unreachable::Port | Unreachable::Reset => icmp::Unreachable::Port,
+            };
+            self.unreachable(now, interface, next_hop, ip_header, reason);
+            return Ok(());
+        }
+
+        let out = self.ip.handle(ip_header)?;
+
+        if !out.is_empty() {
+            self.queue(out);
+        }
+
+        Ok(())
+    }
+
+    /// Sends a packet that arrived on interface `from` on out of `to`, one
+    /// hop closer to where it's going.
+    fn forward(&mut self, now: Instant, from: usize, to: usize, ip: Ip) {
+        if ip.ttl() <= 1 {
+            self.counters.ttl_exceeded += 1;
+            tracing::debug!(
+                source = %ip.source(),
+                destination = %ip.destination(),
+                "TTL exceeded in transit"
+            );
+            let message = IcmpMessage::TimeExceeded {
+                reason: TimeExceeded::InTransit,
+                quote: Quote::of(&ip),
+            };
+            self.report(now, from, ip, message);
+            return;
+        }
+        if to >= self.transmit.len() {
+            self.counters.no_route += 1;
+            tracing::warn!(interface = to, "Route out of an interface we don't have");
+            return;
+        }
+
+        // RFC 1191: the sender finds the path MTU by what we tell it here.
+        let next_hop_mtu = self.mtu_of(to);
+        if ip.packet().len() > next_hop_mtu && ip.dont_fragment() {
+            self.counters.too_big += 1;
+            tracing::debug!(
+                len = ip.packet().len(),
+                mtu = next_hop_mtu,
+                destination = %ip.destination(),
+                "Packet too big for the next hop"
+            );
+            let message = IcmpMessage::DestinationUnreachable {
+                reason: icmp::Unreachable::FragmentationNeeded {
+                    next_hop_mtu: next_hop_mtu.min(u16::MAX as usize) as u16,
+                },
+                quote: Quote::of(&ip),
+            };
+            self.report(now, from, ip, message);
+            return;
+        }
+
+        let mut packet = NetworkBuffer::from(ip.packet().to_vec());
+        ip::decrement_ttl(&mut packet);
+        if let Some(nat) = &mut self.nat
+            && to == nat.outside()
+            && from != to
+            && !nat.outbound(now, &mut packet)
+        {
+            self.counters.untranslatable += 1;
+            tracing::debug!(protocol = ?ip.protocol(), "Dropping packet NAT can't translate");
+            return;
+        }
+        self.transmit[to].push_back(packet);
+    }
+
+    /// Sends `message` about a packet that can't be forwarded back where it
+    /// came from, from our address on the interface it came in on.
+    fn report(&mut self, now: Instant, from: usize, ip: Ip, message: IcmpMessage) {
+        let Some(router) = &self.router else {
+            return;
+        };
+        if !icmp::may_report(&ip) {
+            return;
+        }
+        let source = router.address(from);
+        let packet = IpHeaderWriter::new(
+            source,
+            ip.source(),
+            Protocol::ICMP,
+            ICMP_TTL,
+            message.into_buf(),
+        );
+        if self.may_send_error(now) {
+            self.transmit[from].push_back(packet.into_buf());
+        }
+    }
+
+    /// Tells the sender of a packet nobody takes, or that the firewall turned
+    /// away: a RST for TCP, Destination Unreachable for `reason` for
+    /// everything else.
+    fn unreachable(
+        &mut self,
+        now: Instant,
+        interface: usize,
+        next_hop: NextHop,
+        ip_header: IpPacket,
+        reason: icmp::Unreachable,
+    ) {
+        let reply = match ip_header {
+            _ if ip_header.protocol() == Protocol::TCP => {
+                Tcp::parse(ip_header).ok().and_then(|tcp| tcp.reset())
+            }
+            // `may_report` only knows the limited broadcast, not our subnets'.
+            IpPacket::V4(ip) if icmp::may_report(&ip) && !self.is_broadcast(ip.destination()) => {
+                // From us, or from the router's side the packet came in on.
+                let source = match (next_hop, &self.router) {
+                    (NextHop::Interface(_), Some(router)) => router.address(interface),
+                    _ => ip.destination(),
+                };
+                let message = IcmpMessage::DestinationUnreachable {
+                    reason,
+                    quote: Quote::of(&ip),
+                }
+                .into_buf();
+                Some(
+                    IpHeaderWriter::new(source, ip.source(), Protocol::ICMP, ICMP_TTL, message)
+                        .into_buf(),
+                )
+            }
+            _ => None,
+        };
+        if let Some(reply) = reply
+            && self.may_send_error(now)
+        {
+            self.route(reply);
+        }
+    }
+
+    fn is_broadcast(&self, address: Ipv4Addr) -> bool {
+        self.router
+            .as_ref()
+            .is_some_and(|router| router.is_broadcast(address))
+    }
+
+    fn may_send_error(&mut self, now: Instant) -> bool {
+        let allowed = self.errors.allow(now);
+        if !allowed {
+            self.counters.rate_limited += 1;
+            tracing::debug!("ICMP error or RST rate limited");
+        }
+        allowed
+    }
+
+    /// Queues a packet of our own, letting replies to it past the firewall.
+    fn queue(&mut self, packet: NetworkBuffer) {
+        if let Some(firewall) = &mut self.firewall
+            && let Ok(ip) = IpPacket::parse(&packet)
+        {
+            firewall.outbound(self.timers.now(), ip);
+        }
+        self.route(packet);
+    }
+
+    /// Queues a packet of our own on the interface the routing table picks
+    /// for it, or interface 0 if there's no table or no route. IPv4 packets
+    /// that may be fragmented get their identification here, forwarded
+    /// ones keep theirs.
+    fn route(&mut self, mut packet: NetworkBuffer) {
+        let mut interface = 0;
+        if let Ok(IpPacket::V4(ip)) = IpPacket::parse(&packet) {
+            if let Some(router) = &self.router
+                && let Some(NextHop::Interface(out)) = router.lookup(ip.destination())
+                && out < self.transmit.len()
+            {
+                interface = out;
+            }
+            if !ip.dont_fragment() && !ip.is_fragment() {
+                ip::set_identification(&mut packet, self.identification);
+                self.identification = self.identification.wrapping_add(1);
+            }
+        }
+        self.transmit[interface].push_back(packet);
+    }
+
+    fn drop_corrupt(&mut self, layer: Layer) {
+        tracing::debug!(?layer, "Dropping packet with a bad checksum");
+        *self.counters.bad_checksum.entry(layer).or_default() += 1;
+    }
+
+    /// Queues a packet for transmission as is.
+    #[cfg(test)]
+    pub fn send(&mut self, packet: NetworkBuffer) {
+        self.queue(packet);
+    }
+
+    /// Runs every timer that is due at `now`.
+    pub fn poll(&mut self, now: Instant) {
+        self.timers.advance(now);
+        if let Some(ethernet) = &mut self.ethernet {
+            ethernet.poll(now);
+        }
+
+        for timer in self.timers.expired() {
+            let out = match timer {
+                Timer::Tcp(quad, timer) => self.ip.tcp.on_timer(quad, timer),
+                Timer::Reassembly(key) => {
+                    self.fragments.expire(key);
+                    None
+                }
+                Timer::Nat(key) => {
+                    if let Some(nat) = &mut self.nat {
+                        nat.expire(now, key);
+                    }
+                    None
+                }
+                Timer::Conntrack(key) => {
+                    if let Some(firewall) = &mut self.firewall {
+                        firewall.expire(now, key);
+                    }
+                    None
+                }
+            };
+
+            if let Some(out) = out {
+                self.queue(out);
+            }
+        }
+
+        let tcp = self.ip.tcp.poll().into_iter();
+        for out in tcp.chain(self.ip.udp.poll()).chain(self.ip.icmp.poll()) {
+            self.queue(out);
+        }
+    }
+
+    /// The next point in time `poll` has work to do.
+    pub fn poll_at(&self) -> Option<Instant> {
+        if self.ip.tcp.has_pending() || self.ip.udp.has_pending() || self.ip.icmp.has_pending() {
+            return Some(self.timers.now());
+        }
+        let arp = self.ethernet.as_ref().and_then(EthernetHandler::poll_at);
+        self.timers.next_deadline().into_iter().chain(arp).min()
+    }
+
+    /// The sockets applications use to talk through this stack.
+    pub fn sockets(&self) -> &Sockets {
+        self.ip.tcp.sockets()
+    }
+
+    #[cfg(test)]
+    pub fn poll_transmit(&mut self) -> Option<NetworkBuffer> {
+        self.poll_transmit_on(0)
+    }
+
+    /// The next packet to send on the device of `interface`.
+    pub fn poll_transmit_on(&mut self, interface: usize) -> Option<NetworkBuffer> {
+        if self.ethernet.is_none() || interface != 0 {
+            return self.next_packet(interface);
+        }
+
+        let now = self.timers.now();
+        loop {
+            if let Some(frame) = self.ethernet.as_mut()?.poll_transmit() {
+                return Some(frame);
+            }
+            let packet = self.next_packet(0)?;
+            self.ethernet.as_mut()?.send(now, packet);
+        }
+    }
+
+    /// The next packet to go out of `interface`, split up first if it
+    /// doesn't fit the MTU.
+    fn next_packet(&mut self, interface: usize) -> Option<NetworkBuffer> {
+        let mtu = self.mtu_of(interface);
+        let transmit = self.transmit.get_mut(interface)?;
+        loop {
+            let packet = transmit.pop_front()?;
+            if packet.len() <= mtu {
+                return Some(packet);
+            }
+
+            let fragments = match IpPacket::parse(&packet) {
+                Ok(IpPacket::V4(ip)) => ip.fragment(mtu),
+                Ok(IpPacket::V6(_)) => Err(anyhow::anyhow!("IPv6 packets aren't fragmented")),
+                Err(e) => Err(e.into()),
+            };
+            match fragments {
+                Ok(fragments) => {
+                    for fragment in fragments.into_iter().rev() {
+                        transmit.push_front(fragment);
+                    }
+                }
+                Err(e) => {
+                    tracing::warn!(len = packet.len(), mtu, ?e, "Dropping oversized packet")
+                }
+            }
+        }
+    }
+}
+
+/// The upper layer whose checksum doesn't add up, if any.
+fn bad_checksum(ip: IpPacket) -> Result<Option<Layer>, ParseError> {
+    let (layer, valid) = match ip.protocol() {
+        Protocol::TCP => (Layer::Tcp, Tcp::parse(ip)?.checksum_valid()),
+        Protocol::UDP => (Layer::Udp, Udp::parse(ip)?.checksum_valid()),
+        Protocol::ICMP => (Layer::Icmp, Icmp::parse(ip)?.checksum_valid()),
+        _ => return Ok(None),
+    };
+    Ok((!valid).then_some(layer))
+}
+
+fn is_echo_request(ip: Ip) -> bool {
+    ip.protocol() == Protocol::ICMP
+        && Icmp::parse(ip).is_ok_and(|message| message.icmp_type() == icmp::ECHO_REQUEST)
+}
@@ -0,0 +1,64 @@
+use std::net::{IpAddr, Ipv4Addr};
+
+use crate::proto::{
+    NetworkBuffer, Protocol,
+    ip::IpHeaderWriter,
+    tcp::{TcpControl, TcpHeaderWriter},
+};
+
+/// Builds the packets a client would send, for driving a stack by hand.
+#[derive(Debug, Clone, Copy)]
+pub struct Peer {
+    pub address: Ipv4Addr,
+    pub port: u16,
+    pub server: Ipv4Addr,
+    pub server_port: u16,
+}
+
+impl Peer {
+    pub fn segment(
+        &self,
+        control: TcpControl,
+        sequence: u32,
+        ack: u32,
+        data: &[u8],
+    ) -> NetworkBuffer {
+        let tcp = TcpHeaderWriter::new(self.port, self.server_port, sequence, ack)
+            .set(control)
+            .data(data.into())
+            .calc_checksum_for(self.client_ip(), self.server_ip())
+            .into_buf();
+
+        IpHeaderWriter::new(self.address, self.server, Protocol::TCP, 64, tcp).into_buf()
+    }
+
+    /// A SYN announcing `mss`.
+    #[cfg(test)]
+    pub fn syn(&self, sequence: u32, mss: u16) -> NetworkBuffer {
+        let tcp = TcpHeaderWriter::new(self.port, self.server_port, sequence, 0)
+            .set(TcpControl::SYN)
+            .mss(mss)
+            .calc_checksum_for(self.client_ip(), self.server_ip())
+            .into_buf();
+
+        IpHeaderWriter::new(self.address, self.server, Protocol::TCP, 64, tcp).into_buf()
+    }
+
+    fn client_ip(&self) -> IpAddr {
+        self.address.into()
+    }
+
+    fn server_ip(&self) -> IpAddr {
+        self.server.into()
+    }
+
+    #[cfg(test)]
+    pub fn datagram(&self, data: &[u8]) -> NetworkBuffer {
+        let udp = crate::proto::udp::UdpHeaderWriter::new(self.port, self.server_port)
+            .data(data.into())
+            .calc_checksum_for(self.client_ip(), self.server_ip())
+            .into_buf();
+
+        IpHeaderWriter::new(self.address, self.server, Protocol::UDP, 64, udp).into_buf()
+    }
+}
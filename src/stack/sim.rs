@@ -0,0 +1,774 @@
+use std::{collections::VecDeque, time::Duration};
+
+use crate::{proto::NetworkBuffer, time::Instant};
+
+use super::Stack;
+
+/// xorshift64*, enough randomness to drop packets and small enough that a
+/// run is reproducible from nothing but its seed.
+pub struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        // A zero state would only ever produce zeroes.
+        Self(seed ^ 0x9e37_79b9_7f4a_7c15)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns true with the given probability.
+    pub fn chance(&mut self, probability: f64) -> bool {
+        let unit = (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64;
+        unit < probability
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LinkConfig {
+    /// Probability of a packet being lost, between 0 and 1.
+    pub loss: f64,
+    pub latency: Duration,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            loss: 0.0,
+            latency: Duration::from_millis(10),
+        }
+    }
+}
+
+/// One direction of the link between the two stacks.
+struct Link {
+    config: LinkConfig,
+    in_flight: VecDeque<(Instant, NetworkBuffer)>,
+}
+
+impl Link {
+    fn new(config: LinkConfig) -> Self {
+        Self {
+            config,
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    fn next_arrival(&self) -> Option<Instant> {
+        self.in_flight.front().map(|(at, _)| *at)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Side {
+    A,
+    B,
+}
+
+/// A packet put on the link, and whether the link let it through.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Transmission {
+    pub at: Instant,
+    pub from: Side,
+    pub delivered: bool,
+    pub packet: Vec<u8>,
+}
+
+/// Two stacks connected by a lossy link, running on a virtual clock. Time
+/// jumps straight from one event to the next, so simulating minutes of
+/// timeouts takes no real time at all.
+pub struct Simulation {
+    pub a: Stack,
+    pub b: Stack,
+    now: Instant,
+    rng: Rng,
+    a_to_b: Link,
+    b_to_a: Link,
+    trace: Vec<Transmission>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64, link: LinkConfig, a: Stack, b: Stack) -> Self {
+        Self {
+            a,
+            b,
+            now: Instant::ZERO,
+            rng: Rng::new(seed),
+            a_to_b: Link::new(link),
+            b_to_a: Link::new(link),
+            trace: vec![],
+        }
+    }
+
+    /// Every packet either stack has sent so far.
+    pub fn trace(&self) -> &[Transmission] {
+        &self.trace
+    }
+
+    pub fn run_until(&mut self, deadline: Instant) {
+        loop {
+            self.transmit();
+
+            let next = [
+                self.a.poll_at(),
+                self.b.poll_at(),
+                self.a_to_b.next_arrival(),
+                self.b_to_a.next_arrival(),
+            ]
+            .into_iter()
+            .flatten()
+            .min();
+
+            match next {
+                Some(at) if at <= deadline => self.step(at.max(self.now)),
+                _ => {
+                    self.step(deadline.max(self.now));
+                    self.transmit();
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        self.run_until(self.now + duration);
+    }
+
+    fn step(&mut self, now: Instant) {
+        self.now = now;
+
+        deliver(now, &mut self.a_to_b, &mut self.b);
+        deliver(now, &mut self.b_to_a, &mut self.a);
+
+        self.a.poll(now);
+        self.b.poll(now);
+    }
+
+    /// Moves everything the stacks want to send onto the link.
+    fn transmit(&mut self) {
+        let sides = [
+            (Side::A, &mut self.a, &mut self.a_to_b),
+            (Side::B, &mut self.b, &mut self.b_to_a),
+        ];
+
+        for (side, stack, link) in sides {
+            while let Some(packet) = stack.poll_transmit() {
+                let delivered = !self.rng.chance(link.config.loss);
+                self.trace.push(Transmission {
+                    at: self.now,
+                    from: side,
+                    delivered,
+                    packet: packet.to_vec(),
+                });
+
+                if delivered {
+                    link.in_flight
+                        .push_back((self.now + link.config.latency, packet));
+                }
+            }
+        }
+    }
+}
+
+fn deliver(now: Instant, link: &mut Link, stack: &mut Stack) {
+    while link.next_arrival().is_some_and(|at| at <= now) {
+        let (_, packet) = link.in_flight.pop_front().unwrap();
+        if let Err(e) = stack.receive(now, &packet) {
+            tracing::warn!(?e, "Simulated stack rejected packet");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        network::{
+            firewall::Action,
+            http::HttpHandler,
+            icmp::IcmpHandler,
+            ip::IpHandler,
+            routing::RoutingTable,
+            tcp::{Isn, TcpHandler},
+            udp::UdpHandler,
+        },
+        proto::{
+            Layer, Protocol,
+            icmp::{self, Icmp},
+            ip::{self, Ip, IpHeaderWriter, IpPacket},
+            ipv6::Ipv6HeaderWriter,
+            tcp::{Tcp, TcpControl},
+            udp::UdpHeaderWriter,
+        },
+        socket::{Sockets, UdpSocket},
+        stack::{bad_checksum, peer::Peer},
+        time::Timers,
+    };
+
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+
+    use super::*;
+
+    const PEER: Peer = Peer {
+        address: Ipv4Addr::new(10, 0, 0, 2),
+        port: 50000,
+        server: Ipv4Addr::new(10, 0, 0, 1),
+        server_port: 3000,
+    };
+
+    fn stack() -> Stack {
+        let timers = Timers::default();
+        let sockets = Sockets::default();
+        let ip = IpHandler {
+            icmp: IcmpHandler::new(timers.clone(), sockets.clone()),
+            udp: UdpHandler::new(PEER.server, sockets.clone()),
+            tcp: TcpHandler::new(timers.clone(), sockets)
+                .isn(Isn::Fixed(0))
+                .listen(3000, HttpHandler::none()),
+        };
+        Stack::new(ip, timers)
+    }
+
+    /// A stack that packets are injected into by hand. It has no connections
+    /// of its own, so it would answer replies to them with RSTs otherwise.
+    fn client() -> Stack {
+        stack().error_rate_limit(0, 0)
+    }
+
+    fn segment(control: TcpControl, sequence: u32, ack: u32) -> NetworkBuffer {
+        PEER.segment(control, sequence, ack, &[])
+    }
+
+    fn controls_from(sim: &Simulation, side: Side) -> Vec<(Instant, TcpControl)> {
+        sim.trace()
+            .iter()
+            .filter(|t| t.from == side)
+            .map(|t| {
+                (
+                    t.at,
+                    Tcp::parse(Ip::parse(&t.packet).unwrap()).unwrap().control(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn delayed_fin_fires_and_retransmits_on_virtual_clock() {
+        let mut sim = Simulation::new(1, LinkConfig::default(), client(), stack());
+
+        sim.a.send(segment(TcpControl::SYN, 41, 0));
+        sim.run_for(Duration::from_millis(100));
+        sim.a.send(segment(TcpControl::ACK, 42, 1));
+        sim.a
+            .send(segment(TcpControl::FIN | TcpControl::ACK, 42, 1));
+        sim.run_for(Duration::from_millis(500));
+
+        let ms = Instant::from_millis;
+        let fin = TcpControl::FIN | TcpControl::ACK;
+        assert_eq!(
+            controls_from(&sim, Side::B),
+            [
+                (ms(10), TcpControl::SYN | TcpControl::ACK),
+                (ms(110), TcpControl::ACK),
+            ]
+        );
+
+        // Nobody acks the FIN, so it goes out again with a doubling timeout.
+        sim.run_for(Duration::from_secs(5));
+        assert_eq!(
+            controls_from(&sim, Side::B)[2..],
+            [(ms(1110), fin), (ms(2110), fin), (ms(4110), fin)]
+        );
+    }
+
+    #[test]
+    fn wrong_acks_of_the_syn_are_reset_and_the_handshake_goes_on() {
+        let mut sim = Simulation::new(1, LinkConfig::default(), client(), stack());
+
+        sim.a.send(segment(TcpControl::SYN, 41, 0));
+        sim.run_for(Duration::from_millis(100));
+        sim.a.send(segment(TcpControl::ACK, 42, 7));
+        sim.run_for(Duration::from_millis(100));
+        sim.a.send(segment(TcpControl::ACK, 42, 1));
+        sim.a
+            .send(segment(TcpControl::FIN | TcpControl::ACK, 42, 1));
+        sim.run_for(Duration::from_millis(100));
+
+        let ms = Instant::from_millis;
+        assert_eq!(
+            controls_from(&sim, Side::B),
+            [
+                (ms(10), TcpControl::SYN | TcpControl::ACK),
+                (ms(110), TcpControl::RST),
+                (ms(210), TcpControl::ACK),
+            ]
+        );
+        let reset = &sim.trace()[3].packet;
+        let reset = Tcp::parse(Ip::parse(reset).unwrap()).unwrap();
+        assert_eq!(reset.sequence_number(), 7);
+    }
+
+    #[test]
+    fn unanswered_keepalives_drop_the_connection() {
+        let mut sim = Simulation::new(1, LinkConfig::default(), client(), stack());
+
+        sim.a.send(segment(TcpControl::SYN, 41, 0));
+        sim.run_for(Duration::from_millis(100));
+        sim.a.send(segment(TcpControl::ACK, 42, 1));
+        sim.run_for(Duration::from_secs(600));
+
+        let ms = Instant::from_millis;
+        assert_eq!(
+            controls_from(&sim, Side::B),
+            [
+                (ms(10), TcpControl::SYN | TcpControl::ACK),
+                (ms(60_110), TcpControl::ACK),
+                (ms(70_110), TcpControl::ACK),
+                (ms(80_110), TcpControl::ACK),
+            ]
+        );
+        // Dropping the connection cancelled its idle timer along with everything else.
+        assert_eq!(sim.b.poll_at(), None);
+    }
+
+    #[test]
+    fn lossy_runs_are_reproducible_from_seed() {
+        let run = |seed| {
+            let link = LinkConfig {
+                loss: 0.5,
+                ..Default::default()
+            };
+            let mut sim = Simulation::new(seed, link, client(), stack());
+            for sequence in 0..32 {
+                sim.a.send(segment(TcpControl::SYN, sequence * 100, 0));
+                sim.run_for(Duration::from_millis(50));
+            }
+            sim.trace().to_vec()
+        };
+
+        let first = run(7);
+        assert_eq!(first, run(7));
+        assert!(first.iter().any(|t| !t.delivered));
+        assert!(first.iter().any(|t| t.delivered));
+        assert_ne!(first, run(8));
+    }
+
+    #[test]
+    fn malformed_packets_are_counted_and_dropped() {
+        let mut stack = stack();
+        let now = Instant::from_millis(1);
+
+        // Total length past the end of what arrived.
+        let mut short = segment(TcpControl::SYN, 0, 0);
+        short.truncate(30);
+        // A data offset pointing into the fixed header.
+        let mut offset = segment(TcpControl::SYN, 0, 0);
+        offset[32] = 2 << 4;
+        // A UDP length shorter than its own header.
+        let mut length = PEER.datagram(b"data");
+        length[24..26].copy_from_slice(&4u16.to_be_bytes());
+
+        for packet in [&short, &offset, &length, &NetworkBuffer::empty()] {
+            stack.receive(now, packet).unwrap();
+        }
+        assert!(stack.poll_transmit().is_none());
+
+        let malformed = &stack.counters().malformed;
+        assert_eq!(malformed.get(&Layer::Ip), Some(&2));
+        assert_eq!(malformed.get(&Layer::Tcp), Some(&1));
+        assert_eq!(malformed.get(&Layer::Udp), Some(&1));
+
+        // None of it got in the way of a well formed SYN.
+        stack.receive(now, &segment(TcpControl::SYN, 0, 0)).unwrap();
+        assert!(stack.poll_transmit().is_some());
+    }
+
+    #[test]
+    fn corrupt_packets_are_dropped_unless_checksums_are_trusted() {
+        let now = Instant::from_millis(1);
+        let mut header = segment(TcpControl::SYN, 0, 0);
+        header[8] -= 1;
+        let mut payload = segment(TcpControl::SYN, 0, 0);
+        payload[24] ^= 0xff;
+
+        let mut verifying = stack();
+        verifying.receive(now, &header).unwrap();
+        verifying.receive(now, &payload).unwrap();
+        assert!(verifying.poll_transmit().is_none());
+        let bad_checksum = &verifying.counters().bad_checksum;
+        assert_eq!(bad_checksum.get(&Layer::Ip), Some(&1));
+        assert_eq!(bad_checksum.get(&Layer::Tcp), Some(&1));
+
+        // With offload the device vouches for them, so the SYN gets answered.
+        let mut trusting = stack().trust_checksums(true);
+        trusting.receive(now, &payload).unwrap();
+        assert!(trusting.poll_transmit().is_some());
+        assert!(trusting.counters().bad_checksum.is_empty());
+    }
+
+    #[test]
+    fn routers_forward_and_report_expired_ttls() {
+        let now = Instant::from_millis(1);
+        let table = RoutingTable::default()
+            .interface(Ipv4Addr::new(10, 0, 0, 1), "10.0.0.0/24".parse().unwrap())
+            .interface(Ipv4Addr::new(10, 0, 1, 1), "10.0.1.0/24".parse().unwrap());
+        let mut router = stack().router(table);
+        let across = Peer {
+            server: Ipv4Addr::new(10, 0, 1, 2),
+            ..PEER
+        };
+
+        let datagram = across.datagram(b"hello");
+        router.receive_on(now, 0, &datagram).unwrap();
+        assert!(router.poll_transmit_on(0).is_none());
+        let forwarded = router.poll_transmit_on(1).unwrap();
+        let ip = Ip::parse(&forwarded).unwrap();
+        assert_eq!(ip.ttl(), 63);
+        assert!(ip.checksum_valid());
+        assert_eq!(ip.remainder(), Ip::parse(&datagram).unwrap().remainder());
+
+        // Packets for the router itself are still answered.
+        router
+            .receive_on(now, 0, &segment(TcpControl::SYN, 0, 0))
+            .unwrap();
+        assert!(router.poll_transmit_on(0).is_some());
+        assert!(router.poll_transmit_on(1).is_none());
+
+        let mut expiring = across.datagram(b"hello");
+        expiring[8] = 1;
+        ip::write_header_checksum(&mut expiring[..20]);
+        router.receive_on(now, 0, &expiring).unwrap();
+        assert!(router.poll_transmit_on(1).is_none());
+        let error = router.poll_transmit_on(0).unwrap();
+        let ip = Ip::parse(&error).unwrap();
+        assert_eq!(ip.source(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(ip.destination(), PEER.address);
+        let message = Icmp::parse(ip).unwrap();
+        assert_eq!(message.icmp_type(), icmp::TIME_EXCEEDED);
+        assert!(message.checksum_valid());
+        assert_eq!(&ip.remainder()[8..], &expiring[..28]);
+        assert_eq!(router.counters().ttl_exceeded, 1);
+    }
+
+    #[test]
+    fn routers_fragment_for_smaller_links_or_ask_for_smaller_packets() {
+        let now = Instant::from_millis(1);
+        let table = RoutingTable::default()
+            .interface(Ipv4Addr::new(10, 0, 0, 1), "10.0.0.0/24".parse().unwrap())
+            .interface(Ipv4Addr::new(10, 0, 1, 1), "10.0.1.0/24".parse().unwrap());
+        let mut router = stack().router(table).interface_mtu(1, 576);
+        let across = Peer {
+            server: Ipv4Addr::new(10, 0, 1, 2),
+            ..PEER
+        };
+
+        router
+            .receive_on(now, 0, &across.datagram(&[7; 1000]))
+            .unwrap();
+        let mut fragments = 0;
+        while let Some(fragment) = router.poll_transmit_on(1) {
+            assert!(fragment.len() <= 576);
+            fragments += 1;
+        }
+        assert_eq!(fragments, 2);
+
+        let mut unfragmentable = across.datagram(&[7; 1000]);
+        unfragmentable[6] |= 0x40;
+        ip::write_header_checksum(&mut unfragmentable[..20]);
+        router.receive_on(now, 0, &unfragmentable).unwrap();
+        assert!(router.poll_transmit_on(1).is_none());
+        let error = router.poll_transmit_on(0).unwrap();
+        let ip = Ip::parse(&error).unwrap();
+        assert_eq!(ip.source(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(ip.destination(), PEER.address);
+        assert!(matches!(
+            Icmp::parse(ip).unwrap().message().unwrap(),
+            icmp::IcmpMessage::DestinationUnreachable {
+                reason: icmp::Unreachable::FragmentationNeeded { next_hop_mtu: 576 },
+                ..
+            }
+        ));
+        assert_eq!(router.counters().too_big, 1);
+    }
+
+    #[test]
+    fn masquerades_flows_leaving_through_nat() {
+        let now = Instant::from_millis(1);
+        let public = Ipv4Addr::new(10, 0, 1, 1);
+        let table = RoutingTable::default()
+            .interface(Ipv4Addr::new(10, 0, 0, 1), "10.0.0.0/24".parse().unwrap())
+            .interface(public, "10.0.1.0/24".parse().unwrap());
+        let mut router = stack().router(table).nat(public, 1);
+        let across = Peer {
+            server: Ipv4Addr::new(10, 0, 1, 2),
+            server_port: 53,
+            ..PEER
+        };
+
+        router
+            .receive_on(now, 0, &across.datagram(b"query"))
+            .unwrap();
+        let out = router.poll_transmit_on(1).unwrap();
+        let ip = Ip::parse(&out).unwrap();
+        assert_eq!(ip.source(), public);
+        assert!(bad_checksum(IpPacket::V4(ip)).unwrap().is_none());
+        let port = crate::utils::read_u16(ip.remainder());
+
+        let answer = Peer {
+            address: across.server,
+            port: 53,
+            server: public,
+            server_port: port,
+        };
+        router
+            .receive_on(now, 1, &answer.datagram(b"answer"))
+            .unwrap();
+        let back = router.poll_transmit_on(0).unwrap();
+        let ip = Ip::parse(&back).unwrap();
+        assert_eq!(ip.destination(), PEER.address);
+        assert_eq!(crate::utils::read_u16(&ip.remainder()[2..]), PEER.port);
+        assert!(bad_checksum(IpPacket::V4(ip)).unwrap().is_none());
+
+        // Only TCP and UDP have ports to translate.
+        let mut other = across.datagram(b"query");
+        other[9] = 47;
+        ip::write_header_checksum(&mut other[..20]);
+        router.receive_on(now, 0, &other).unwrap();
+        assert!(router.poll_transmit_on(1).is_none());
+        assert_eq!(router.counters().untranslatable, 1);
+    }
+
+    #[test]
+    fn firewall_rules_decide_and_replies_pass() {
+        let now = Instant::from_millis(1);
+        let rules = serde_json::from_str(
+            r#"[
+                { "action": "reject", "protocol": "tcp", "destination_port": 8080 },
+                { "action": "allow", "protocol": "tcp", "destination_port": 3000 },
+                { "action": "reject", "protocol": "udp", "destination_port": "1-1024" }
+            ]"#,
+        )
+        .unwrap();
+        let mut stack = stack().firewall(rules, Action::Deny);
+
+        stack.receive(now, &segment(TcpControl::SYN, 0, 0)).unwrap();
+        let reply = stack.poll_transmit().unwrap();
+        let reply = Tcp::parse(IpPacket::parse(&reply).unwrap()).unwrap();
+        assert_eq!(reply.control(), TcpControl::SYN | TcpControl::ACK);
+
+        let closed = Peer {
+            server_port: 8080,
+            ..PEER
+        };
+        stack
+            .receive(now, &closed.segment(TcpControl::SYN, 41, 0, &[]))
+            .unwrap();
+        let reset = stack.poll_transmit().unwrap();
+        let reset = Tcp::parse(IpPacket::parse(&reset).unwrap()).unwrap();
+        assert_eq!(reset.control(), TcpControl::RST | TcpControl::ACK);
+        assert_eq!(reset.ack_number(), 42);
+        assert!(reset.checksum_valid());
+
+        let dns = Peer {
+            server_port: 53,
+            ..PEER
+        };
+        let query = dns.datagram(b"query");
+        stack.receive(now, &query).unwrap();
+        let error = stack.poll_transmit().unwrap();
+        let ip = Ip::parse(&error).unwrap();
+        assert_eq!(ip.source(), PEER.server);
+        let message = Icmp::parse(ip).unwrap();
+        assert_eq!(
+            (message.icmp_type(), message.icmp_code()),
+            (
+                icmp::DESTINATION_UNREACHABLE,
+                icmp::ADMINISTRATIVELY_PROHIBITED
+            )
+        );
+        assert_eq!(&ip.remainder()[8..], &query[..28]);
+
+        // Nothing allows UDP to high ports, unless we started the flow.
+        let high = Peer {
+            server_port: 5000,
+            ..PEER
+        };
+        stack.receive(now, &high.datagram(b"hello")).unwrap();
+        assert!(stack.poll_transmit().is_none());
+        let ours = Peer {
+            address: PEER.server,
+            port: 5000,
+            server: PEER.address,
+            server_port: PEER.port,
+        };
+        stack.send(ours.datagram(b"hello"));
+        assert!(stack.poll_transmit().is_some());
+        stack.receive(now, &high.datagram(b"hello")).unwrap();
+
+        assert_eq!(stack.counters().filtered, 3);
+        assert_eq!(stack.rule_hits(), [1, 1, 1]);
+    }
+
+    #[test]
+    fn answers_only_on_local_addresses() {
+        let now = Instant::from_millis(1);
+        let alias = Ipv4Addr::new(10, 0, 0, 5);
+        let table = RoutingTable::default()
+            .interface(PEER.server, "10.0.0.0/24".parse().unwrap())
+            .alias(alias);
+        let timers = Timers::default();
+        let sockets = Sockets::default();
+        let ip = IpHandler {
+            icmp: IcmpHandler::new(timers.clone(), sockets.clone()),
+            udp: UdpHandler::new(PEER.server, sockets.clone()),
+            tcp: TcpHandler::new(timers.clone(), sockets.clone())
+                .listen(3000, HttpHandler::none())
+                .listen_on(SocketAddr::new(alias.into(), 8080), HttpHandler::none()),
+        };
+        let mut stack = Stack::new(ip, timers).addresses(table);
+        let mut everywhere = UdpSocket::bind(&sockets, 7).unwrap();
+        everywhere.set_nonblocking(true);
+        let only_alias = UdpSocket::bind_addr(&sockets, SocketAddr::new(alias.into(), 9)).unwrap();
+
+        let to = |server: [u8; 4], server_port| Peer {
+            server: Ipv4Addr::from(server),
+            server_port,
+            ..PEER
+        };
+        let mut syn_ack_from = |peer: Peer| {
+            stack
+                .receive(now, &peer.segment(TcpControl::SYN, 0, 0, &[]))
+                .unwrap();
+            let reply = stack.poll_transmit().unwrap();
+            let ip = Ip::parse(&reply).unwrap();
+            assert_eq!(
+                Tcp::parse(ip).unwrap().control(),
+                TcpControl::SYN | TcpControl::ACK
+            );
+            ip.source()
+        };
+        assert_eq!(syn_ack_from(PEER), PEER.server);
+        assert_eq!(syn_ack_from(to([10, 0, 0, 5], 3000)), alias);
+        assert_eq!(syn_ack_from(to([10, 0, 0, 5], 8080)), alias);
+
+        // Bound to the alias only.
+        let wrong_address = to([10, 0, 0, 1], 8080).segment(TcpControl::SYN, 0, 0, &[]);
+        stack.receive(now, &wrong_address).unwrap();
+        let reset = stack.poll_transmit().unwrap();
+        let reset = Tcp::parse(IpPacket::parse(&reset).unwrap()).unwrap();
+        assert!(reset.control().contains(TcpControl::RST));
+
+        for foreign in [to([10, 0, 0, 9], 3000), to([10, 0, 0, 255], 3000)] {
+            stack
+                .receive(now, &foreign.segment(TcpControl::SYN, 0, 0, &[]))
+                .unwrap();
+            assert!(stack.poll_transmit().is_none());
+        }
+        assert_eq!(stack.counters().not_ours, 2);
+
+        // Datagrams to the broadcast address are ours though.
+        stack
+            .receive(now, &to([10, 0, 0, 255], 7).datagram(b"anyone?"))
+            .unwrap();
+        let mut buf = [0; 16];
+        let (read, _) = everywhere.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b"anyone?");
+
+        only_alias
+            .send_to(b"hello", SocketAddr::new(PEER.address.into(), 50000))
+            .unwrap();
+        stack.poll(now);
+        let sent = stack.poll_transmit().unwrap();
+        assert_eq!(Ip::parse(&sent).unwrap().source(), alias);
+    }
+
+    #[test]
+    fn ipv6_packets_are_ours_only_at_our_address() {
+        let ours = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 1);
+        let client = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 2);
+        let timers = Timers::default();
+        let sockets = Sockets::default();
+        let ip = IpHandler {
+            icmp: IcmpHandler::new(timers.clone(), sockets.clone()),
+            udp: UdpHandler::new(PEER.server, sockets.clone()).ipv6(ours),
+            tcp: TcpHandler::new(timers.clone(), sockets.clone()),
+        };
+        let mut stack = Stack::new(ip, timers).ipv6_address(ours);
+        let mut socket = UdpSocket::bind(&sockets, 7).unwrap();
+        socket.set_nonblocking(true);
+
+        let datagram = |to: Ipv6Addr, data: &[u8]| {
+            let udp = UdpHeaderWriter::new(50000, 7)
+                .data(data.into())
+                .calc_checksum_for(client.into(), to.into())
+                .into_buf();
+            Ipv6HeaderWriter::new(client, to, Protocol::UDP, 64, udp).into_buf()
+        };
+        let now = Instant::from_millis(1);
+        let elsewhere = Ipv6Addr::new(0xfd00, 0, 0, 0, 0, 0, 0, 9);
+        stack.receive(now, &datagram(elsewhere, b"lost")).unwrap();
+        stack.receive(now, &datagram(ours, b"found")).unwrap();
+
+        let mut buf = [0; 16];
+        let (read, _) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(&buf[..read], b"found");
+        assert!(socket.recv_from(&mut buf).is_err());
+        assert_eq!(stack.counters().not_ours, 1);
+    }
+
+    #[test]
+    fn unreachable_protocols_and_ports_are_reported_within_rate() {
+        let now = Instant::from_millis(1);
+        let table = RoutingTable::default().interface(PEER.server, "10.0.0.0/24".parse().unwrap());
+        let mut stack = stack().error_rate_limit(1, 2).addresses(table);
+
+        let gre = IpHeaderWriter::new(
+            PEER.address,
+            PEER.server,
+            Protocol::Unknown(47),
+            64,
+            vec![0; 8].into(),
+        )
+        .into_buf();
+        stack.receive(now, &gre).unwrap();
+        let error = stack.poll_transmit().unwrap();
+        let ip = Ip::parse(&error).unwrap();
+        assert_eq!((ip.source(), ip.destination()), (PEER.server, PEER.address));
+        let message = Icmp::parse(ip).unwrap();
+        assert_eq!(
+            (message.icmp_type(), message.icmp_code()),
+            (icmp::DESTINATION_UNREACHABLE, icmp::PROTOCOL_UNREACHABLE)
+        );
+        assert_eq!(message.payload(), &gre[..28]);
+
+        let closed = Peer {
+            server_port: 9,
+            ..PEER
+        };
+        // Nobody answers broadcasts, with a port or anything else.
+        let broadcast = Peer {
+            server: Ipv4Addr::new(10, 0, 0, 255),
+            ..closed
+        };
+        stack.receive(now, &broadcast.datagram(b"anyone?")).unwrap();
+        assert!(stack.poll_transmit().is_none());
+
+        stack.receive(now, &closed.datagram(b"one")).unwrap();
+        let error = stack.poll_transmit().unwrap();
+        let message = Icmp::parse(Ip::parse(&error).unwrap()).unwrap();
+        assert_eq!(message.icmp_code(), icmp::PORT_UNREACHABLE);
+
+        // The burst is used up, the next error has to wait a second.
+        stack.receive(now, &closed.datagram(b"two")).unwrap();
+        assert!(stack.poll_transmit().is_none());
+        stack
+            .receive(Instant::from_millis(1001), &closed.datagram(b"three"))
+            .unwrap();
+        assert!(stack.poll_transmit().is_some());
+        assert_eq!(stack.counters().unreachable, 5);
+        assert_eq!(stack.counters().rate_limited, 1);
+    }
+}
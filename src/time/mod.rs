@@ -0,0 +1,126 @@
+use std::{
+    ops::{Add, Sub},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+mod rate;
+mod wheel;
+
+pub use rate::RateLimit;
+pub use wheel::{TimerId, TimerWheel};
+
+/// A point in time on the stack's own clock, counted in microseconds from
+/// whenever the stack was started. Real runs derive it from the OS clock,
+/// simulations advance it by hand.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant {
+    micros: u64,
+}
+
+impl Instant {
+    pub const ZERO: Instant = Instant { micros: 0 };
+
+    pub fn from_micros(micros: u64) -> Self {
+        Self { micros }
+    }
+
+    #[cfg(test)]
+    pub fn from_millis(millis: u64) -> Self {
+        Self::from_micros(millis * 1000)
+    }
+
+    pub fn micros(&self) -> u64 {
+        self.micros
+    }
+
+    pub fn saturating_duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_micros(self.micros.saturating_sub(earlier.micros))
+    }
+}
+
+impl From<Duration> for Instant {
+    fn from(value: Duration) -> Self {
+        Self::from_micros(value.as_micros() as u64)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, rhs: Duration) -> Self::Output {
+        Self::from_micros(self.micros + rhs.as_micros() as u64)
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, rhs: Instant) -> Self::Output {
+        self.saturating_duration_since(rhs)
+    }
+}
+
+/// The stack's clock and its timers. Handlers hold a clone and schedule
+/// through it, the `Stack` advances the clock and dispatches whatever has
+/// come due.
+pub struct Timers<T> {
+    inner: Arc<Mutex<TimersInner<T>>>,
+}
+
+struct TimersInner<T> {
+    now: Instant,
+    wheel: TimerWheel<T>,
+}
+
+impl<T> Clone for Timers<T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<T> Default for Timers<T> {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(TimersInner {
+                now: Instant::ZERO,
+                wheel: TimerWheel::default(),
+            })),
+        }
+    }
+}
+
+impl<T> Timers<T> {
+    pub fn now(&self) -> Instant {
+        self.inner.lock().unwrap().now
+    }
+
+    /// Moves the clock forward. Time never goes backwards.
+    pub fn advance(&self, now: Instant) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.now = inner.now.max(now);
+    }
+
+    pub fn schedule(&self, delay: Duration, value: T) -> TimerId {
+        let mut inner = self.inner.lock().unwrap();
+        let deadline = inner.now + delay;
+        inner.wheel.schedule(deadline, value)
+    }
+
+    pub fn cancel(&self, id: TimerId) -> Option<T> {
+        self.inner.lock().unwrap().wheel.cancel(id)
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.inner.lock().unwrap().wheel.next_deadline()
+    }
+
+    /// Removes and returns every timer that is due at the current time.
+    pub fn expired(&self) -> Vec<T> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = inner.now;
+        inner.wheel.expire(now)
+    }
+}
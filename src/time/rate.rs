@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use super::Instant;
+
+/// A token bucket: `burst` events back to back, then one per interval as
+/// tokens are earned back.
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    /// Time it takes to earn one token back.
+    interval: Duration,
+    burst: u32,
+    tokens: u32,
+    /// When `tokens` was last topped up.
+    refilled: Instant,
+}
+
+impl RateLimit {
+    /// A rate of zero never earns tokens back, nothing gets past at all
+    /// without a burst.
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        Self {
+            interval: match per_second {
+                0 => Duration::MAX,
+                _ => Duration::from_secs(1) / per_second,
+            },
+            burst,
+            tokens: burst,
+            refilled: Instant::ZERO,
+        }
+    }
+
+    /// Takes a token if there is one, after adding those earned since the last time.
+    pub fn allow(&mut self, now: Instant) -> bool {
+        let earned = (now - self.refilled).as_micros() / self.interval.as_micros().max(1);
+        if earned > 0 {
+            self.tokens = self.burst.min(self.tokens.saturating_add(earned as u32));
+            self.refilled = match self.tokens {
+                tokens if tokens == self.burst => now,
+                _ => self.refilled + self.interval * earned as u32,
+            };
+        }
+
+        if self.tokens == 0 {
+            return false;
+        }
+        self.tokens -= 1;
+        true
+    }
+}
@@ -0,0 +1,156 @@
+use std::{collections::HashMap, time::Duration};
+
+use super::Instant;
+
+const DEFAULT_TICK: Duration = Duration::from_millis(10);
+const DEFAULT_SLOTS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct TimerId(u64);
+
+struct Entry<T> {
+    id: u64,
+    deadline: Instant,
+    value: T,
+}
+
+/// A hashed timing wheel. Timers are bucketed by the tick their deadline
+/// falls on, so scheduling and cancelling are cheap and expiring only has to
+/// look at the buckets the clock moved over. Deadlines further out than one
+/// revolution simply stay in their bucket until their turn comes around.
+pub struct TimerWheel<T> {
+    tick: Duration,
+    slots: Vec<Vec<Entry<T>>>,
+    // Every tick before this one has been expired.
+    current_tick: u64,
+    next_id: u64,
+    // Timer id to the slot it lives in, for cancelling.
+    slot_of: HashMap<u64, usize>,
+}
+
+impl<T> Default for TimerWheel<T> {
+    fn default() -> Self {
+        Self::new(DEFAULT_TICK, DEFAULT_SLOTS)
+    }
+}
+
+impl<T> TimerWheel<T> {
+    pub fn new(tick: Duration, slots: usize) -> Self {
+        Self {
+            tick,
+            slots: (0..slots).map(|_| Vec::new()).collect(),
+            current_tick: 0,
+            next_id: 0,
+            slot_of: HashMap::new(),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.slot_of.len()
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.slot_of.is_empty()
+    }
+
+    pub fn schedule(&mut self, deadline: Instant, value: T) -> TimerId {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        // Deadlines in the past go into the current slot and fire on the next expire.
+        let tick = self.tick_of(deadline).max(self.current_tick);
+        let slot = self.slot_index(tick);
+        self.slots[slot].push(Entry {
+            id,
+            deadline,
+            value,
+        });
+        self.slot_of.insert(id, slot);
+
+        TimerId(id)
+    }
+
+    /// Cancels a timer, returning its value if it had not fired yet.
+    pub fn cancel(&mut self, id: TimerId) -> Option<T> {
+        let slot = self.slot_of.remove(&id.0)?;
+        let entries = &mut self.slots[slot];
+        let index = entries.iter().position(|e| e.id == id.0)?;
+        Some(entries.swap_remove(index).value)
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.slots.iter().flatten().map(|e| e.deadline).min()
+    }
+
+    /// Removes every timer with a deadline at or before `now`, in deadline order.
+    pub fn expire(&mut self, now: Instant) -> Vec<T> {
+        let now_tick = self.tick_of(now);
+        if now_tick < self.current_tick {
+            return vec![];
+        }
+
+        // Past one full revolution every slot has to be looked at anyway.
+        let ticks = (now_tick - self.current_tick + 1).min(self.slots.len() as u64);
+
+        let mut expired = vec![];
+        for tick in self.current_tick..self.current_tick + ticks {
+            let slot = self.slot_index(tick);
+            let entries = std::mem::take(&mut self.slots[slot]);
+            let (due, pending): (Vec<_>, Vec<_>) =
+                entries.into_iter().partition(|e| e.deadline <= now);
+
+            self.slots[slot] = pending;
+            for entry in &due {
+                self.slot_of.remove(&entry.id);
+            }
+            expired.extend(due);
+        }
+
+        // The slot for `now_tick` may still hold timers later in this tick,
+        // so it is looked at again next time.
+        self.current_tick = now_tick;
+
+        expired.sort_by_key(|e| (e.deadline, e.id));
+        expired.into_iter().map(|e| e.value).collect()
+    }
+
+    fn tick_of(&self, instant: Instant) -> u64 {
+        instant.micros() / self.tick.as_micros() as u64
+    }
+
+    fn slot_index(&self, tick: u64) -> usize {
+        (tick % self.slots.len() as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expires_in_deadline_order_and_honours_cancel() {
+        let mut wheel = TimerWheel::new(Duration::from_millis(10), 8);
+        let ms = Instant::from_millis;
+
+        wheel.schedule(ms(30), "c");
+        let cancelled = wheel.schedule(ms(15), "cancelled");
+        wheel.schedule(ms(12), "a");
+        wheel.schedule(ms(25), "b");
+        // Several revolutions out, shares a slot with "b".
+        wheel.schedule(ms(25 + 8 * 10 * 3), "far");
+
+        assert_eq!(wheel.cancel(cancelled), Some("cancelled"));
+        assert_eq!(wheel.cancel(cancelled), None);
+        assert_eq!(wheel.next_deadline(), Some(ms(12)));
+
+        assert_eq!(wheel.expire(ms(11)), Vec::<&str>::new());
+        assert_eq!(wheel.expire(ms(30)), ["a", "b", "c"]);
+        assert_eq!(wheel.len(), 1);
+
+        assert_eq!(wheel.expire(ms(200)), Vec::<&str>::new());
+        assert_eq!(wheel.expire(ms(305)), ["far"]);
+        assert!(wheel.is_empty());
+    }
+}
@@ -1,3 +1,5 @@
+use std::net::IpAddr;
+
 pub fn read_u16(d: &[u8]) -> u16 {
     u16::from_be_bytes([d[0], d[1]])
 }
@@ -98,7 +100,7 @@ pub fn add_slice(start_sum: u64, slice: &[u8]) -> u64 {
     }
 
     // unaligned end pad the last byte with
-    if 0 != slice.len() % 2 {
+    if !slice.len().is_multiple_of(2) {
         sum = add_2bytes(
             sum,
             // SAFETY:
@@ -112,6 +114,32 @@ pub fn add_slice(start_sum: u64, slice: &[u8]) -> u64 {
     sum
 }
 
+/// Add the pseudo header TCP and UDP checksums cover. The IPv4 one has a
+/// 16 bit length and the IPv6 one a 32 bit length, but both sum the same.
+pub fn add_pseudo_header(
+    start: u64,
+    source: IpAddr,
+    destination: IpAddr,
+    protocol: u8,
+    length: usize,
+) -> u64 {
+    let mut sum = start;
+    for address in [source, destination] {
+        sum = match address {
+            IpAddr::V4(address) => add_4bytes(sum, address.octets()),
+            IpAddr::V6(address) => add_slice(sum, &address.octets()),
+        };
+    }
+    sum = add_2bytes(sum, [0, protocol]);
+    add_4bytes(sum, (length as u32).to_be_bytes())
+}
+
+/// Whether summed up words, the checksum among them, add up to all ones.
+#[inline]
+pub fn checksum_valid(sum: u64) -> bool {
+    ones_complement(sum) == 0
+}
+
 /// Converts summed up words from an u64 to an u16 with 0 being replaced by 0xffff (useful
 /// for TCP and UDP headers).
 ///
@@ -121,11 +149,7 @@ pub fn ones_complement_with_no_zero(sum: u64) -> u16 {
     // In case of 0 use the ones complement (zero is reserved
     // value for no checksum).
     let u16value = ones_complement(sum);
-    if u16value == 0 {
-        0xffff
-    } else {
-        u16value
-    }
+    if u16value == 0 { 0xffff } else { u16value }
 }
 
 /// Converts summed up words from an u64 to an u16 which can be used in a ipv4.
@@ -144,3 +168,12 @@ pub fn ones_complement(sum: u64) -> u16 {
     // native endinaess during calculations).
     !u16value
 }
+
+/// Patches a checksum for one 16 bit word going from `old` to `new`, without
+/// summing up the rest again (RFC 1624). Everything is as read off the wire.
+#[inline]
+pub fn update_checksum(checksum: u16, old: u16, new: u16) -> u16 {
+    let sum = (!checksum) as u32 + (!old) as u32 + new as u32;
+    let sum = (sum >> 16) + (sum & 0xffff);
+    !((sum >> 16) + sum) as u16
+}